@@ -0,0 +1,1029 @@
+//! Control rooms through machine-readable endpoints.
+//!
+//! The actions here are shared with the HTML control panel, which just renders their
+//! results differently.
+
+use crate::config::ConfigTile;
+use crate::game::{self, Map, SnakeID, Tile, WrapConfig};
+use crate::leaderboard::Standing;
+use crate::room::{self, Room, RoomEvent, RoomList, State, WaitingList};
+
+use futures::{stream, SinkExt, Stream, StreamExt};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::http::StatusCode;
+use warp::sse;
+use warp::ws::{Message, WebSocket};
+use warp::Reply;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// The outcome of a control action.
+#[derive(Clone, Debug, Serialize)]
+pub struct Outcome {
+    /// Whether the action succeeded.
+    pub success: bool,
+
+    /// A human-readable description of what happened.
+    pub message: String,
+}
+
+impl<T: ToString, E: ToString> From<Result<T, E>> for Outcome {
+    fn from(r: Result<T, E>) -> Self {
+        match r {
+            Ok(msg) => Outcome {
+                success: true,
+                message: msg.to_string(),
+            },
+            Err(msg) => Outcome {
+                success: false,
+                message: msg.to_string(),
+            },
+        }
+    }
+}
+
+fn fix<E: ToString>(e: E) -> String {
+    e.to_string()
+}
+
+/// Wrap the result of an action into a JSON reply.
+pub fn reply(result: Result<String, String>) -> impl Reply {
+    warp::reply::json(&Outcome::from(result))
+}
+
+/// The body of requests acting on a single waiter.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WaiterRequest {
+    /// The address of the waiter's connection.
+    pub waiter: SocketAddr,
+}
+
+/// How many maps a room's history has, as reported by `/room/<id>/history/length`.
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryLength {
+    /// The number of steps recorded, including any dropped to stay within the room's
+    /// `history_limit`.
+    pub length: usize,
+}
+
+/// The query of requests for a room's history.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    /// The first step to send; earlier steps are left out.
+    #[serde(default)]
+    pub since: usize,
+}
+
+/// Reply with the map at one step of a room's history, or a `404` if it isn't there.
+pub fn history_step(room: &Mutex<Room>, step: usize) -> impl Reply {
+    match room.lock().unwrap().history_at(step) {
+        Some(map) => warp::reply::with_status(warp::reply::json(map), StatusCode::OK),
+        None => {
+            let outcome = Outcome::from(Err::<String, _>("No such step."));
+            warp::reply::with_status(warp::reply::json(&outcome), StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// Start the game if the room now has enough players to start by itself.
+///
+/// Returns the message to report for the subscription that triggered this.
+fn auto_start(room: &Arc<Mutex<Room>>, message: String) -> String {
+    if !room.lock().unwrap().should_auto_start() {
+        return message;
+    }
+
+    // `run` checks the room is still waiting, so a racing start is harmless
+    match room::run(room.clone()) {
+        Ok(()) => format!("{} Started room execution.", message),
+        Err(e) => format!("{} Couldn't start the room automatically: {}", message, e),
+    }
+}
+
+/// Move a waiter into the room.
+pub async fn subscribe(
+    room: &Arc<Mutex<Room>>,
+    waiting: &WaitingList,
+    addr: SocketAddr,
+) -> Result<String, String> {
+    let result = waiting
+        .subscribe(&addr, room)
+        .await
+        .map(|_| "Subscribed connection to room.".to_owned())
+        .map_err(fix);
+
+    result.map(|msg| auto_start(room, msg))
+}
+
+/// Move every waiter into the room.
+pub async fn subscribe_all(
+    room: &Arc<Mutex<Room>>,
+    waiting: &WaitingList,
+) -> Result<String, String> {
+    let count = waiting.len().await;
+    let result = if count > 0 {
+        waiting
+            .subscribe_all(room)
+            .await
+            .map(|_| format!("Subscribed {} connection(s) to the room.", count))
+            .map_err(fix)
+    } else {
+        Err("No current connections to subscribe.".to_owned())
+    };
+
+    result.map(|msg| auto_start(room, msg))
+}
+
+/// Move a player in the room back to the waiting list.
+pub async fn unsubscribe(
+    room: &Mutex<Room>,
+    waiting: &WaitingList,
+    addr: SocketAddr,
+) -> Result<String, String> {
+    waiting
+        .unsubscribe(&addr, room)
+        .await
+        .map(|_| "Removed connection from room.".to_owned())
+        .map_err(fix)
+}
+
+/// Drop a waiter's connection.
+pub async fn kill(waiting: &WaitingList, addr: SocketAddr) -> Result<String, String> {
+    if waiting.remove(&addr).await {
+        Ok("Successfully killed the connection.".to_owned())
+    } else {
+        Ok("Nothing to do.".to_owned())
+    }
+}
+
+/// Drop every waiter's connection.
+pub async fn kill_all(waiting: &WaitingList) -> Result<String, String> {
+    waiting.clear().await;
+    Ok("Success!".to_owned())
+}
+
+/// Start the game in a room.
+pub fn start(room: &Arc<Mutex<Room>>) -> Result<String, String> {
+    room::run(room.clone())
+        .map(|_| "Started room execution.".to_owned())
+        .map_err(fix)
+}
+
+/// Reset a room back to waiting for players.
+pub fn reset(room: &Mutex<Room>) -> Result<String, String> {
+    let room_inner = &mut room.lock().unwrap();
+    room_inner
+        .reset()
+        .map(|_| "Room reset successfully.".to_owned())
+        .map_err(fix)
+}
+
+/// Put a room back to waiting for players, keeping the ones it has.
+pub fn restart(room: &Mutex<Room>) -> Result<String, String> {
+    room.lock()
+        .unwrap()
+        .restart()
+        .map(|_| "Room restarted successfully.".to_owned())
+        .map_err(fix)
+}
+
+/// End the game in a room early, keeping the scores as they stand.
+pub fn force_end(room: &Mutex<Room>) -> Result<String, String> {
+    room.lock()
+        .unwrap()
+        .force_end()
+        .map(|_| "Ended the game.".to_owned())
+        .map_err(fix)
+}
+
+/// Hold a game in progress until it's resumed.
+pub fn pause(room: &Mutex<Room>) -> Result<String, String> {
+    room.lock()
+        .unwrap()
+        .pause()
+        .map(|_| "Paused the game.".to_owned())
+        .map_err(fix)
+}
+
+/// Carry on with a paused game.
+pub fn resume(room: &Mutex<Room>) -> Result<String, String> {
+    room.lock()
+        .unwrap()
+        .resume()
+        .map(|_| "Resumed the game.".to_owned())
+        .map_err(fix)
+}
+
+/// Change the number of doodahs in a running game.
+pub fn set_doodah_count(room: &Mutex<Room>, count: usize) -> Result<String, String> {
+    let room_inner = &mut room.lock().unwrap();
+    room_inner
+        .set_doodah_count(count)
+        .map(|_| "Updated the number of doodahs.".to_owned())
+        .map_err(fix)
+}
+
+/// A player in a room, as reported by [`state`].
+///
+/// [`state`]: fn.state.html
+#[derive(Clone, Debug, Serialize)]
+pub struct PlayerReport {
+    /// The address of the player's connection.
+    pub addr: SocketAddr,
+
+    /// The name the player connected with.
+    pub name: String,
+
+    /// The player's snake, once the game has started.
+    pub id: Option<SnakeID>,
+}
+
+/// A summary of a room's current state, without the map itself.
+#[derive(Clone, Debug, Serialize)]
+pub struct StateReport {
+    /// One of `waiting`, `playing` or `finished`.
+    pub status: &'static str,
+
+    /// How many steps the game has taken.
+    pub step: usize,
+
+    /// Everyone in the room.
+    pub players: Vec<PlayerReport>,
+
+    /// The most players the room accepts.
+    pub max_players: usize,
+
+    /// Scores for every player in the game, by address.
+    pub scores: HashMap<SocketAddr, usize>,
+}
+
+/// Summarise the current state of a room.
+pub fn state(room: &Mutex<Room>) -> StateReport {
+    report(&room.lock().unwrap())
+}
+
+fn report(room_inner: &Room) -> StateReport {
+    let history = room_inner.history_len();
+    let max_players = room_inner.max_players();
+
+    match room_inner.get_state() {
+        State::Waiting { players, .. } => StateReport {
+            status: "waiting",
+            step: 0,
+            players: players
+                .into_iter()
+                .map(|(addr, name)| PlayerReport {
+                    addr,
+                    name,
+                    id: None,
+                })
+                .collect(),
+            max_players,
+            scores: HashMap::new(),
+        },
+        State::Playing { map, players } => {
+            let map = map.lock().unwrap();
+            StateReport {
+                status: "playing",
+                step: history,
+                max_players,
+                scores: players
+                    .iter()
+                    .map(|(&addr, (_, id))| {
+                        (addr, map.scores.get(id).copied().unwrap_or(0))
+                    })
+                    .collect(),
+                players: players
+                    .into_iter()
+                    .map(|(addr, (name, id))| PlayerReport {
+                        addr,
+                        name,
+                        id: Some(id),
+                    })
+                    .collect(),
+            }
+        }
+        // the final map is kept in the history as well as the one before it
+        State::Finished { scores, .. } => StateReport {
+            status: "finished",
+            step: history.saturating_sub(1),
+            max_players,
+            players: scores
+                .iter()
+                .map(|(&addr, (name, _))| PlayerReport {
+                    addr,
+                    name: name.clone(),
+                    id: None,
+                })
+                .collect(),
+            scores: scores
+                .into_iter()
+                .map(|(addr, (_, score))| (addr, score))
+                .collect(),
+        },
+    }
+}
+
+/// A room to create while the server is running.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewRoom {
+    /// The name of the room.
+    pub name: String,
+
+    /// The description for the room.
+    #[serde(default)]
+    pub description: String,
+
+    /// Map width
+    pub width: usize,
+
+    /// Map height
+    pub height: usize,
+
+    /// The layout as ASCII art, as understood by [`Map::from_ascii`].
+    ///
+    /// [`Map::from_ascii`]: ../game/struct.Map.html#method.from_ascii
+    pub layout: Option<String>,
+
+    /// The layout as a list of tile names, as an alternative to `layout`.
+    pub tiles: Option<Vec<ConfigTile>>,
+
+    /// How long between each snake movement, in milliseconds.
+    pub timestep: Option<u64>,
+
+    /// Maximum number of turns in a game; also accepted as `max_steps`.
+    #[serde(alias = "max_steps")]
+    pub max_turns: Option<usize>,
+
+    /// How many doodahs are kept on the board at once.
+    #[serde(default = "default_doodahs")]
+    pub doodah_count: usize,
+
+    /// How many of the server's own snakes join each game.
+    #[serde(default)]
+    pub bots: usize,
+}
+
+fn default_doodahs() -> usize {
+    1
+}
+
+/// The reply to creating a room.
+#[derive(Clone, Debug, Serialize)]
+pub struct Created {
+    #[serde(flatten)]
+    pub outcome: Outcome,
+
+    /// The new room's id, if it was created.
+    pub id: Option<usize>,
+}
+
+impl From<Result<usize, String>> for Created {
+    fn from(r: Result<usize, String>) -> Self {
+        Created {
+            id: r.as_ref().ok().copied(),
+            outcome: r.map(|id| format!("Created room {}.", id)).into(),
+        }
+    }
+}
+
+/// Check a new room and add it to the list of rooms, returning its id.
+pub fn create_room(rooms: &RwLock<RoomList>, new: NewRoom) -> Result<usize, String> {
+    let tiles = match (new.layout, new.tiles) {
+        (Some(layout), None) => {
+            let (width, height, tiles) = Map::from_ascii(&layout).map_err(fix)?;
+            if (width, height) != (new.width, new.height) {
+                return Err(format!(
+                    "layout is {}x{}, but the room should be {}x{}",
+                    width, height, new.width, new.height
+                ));
+            }
+            tiles
+        }
+        (None, Some(tiles)) => tiles.into_iter().map(Tile::from).collect(),
+        _ => return Err("exactly one of layout or tiles must be given".to_owned()),
+    };
+
+    let mut builder = Room::builder()
+        .width(new.width)
+        .height(new.height)
+        .tiles(tiles)
+        .doodah_count(new.doodah_count)
+        .bots(new.bots)
+        .name(&new.name)
+        .description(&new.description);
+    if let Some(ms) = new.timestep {
+        builder = builder.timestep(Duration::from_millis(ms));
+    }
+    if let Some(max_turns) = new.max_turns {
+        builder = builder.max_turns(max_turns);
+    }
+    let room = builder.build().map_err(fix)?;
+
+    Ok(rooms.write().unwrap().push(Arc::new(Mutex::new(room))))
+}
+
+/// Remove a room, moving anyone subscribed to it back to the waiting list and
+/// resetting any game in progress so its clients are disconnected.
+///
+/// Returns `None` if there is no such room.
+pub async fn delete_room(
+    rooms: &RwLock<RoomList>,
+    waiting: &WaitingList,
+    id: usize,
+) -> Option<String> {
+    let room = rooms.write().unwrap().remove(id)?;
+
+    let state = room.lock().unwrap().get_state();
+    if let State::Waiting { players, .. } = state {
+        for (addr, _) in players {
+            // the room is no longer listed, so nobody else can move them out first
+            let _ = waiting.unsubscribe(&addr, &room).await;
+        }
+    }
+
+    // the game may have just finished by itself, in which case there's nothing to stop
+    let _ = room.lock().unwrap().reset();
+    Some(format!("Deleted room {}.", id))
+}
+
+/// A short description of a room, as listed by [`rooms`].
+///
+/// [`rooms`]: fn.rooms.html
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomSummary {
+    /// The room's index, as used in `/room/<id>`.
+    pub id: usize,
+
+    /// The name of the room.
+    pub name: String,
+
+    /// The description for the room.
+    pub description: String,
+
+    /// One of `waiting`, `playing` or `finished`.
+    pub status: &'static str,
+
+    /// How many players are in the room.
+    pub player_count: usize,
+
+    /// Map width
+    pub width: usize,
+
+    /// Map height
+    pub height: usize,
+}
+
+/// Summarise every room.
+pub fn rooms(rooms: &RoomList) -> Vec<RoomSummary> {
+    rooms
+        .iter()
+        .map(|(id, room)| {
+            let room_inner = room.lock().unwrap();
+            let (status, player_count) = match room_inner.get_state() {
+                State::Waiting { players, .. } => ("waiting", players.len()),
+                State::Playing { players, .. } => ("playing", players.len()),
+                State::Finished { scores, .. } => ("finished", scores.len()),
+            };
+            RoomSummary {
+                id,
+                name: room_inner.name.clone(),
+                description: room_inner.description.clone(),
+                status,
+                player_count,
+                width: room_inner.width,
+                height: room_inner.height,
+            }
+        })
+        .collect()
+}
+
+/// Every player's totals from finished games in any room, best first.
+pub fn leaderboard(rooms: &RoomList) -> Vec<Standing> {
+    rooms.leaderboard().lock().unwrap().standings()
+}
+
+/// Turn a room's events into server-sent events.
+///
+/// The stream starts with a `state` event describing the room as it is now, as from
+/// [`state`], so even a room that's waiting for a game has something to show. Each
+/// step of a game is then sent as the new map, and the stream ends with a `done` event
+/// once the game is over.
+///
+/// [`state`]: fn.state.html
+pub fn events(room: &Mutex<Room>) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+    // take the snapshot and subscribe together, so no step is missed in between
+    let (snapshot, rx) = {
+        let room_inner = room.lock().unwrap();
+        (report(&room_inner), room_inner.events())
+    };
+    let snapshot = sse::Event::default()
+        .event("state")
+        .data(serde_json::to_string(&snapshot).unwrap());
+
+    let updates = stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        loop {
+            match rx.recv().await {
+                Ok(RoomEvent::Step(json)) => {
+                    return Some((
+                        Ok(sse::Event::default().data(json.as_str())),
+                        Some(rx),
+                    ))
+                }
+                Ok(RoomEvent::Done(_)) => {
+                    return Some((
+                        Ok(sse::Event::default().event("done").data("{}")),
+                        None,
+                    ))
+                }
+                // a slow watcher can just skip to the latest maps
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    stream::once(async { Ok(snapshot) }).chain(updates)
+}
+
+/// Send a room's games to a spectator over a websocket.
+///
+/// Each step of a game is sent as the new map, and once the game is over a final
+/// `{"state":"done","scores":{...}}` message is sent before closing the socket.
+pub async fn watch(mut socket: WebSocket, mut rx: broadcast::Receiver<RoomEvent>) {
+    loop {
+        let msg = match rx.recv().await {
+            Ok(RoomEvent::Step(json)) => Message::text(json.as_str()),
+            Ok(RoomEvent::Done(scores)) => {
+                let done = format!("{{\"state\":\"done\",\"scores\":{}}}", scores);
+                let _ = socket.send(Message::text(done)).await;
+                break;
+            }
+            // a slow spectator can just skip to the latest maps
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        if socket.send(msg).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = socket.close().await;
+}
+
+/// Get the waiter named by a control form.
+fn form_waiter(form: &HashMap<String, String>) -> Result<SocketAddr, String> {
+    form.get("waiter")
+        .ok_or_else(|| "missing field: waiter".to_owned())
+        .and_then(|addr| addr.parse::<SocketAddr>().map_err(fix))
+}
+
+/// Perform the action requested by a room's control form.
+///
+/// Returns `None` if the form doesn't name any known action.
+#[allow(clippy::implicit_hasher)]
+pub async fn room_action(
+    room: &Arc<Mutex<Room>>,
+    waiting: &WaitingList,
+    form: &HashMap<String, String>,
+) -> Option<Result<String, String>> {
+    let result = if form.contains_key("subscribe") {
+        match form_waiter(form) {
+            Ok(addr) => subscribe(room, waiting, addr).await,
+            Err(e) => Err(e),
+        }
+    } else if form.contains_key("subscribe_all") {
+        subscribe_all(room, waiting).await
+    } else if form.contains_key("unsubscribe") {
+        match form_waiter(form) {
+            Ok(addr) => unsubscribe(room, waiting, addr).await,
+            Err(e) => Err(e),
+        }
+    } else if form.contains_key("kill") {
+        match form_waiter(form) {
+            Ok(addr) => kill(waiting, addr).await,
+            Err(e) => Err(e),
+        }
+    } else if form.contains_key("kill_all") {
+        kill_all(waiting).await
+    } else if form.contains_key("start_room") {
+        start(room)
+    } else if form.contains_key("reset_room") {
+        reset(room)
+    } else if form.contains_key("restart_room") {
+        restart(room)
+    } else if form.contains_key("end_room") {
+        force_end(room)
+    } else if form.contains_key("pause_room") {
+        pause(room)
+    } else if form.contains_key("resume_room") {
+        resume(room)
+    } else {
+        return None;
+    };
+
+    Some(result)
+}
+
+/// Change the number of doodahs in a running game, as requested by a form.
+#[allow(clippy::implicit_hasher)]
+pub fn form_doodah_count(
+    room: &Mutex<Room>,
+    form: &HashMap<String, String>,
+) -> Result<String, String> {
+    form.get("count")
+        .ok_or_else(|| "missing field: count".to_owned())
+        .and_then(|count| count.parse::<usize>().map_err(fix))
+        .and_then(|count| set_doodah_count(room, count))
+}
+
+#[allow(clippy::implicit_hasher)]
+pub async fn room_request(
+    id: usize,
+    room: Arc<Mutex<Room>>,
+    waiting: Arc<WaitingList>,
+    form: HashMap<String, String>,
+    rooms: Arc<RwLock<RoomList>>,
+) -> Outcome {
+    if form.contains_key("delete_room") {
+        return delete_room(&rooms, &waiting, id)
+            .await
+            .ok_or_else(|| "No such room.".to_owned())
+            .into();
+    }
+
+    room_action(&room, &waiting, &form)
+        .await
+        .unwrap_or_else(|| Err("No action requested.".to_owned()))
+        .into()
+}
+
+#[allow(clippy::implicit_hasher)]
+pub fn room_doodahs(
+    _id: usize,
+    room: Arc<Mutex<Room>>,
+    _waiting: Arc<WaitingList>,
+    form: HashMap<String, String>,
+) -> Outcome {
+    form_doodah_count(&room, &form).into()
+}
+
+/// A layout to check before creating a room with it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LayoutRequest {
+    /// The layout as ASCII art, as understood by [`Map::from_ascii`].
+    ///
+    /// [`Map::from_ascii`]: ../game/struct.Map.html#method.from_ascii
+    pub layout: String,
+
+    /// How many snakes the layout should have room for.
+    #[serde(default = "default_snakes")]
+    pub snakes: usize,
+
+    /// Which edges of the map wrap around.
+    #[serde(default)]
+    pub wrap: WrapConfig,
+}
+
+fn default_snakes() -> usize {
+    1
+}
+
+/// The results of checking a layout.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LayoutReport {
+    /// Whether the layout can be used for a room.
+    pub valid: bool,
+
+    /// Everything wrong with the layout.
+    pub errors: Vec<String>,
+
+    /// Width of the layout.
+    pub width: usize,
+
+    /// Height of the layout.
+    pub height: usize,
+
+    /// Number of blank tiles.
+    pub blank_count: usize,
+
+    /// Number of blank tiles in the largest connected region.
+    pub largest_region: usize,
+}
+
+/// Check that a layout is well-formed and has space for the requested snakes.
+pub fn validate_layout(request: LayoutRequest) -> LayoutReport {
+    let (width, height, tiles) = match Map::from_ascii(&request.layout) {
+        Ok(layout) => layout,
+        Err(e) => {
+            return LayoutReport {
+                errors: vec![e.to_string()],
+                ..LayoutReport::default()
+            }
+        }
+    };
+
+    let blank_count = tiles.iter().filter(|&&t| t == Tile::Blank).count();
+    let regions = game::blank_regions(width, height, &tiles, request.wrap);
+    let largest_region = regions.first().copied().unwrap_or(0);
+
+    let mut errors = Vec::new();
+    if blank_count == 0 {
+        errors.push("layout has no blank tiles".to_owned());
+    } else if largest_region < request.snakes {
+        errors.push(format!(
+            "largest open region has {} tiles, but {} snake(s) are needed",
+            largest_region, request.snakes
+        ));
+    }
+
+    LayoutReport {
+        valid: errors.is_empty(),
+        errors,
+        width,
+        height,
+        blank_count,
+        largest_region,
+    }
+}
+
+/// Describe the metrics the server records, so they come with help text.
+pub fn describe_metrics() {
+    metrics::describe_gauge!("snake_arena_rooms_total", "Number of rooms on the server.");
+    metrics::describe_gauge!(
+        "snake_arena_rooms_playing",
+        "Number of rooms with a game in progress."
+    );
+    metrics::describe_gauge!(
+        "snake_arena_connections_active",
+        "Number of connections waiting for or playing in a game."
+    );
+    metrics::describe_gauge!(
+        "snake_arena_rooms_waiting",
+        "Number of rooms waiting for a game to start."
+    );
+    metrics::describe_gauge!(
+        "snake_arena_rooms_finished",
+        "Number of rooms showing the results of a finished game."
+    );
+    metrics::describe_gauge!(
+        "snake_arena_waiters",
+        "Number of connections in the waiting list, not yet in any room."
+    );
+    metrics::describe_counter!(
+        "snake_arena_games_started_total",
+        "Number of games started in any room."
+    );
+    metrics::describe_counter!(
+        "snake_arena_games_finished_total",
+        "Number of games that ran to the end or were ended early."
+    );
+    metrics::describe_histogram!(
+        "snake_arena_game_turns",
+        "Number of turns each finished game lasted."
+    );
+    metrics::describe_counter!(
+        "snake_arena_steps_total",
+        "Number of game steps played in each room."
+    );
+    metrics::describe_counter!(
+        "snake_arena_errors_total",
+        "Number of connections that failed or were dropped because of an error."
+    );
+}
+
+/// Report the server's metrics in the Prometheus text format.
+///
+/// Counters are kept up to date as things happen; the gauges are measured here.
+pub async fn metrics(
+    handle: &PrometheusHandle,
+    rooms: &RwLock<RoomList>,
+    waiting: &WaitingList,
+) -> String {
+    let (mut waiting_rooms, mut playing, mut finished) = (0, 0, 0);
+    let waiters = waiting.len().await;
+    let mut connections = waiters;
+    let rooms = rooms.read().unwrap();
+    for (_, room) in rooms.iter() {
+        match room.lock().unwrap().get_state() {
+            State::Waiting { players, .. } => {
+                waiting_rooms += 1;
+                connections += players.len();
+            }
+            State::Playing { players, .. } => {
+                playing += 1;
+                connections += players.len();
+            }
+            State::Finished { .. } => finished += 1,
+        }
+    }
+
+    metrics::gauge!("snake_arena_rooms_total").set(rooms.iter().count() as f64);
+    metrics::gauge!("snake_arena_rooms_waiting").set(waiting_rooms as f64);
+    metrics::gauge!("snake_arena_rooms_playing").set(playing as f64);
+    metrics::gauge!("snake_arena_rooms_finished").set(finished as f64);
+    metrics::gauge!("snake_arena_waiters").set(waiters as f64);
+    metrics::gauge!("snake_arena_connections_active").set(connections as f64);
+    handle.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{BufReader, DuplexStream};
+
+    /// A made-up address for the connection with the given number.
+    fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], n))
+    }
+
+    /// Add a connection called `name` to the waiting list, giving the client's end.
+    async fn wait(waiting: &WaitingList, n: u16, name: &str) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let (reader, writer) = tokio::io::split(Box::new(ours) as Box<dyn crate::Stream>);
+        waiting
+            .insert(addr(n), name.to_owned(), BufReader::new(reader), writer)
+            .await;
+        theirs
+    }
+
+    /// A room with an open `width` by `height` map.
+    fn open_room(width: usize, height: usize) -> Arc<Mutex<Room>> {
+        let tiles = vec![Tile::Blank; width * height];
+        let room = Room::builder()
+            .width(width)
+            .height(height)
+            .tiles(tiles)
+            .name("test")
+            .build()
+            .unwrap();
+        Arc::new(Mutex::new(room))
+    }
+
+    #[tokio::test]
+    async fn full_room_starts_by_itself() {
+        let room = open_room(5, 5);
+        {
+            let mut room = room.lock().unwrap();
+            room.player_limit = Some(2);
+            room.start_when_full = true;
+        }
+        let waiting = WaitingList::new();
+        let _first = wait(&waiting, 1, "a").await;
+        let _second = wait(&waiting, 2, "b").await;
+
+        let message = subscribe(&room, &waiting, addr(1)).await.unwrap();
+        assert_eq!(message, "Subscribed connection to room.");
+        assert_eq!(state(&room).status, "waiting");
+
+        let message = subscribe(&room, &waiting, addr(2)).await.unwrap();
+        assert!(message.ends_with("Started room execution."));
+        assert_eq!(state(&room).status, "playing");
+    }
+
+    #[tokio::test]
+    async fn room_starts_with_auto_start_players() {
+        let room = open_room(5, 5);
+        room.lock().unwrap().auto_start = Some(3);
+        let waiting = WaitingList::new();
+        let mut clients = Vec::new();
+        for n in 1..=3 {
+            clients.push(wait(&waiting, n, "a").await);
+        }
+
+        subscribe_all(&room, &waiting).await.unwrap();
+        assert_eq!(state(&room).status, "playing");
+        assert!(waiting.is_empty().await);
+    }
+
+    /// Check a line of Prometheus text output: a `# HELP` or `# TYPE` comment, or a
+    /// sample like `name{label="value"} 1.5`.
+    fn is_metric_line(line: &str) -> bool {
+        let is_name = |name: &str| {
+            let mut chars = name.chars();
+            chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        };
+        let is_value = |value: &str| {
+            value.parse::<f64>().is_ok() || ["+Inf", "-Inf", "NaN"].contains(&value)
+        };
+
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            return match (parts.next(), parts.next(), parts.next()) {
+                (Some("HELP"), Some(name), Some(_)) => is_name(name),
+                (Some("TYPE"), Some(name), Some(kind)) => {
+                    is_name(name)
+                        && ["counter", "gauge", "histogram", "summary", "untyped"]
+                            .contains(&kind)
+                }
+                _ => false,
+            };
+        }
+
+        let (series, value) = match line.rsplit_once(' ') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let name = match series.split_once('{') {
+            Some((name, labels)) => {
+                let labels = match labels.strip_suffix('}') {
+                    Some(labels) => labels,
+                    None => return false,
+                };
+                let valid_labels =
+                    labels.split(',').all(|label| match label.split_once('=') {
+                        Some((key, value)) => {
+                            is_name(key)
+                                && value.len() >= 2
+                                && value.starts_with('"')
+                                && value.ends_with('"')
+                        }
+                        None => false,
+                    });
+                if !valid_labels {
+                    return false;
+                }
+                name
+            }
+            None => series,
+        };
+        is_name(name) && is_value(value)
+    }
+
+    #[tokio::test]
+    async fn metrics_are_valid_prometheus_lines() {
+        let recorder =
+            metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::set_global_recorder(recorder).unwrap();
+        describe_metrics();
+
+        // play a short game, so there's something to count
+        let room = open_room(5, 5);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 1;
+            room.max_turns = Some(3);
+        }
+        let mut rooms = RoomList::new();
+        rooms.push(room.clone());
+        rooms.push(open_room(3, 3));
+        let rooms = RwLock::new(rooms);
+        let waiting = WaitingList::new();
+        let _client = wait(&waiting, 1, "a").await;
+        room::run(room.clone()).unwrap();
+        while state(&room).status != "finished" {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let text = metrics(&handle, &rooms, &waiting).await;
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            assert!(is_metric_line(line), "not a metric line: {:?}", line);
+            let name = line
+                .trim_start_matches("# HELP ")
+                .trim_start_matches("# TYPE ");
+            assert!(
+                name.starts_with("snake_arena_"),
+                "not namespaced: {:?}",
+                line
+            );
+        }
+        for expected in &[
+            "snake_arena_rooms_total 2",
+            "snake_arena_rooms_waiting 1",
+            "snake_arena_rooms_finished 1",
+            "snake_arena_waiters 1",
+            "snake_arena_connections_active 1",
+        ] {
+            assert!(text.lines().any(|line| line == *expected), "{}", expected);
+        }
+        for name in &[
+            "snake_arena_games_started_total",
+            "snake_arena_games_finished_total",
+            "snake_arena_game_turns_sum",
+        ] {
+            assert!(text.lines().any(|line| line.starts_with(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn bad_metric_lines_are_caught() {
+        assert!(is_metric_line("snake_arena_x 1"));
+        assert!(is_metric_line("snake_arena_x{room=\"1\"} 0.5"));
+        assert!(!is_metric_line("snake arena 1"));
+        assert!(!is_metric_line("snake_arena_x one"));
+        assert!(!is_metric_line("snake_arena_x{room=1} 1"));
+        assert!(!is_metric_line("# TYPE snake_arena_x thing"));
+    }
+}
@@ -0,0 +1,68 @@
+//! Snakes played by the server itself, for filling out rooms without extra clients.
+
+use crate::game::{Map, Position, SnakeID, Tile};
+use crate::room::Request;
+
+/// A strategy for playing a snake from inside the server.
+///
+/// Bots are asked for a move once per turn while their snake is alive, just like a
+/// connected client would be.
+pub trait Bot: Send {
+    /// Choose what the snake with the given `id` does this turn on `map`.
+    ///
+    /// Requests that don't use up a turn, like `Status`, are treated as `Forward`.
+    fn decide(&mut self, map: &Map, id: SnakeID) -> Request;
+}
+
+/// A bot that heads for the nearest doodah, and otherwise just tries not to crash.
+///
+/// It only looks one tile ahead, so it's easily trapped; it's there to make up the
+/// numbers, not to win.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Greedy;
+
+impl Bot for Greedy {
+    fn decide(&mut self, map: &Map, id: SnakeID) -> Request {
+        let head = match map.surroundings(id) {
+            Some(surroundings) => surroundings.head,
+            None => return Request::Forward,
+        };
+        let dir = match map.tiles[head.y * map.dims.width() + head.x] {
+            Tile::SnakeHead { dir, .. } => dir,
+            _ => return Request::Forward,
+        };
+
+        let doodahs: Vec<_> = map
+            .tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, tile)| matches!(tile, Tile::Doodah { .. }))
+            .map(|(i, _)| Position {
+                x: i % map.dims.width(),
+                y: i / map.dims.width(),
+            })
+            .collect();
+        let distance = |pos: Position| {
+            doodahs
+                .iter()
+                .map(|d| d.x.abs_diff(pos.x) + d.y.abs_diff(pos.y))
+                .min()
+                .unwrap_or(0)
+        };
+
+        // of the moves that don't run straight into something, take the one that ends
+        // up closest to a doodah, preferring to keep going the same way
+        [
+            (Request::Forward, dir),
+            (Request::Left, dir.left()),
+            (Request::Right, dir.right()),
+        ]
+        .iter()
+        .filter_map(|&(req, dir)| match map.neighbor(head, dir)? {
+            (pos, Tile::Blank) | (pos, Tile::Doodah { .. }) => Some((req, distance(pos))),
+            _ => None,
+        })
+        .min_by_key(|&(_, distance)| distance)
+        .map_or(Request::Forward, |(req, _)| req)
+    }
+}
@@ -0,0 +1,485 @@
+//! Load room definitions from a configuration file.
+//!
+//! The file is TOML, with one `[[room]]` table per room:
+//!
+//! ```toml
+//! [[room]]
+//! name = "Tiny"
+//! description = "Barely enough space to turn around."
+//! width = 3
+//! height = 2
+//! timestep_ms = 200
+//! doodah_count = 1
+//! tiles = ["Wall", "Blank", "Wall",
+//!          "Blank", "Blank", "Blank"]
+//! ```
+//!
+//! Instead of `tiles`, the room can be given a `layout` as ASCII art, as understood by
+//! [`Map::from_ascii`]. The same definitions can also be written as JSON, in a file
+//! ending in `.json`.
+//!
+//! `timestep_ms`, `move_timeout_ms`, `max_turns`, `auto_start`, `max_players`,
+//! `shrink_after` and `history_limit` may be left out, in which case the room doesn't
+//! have them; without `move_timeout_ms`, clients have the timestep to answer in.
+//! `shrink_interval` defaults to `1`, `countdown_ms`, `reconnect_window_ms` and `bots`
+//! to `0`, and `start_when_full` and `keep_players` to `false`. `doodah_weights` gives
+//! how likely doodahs are to be worth each value, starting from one; by default they're
+//! all worth one. `client_timeout_ms` is how long a client may take over a turn before
+//! it's dropped, `60000` unless given. `scoring` says how snakes earn their points, as
+//! a table like `{ type = "SurvivalTime", per_turn = 1 }`; the default is
+//! `{ type = "DoodahsOnly" }`, where only doodahs count. `spawn` is `"Random"` by
+//! default, or `"Spread"` to start snakes as far apart as they fit. `max_steps` is
+//! another name for `max_turns`.
+//!
+//! [`Map::from_ascii`]: ../game/struct.Map.html#method.from_ascii
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::game::{Map, ParseError, ScoringMode, SpawnMode, Tile};
+use crate::room::{BuildError, Room};
+
+/// A tile in a room definition, written as its name.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Deserialize)]
+pub enum ConfigTile {
+    Blank,
+    Wall,
+    Hazard,
+}
+
+impl From<ConfigTile> for Tile {
+    fn from(tile: ConfigTile) -> Self {
+        match tile {
+            ConfigTile::Blank => Tile::Blank,
+            ConfigTile::Wall => Tile::Wall,
+            ConfigTile::Hazard => Tile::Hazard,
+        }
+    }
+}
+
+/// The definition of a single room, as loaded by [`load_rooms`] or passed to
+/// [`Room::from_config`].
+///
+/// [`load_rooms`]: fn.load_rooms.html
+/// [`Room::from_config`]: ../room/struct.Room.html#method.from_config
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomConfig {
+    /// The name of the room.
+    pub name: String,
+
+    /// The description for the room.
+    pub description: String,
+
+    /// Map width
+    pub width: usize,
+
+    /// Map height
+    pub height: usize,
+
+    /// How long between each snake movement, in milliseconds.
+    pub timestep_ms: Option<u64>,
+
+    /// How long the server waits for each client's move, in milliseconds.
+    pub move_timeout_ms: Option<u64>,
+
+    /// How long a player who loses their connection has to reconnect, in milliseconds.
+    #[serde(default)]
+    pub reconnect_window_ms: u64,
+
+    /// The most time a client may take over a turn before it's dropped, in
+    /// milliseconds.
+    pub client_timeout_ms: Option<u64>,
+
+    /// How long players have to say they're ready before a game starts, in
+    /// milliseconds.
+    #[serde(default)]
+    pub countdown_ms: u64,
+
+    /// Maximum number of turns in a game; also accepted as `max_steps`.
+    #[serde(alias = "max_steps")]
+    pub max_turns: Option<usize>,
+
+    /// Number of players at which the game starts by itself.
+    pub auto_start: Option<usize>,
+
+    /// Most players the room accepts.
+    pub max_players: Option<usize>,
+
+    /// Whether the game starts by itself once the room is full.
+    #[serde(default)]
+    pub start_when_full: bool,
+
+    /// How many of the server's own snakes join each game.
+    #[serde(default)]
+    pub bots: usize,
+
+    /// Whether players stay in the room once their game is over.
+    #[serde(default)]
+    pub keep_players: bool,
+
+    /// How snakes earn their points.
+    #[serde(default)]
+    pub scoring: ScoringMode,
+
+    /// How snakes are placed at the start of a game.
+    #[serde(default)]
+    pub spawn: SpawnMode,
+
+    /// After how many turns the border starts closing in.
+    pub shrink_after: Option<usize>,
+
+    /// How many turns apart each ring of the border closes in.
+    pub shrink_interval: Option<usize>,
+
+    /// Most maps of a game's history to keep.
+    pub history_limit: Option<usize>,
+
+    /// How many doodahs are kept on the board at once.
+    pub doodah_count: usize,
+
+    /// How likely doodahs are to be worth each value, starting from one.
+    pub doodah_weights: Option<Vec<u32>>,
+
+    /// The layout as ASCII art, as understood by [`Map::from_ascii`].
+    ///
+    /// [`Map::from_ascii`]: ../game/struct.Map.html#method.from_ascii
+    pub layout: Option<String>,
+
+    /// The layout as a list of tile names, as an alternative to `layout`.
+    pub tiles: Option<Vec<ConfigTile>>,
+}
+
+impl RoomConfig {
+    /// Get the tiles of the room, from whichever of `layout` and `tiles` was given.
+    fn tiles(&self) -> Result<Vec<Tile>, LayoutError> {
+        match (&self.layout, &self.tiles) {
+            (Some(layout), None) => {
+                let (width, height, tiles) =
+                    Map::from_ascii(layout).map_err(LayoutError::Parse)?;
+                if (width, height) != (self.width, self.height) {
+                    return Err(LayoutError::WrongSize {
+                        expected: (self.width, self.height),
+                        found: (width, height),
+                    });
+                }
+                Ok(tiles)
+            }
+            (None, Some(tiles)) => Ok(tiles.iter().copied().map(Tile::from).collect()),
+            _ => Err(LayoutError::NotExactlyOne),
+        }
+    }
+
+    /// Check the definition and create the room.
+    pub(crate) fn build(self) -> Result<Room, ConfigError> {
+        let tiles = self.tiles().map_err(|error| ConfigError::Layout {
+            name: self.name.clone(),
+            error,
+        })?;
+        let mut builder = Room::builder()
+            .width(self.width)
+            .height(self.height)
+            .tiles(tiles)
+            .doodah_count(self.doodah_count)
+            .countdown(Duration::from_millis(self.countdown_ms))
+            .name(&self.name)
+            .description(&self.description);
+
+        if let Some(ms) = self.timestep_ms {
+            builder = builder.timestep(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.move_timeout_ms {
+            builder = builder.move_timeout(Duration::from_millis(ms));
+        }
+        builder =
+            builder.reconnect_window(Duration::from_millis(self.reconnect_window_ms));
+        if let Some(ms) = self.client_timeout_ms {
+            builder = builder.client_timeout(Duration::from_millis(ms));
+        }
+        if let Some(max_turns) = self.max_turns {
+            builder = builder.max_turns(max_turns);
+        }
+        if let Some(players) = self.auto_start {
+            builder = builder.auto_start(players);
+        }
+        if let Some(players) = self.max_players {
+            builder = builder.max_players(players);
+        }
+        if self.start_when_full {
+            builder = builder.start_when_full();
+        }
+        builder = builder.bots(self.bots);
+        if self.keep_players {
+            builder = builder.keep_players();
+        }
+        builder = builder.scoring(self.scoring).spawn(self.spawn);
+        if let Some(after) = self.shrink_after {
+            builder = builder.shrink(after, self.shrink_interval.unwrap_or(1));
+        }
+        if let Some(limit) = self.history_limit {
+            builder = builder.history_limit(limit);
+        }
+        if let Some(weights) = self.doodah_weights {
+            builder = builder.doodah_weights(weights);
+        }
+
+        let name = self.name;
+        builder
+            .build()
+            .map_err(|error| ConfigError::Room { name, error })
+    }
+}
+
+/// A problem with the layout given in a room definition.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum LayoutError {
+    /// Neither or both of `layout` and `tiles` were given.
+    NotExactlyOne,
+
+    /// The ASCII `layout` couldn't be parsed.
+    Parse(ParseError),
+
+    /// The ASCII `layout` isn't the size given by `width` and `height`.
+    WrongSize {
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutError::NotExactlyOne => {
+                write!(f, "exactly one of layout or tiles must be given")
+            }
+            LayoutError::Parse(e) => write!(f, "couldn't parse layout: {}", e),
+            LayoutError::WrongSize { expected, found } => write!(
+                f,
+                "layout is {}x{}, but the room should be {}x{}",
+                found.0, found.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// The whole configuration file.
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    #[serde(default)]
+    room: Vec<RoomConfig>,
+}
+
+/// An error encountered while loading room definitions.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read.
+    Io(io::Error),
+
+    /// The file isn't a valid configuration.
+    Parse(toml::de::Error),
+
+    /// The file isn't a valid JSON configuration.
+    Json(serde_json::Error),
+
+    /// One of the rooms has a bad layout.
+    Layout { name: String, error: LayoutError },
+
+    /// One of the rooms is invalid.
+    Room { name: String, error: BuildError },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "couldn't read room definitions: {}", e),
+            ConfigError::Parse(e) => write!(f, "couldn't parse room definitions: {}", e),
+            ConfigError::Json(e) => write!(f, "couldn't parse room definitions: {}", e),
+            ConfigError::Layout { name, error } => {
+                write!(f, "room {:?}: {}", name, error)
+            }
+            ConfigError::Room { name, error } => write!(f, "room {:?}: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Load and check the rooms defined in a file.
+///
+/// Files ending in `.json` are read as JSON, with the same layout as the TOML; anything
+/// else is read as TOML. A file with nothing in it defines no rooms.
+pub fn load_rooms(path: &Path) -> Result<Vec<Room>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let config: Config = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&text).map_err(ConfigError::Json)?
+    } else {
+        toml::from_str(&text).map_err(ConfigError::Parse)?
+    };
+
+    config.room.into_iter().map(Room::from_config).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Everything about a room that a game is set up from.
+    fn settings(room: &Room) -> serde_json::Value {
+        json!({
+            "name": room.name,
+            "description": room.description,
+            "tiles": room.tiles,
+            "rules": room.rules(),
+            "auto_start": room.auto_start,
+            "player_limit": room.player_limit,
+            "start_when_full": room.start_when_full,
+            "bots": room.bots,
+            "keep_players": room.keep_players,
+            "spawn": room.spawn,
+            "history_limit": room.history_limit,
+        })
+    }
+
+    const SAMPLE: &str = r#"
+        [[room]]
+        name = "Tiny"
+        description = "Barely enough space to turn around."
+        width = 3
+        height = 2
+        timestep_ms = 200
+        move_timeout_ms = 150
+        reconnect_window_ms = 1000
+        max_steps = 50
+        auto_start = 2
+        max_players = 3
+        bots = 1
+        keep_players = true
+        scoring = { type = "SurvivalTime", per_turn = 2 }
+        spawn = "Spread"
+        shrink_after = 10
+        history_limit = 20
+        doodah_count = 1
+        doodah_weights = [3, 1]
+        tiles = ["Wall", "Blank", "Hazard",
+                 "Blank", "Blank", "Blank"]
+
+        [[room]]
+        name = "Drawn"
+        description = "The same, but drawn."
+        width = 3
+        height = 2
+        doodah_count = 0
+        layout = """
+            #.x
+            ...
+        """
+    "#;
+
+    #[test]
+    fn config_builds_the_same_room_as_by_hand() {
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        let mut rooms = config.room.into_iter().map(Room::from_config);
+        let tiny = rooms.next().unwrap().unwrap();
+        let drawn = rooms.next().unwrap().unwrap();
+
+        let tiles = vec![
+            Tile::Wall,
+            Tile::Blank,
+            Tile::Hazard,
+            Tile::Blank,
+            Tile::Blank,
+            Tile::Blank,
+        ];
+        let by_hand = Room::builder()
+            .width(3)
+            .height(2)
+            .tiles(tiles.clone())
+            .timestep(Duration::from_millis(200))
+            .move_timeout(Duration::from_millis(150))
+            .reconnect_window(Duration::from_secs(1))
+            .max_turns(50)
+            .auto_start(2)
+            .max_players(3)
+            .bots(1)
+            .keep_players()
+            .scoring(ScoringMode::SurvivalTime { per_turn: 2 })
+            .spawn(SpawnMode::Spread)
+            .shrink(10, 1)
+            .history_limit(20)
+            .doodah_count(1)
+            .doodah_weights(vec![3, 1])
+            .name("Tiny")
+            .description("Barely enough space to turn around.")
+            .build()
+            .unwrap();
+        assert_eq!(settings(&tiny), settings(&by_hand));
+
+        let by_hand = Room::builder()
+            .width(3)
+            .height(2)
+            .tiles(tiles)
+            .name("Drawn")
+            .description("The same, but drawn.")
+            .build()
+            .unwrap();
+        assert_eq!(settings(&drawn), settings(&by_hand));
+    }
+
+    #[test]
+    fn json_config_matches_toml() {
+        let toml: Config = toml::from_str(SAMPLE).unwrap();
+        let json = serde_json::to_string(&json!({
+            "room": [{
+                "name": "Drawn",
+                "description": "The same, but drawn.",
+                "width": 3,
+                "height": 2,
+                "doodah_count": 0,
+                "layout": "#.x\n...",
+            }]
+        }))
+        .unwrap();
+        let json: Config = serde_json::from_str(&json).unwrap();
+        let from_toml = Room::from_config(toml.room[1].clone()).unwrap();
+        let from_json = Room::from_config(json.room[0].clone()).unwrap();
+        assert_eq!(settings(&from_json), settings(&from_toml));
+    }
+
+    #[test]
+    fn layouts_must_be_given_once_and_fit() {
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        let layout_error = |config: RoomConfig| match Room::from_config(config) {
+            Err(ConfigError::Layout { error, .. }) => error,
+            other => panic!("expected a layout error, got {:?}", other.map(|_| ())),
+        };
+
+        let mut both = config.room[0].clone();
+        both.layout = config.room[1].layout.clone();
+        assert_eq!(layout_error(both), LayoutError::NotExactlyOne);
+
+        let mut neither = config.room[0].clone();
+        neither.tiles = None;
+        assert_eq!(layout_error(neither), LayoutError::NotExactlyOne);
+
+        let mut wrong_size = config.room[1].clone();
+        wrong_size.width = 4;
+        assert_eq!(
+            layout_error(wrong_size),
+            LayoutError::WrongSize {
+                expected: (4, 2),
+                found: (3, 2)
+            }
+        );
+    }
+}
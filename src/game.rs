@@ -1,11 +1,12 @@
 //! Describe the snake game.
 
-use rand::{distributions::Standard, prelude::*};
-use serde::Serialize;
+use rand::{distributions::Standard, prelude::*, rngs::StdRng};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
 
 /// The direction a snake is facing.
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Direction {
     North,
     East,
@@ -13,6 +14,14 @@ pub enum Direction {
     West,
 }
 
+/// Every direction, for when they all need to be tried.
+const DIRECTIONS: [Direction; 4] = [
+    Direction::North,
+    Direction::West,
+    Direction::South,
+    Direction::East,
+];
+
 impl Direction {
     /// Get a new direction to the right of `self`.
     pub fn right(self) -> Direction {
@@ -33,30 +42,397 @@ impl Direction {
             Direction::East => Direction::North,
         }
     }
+
+    /// Get the direction opposite to `self`.
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::North => Direction::South,
+            Direction::East => Direction::West,
+            Direction::South => Direction::North,
+            Direction::West => Direction::East,
+        }
+    }
 }
 
 impl Distribution<Direction> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Direction {
-        use Direction::*;
-        const DIRECTIONS: [Direction; 4] = [North, West, South, East];
-        DIRECTIONS[rng.gen_range(0, 3)]
+        DIRECTIONS[rng.gen_range(0, DIRECTIONS.len())]
     }
 }
 
 /// The size of a tile grid.
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Dimensions {
     width: usize,
     height: usize,
 }
 
+/// Which edges of a tile grid wrap around to the opposite side.
+///
+/// A snake moving off an edge that doesn't wrap dies as if it hit a wall.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct WrapConfig {
+    /// Whether the east and west edges wrap.
+    pub horizontal: bool,
+
+    /// Whether the north and south edges wrap.
+    pub vertical: bool,
+}
+
+impl Default for WrapConfig {
+    fn default() -> Self {
+        WrapConfig {
+            horizontal: true,
+            vertical: true,
+        }
+    }
+}
+
+impl From<bool> for WrapConfig {
+    /// Either wrap every edge, or make every edge lethal.
+    fn from(wrap: bool) -> Self {
+        WrapConfig {
+            horizontal: wrap,
+            vertical: wrap,
+        }
+    }
+}
+
+impl Dimensions {
+    /// Width of the grid, in tiles.
+    pub fn width(self) -> usize {
+        self.width
+    }
+
+    /// Height of the grid, in tiles.
+    pub fn height(self) -> usize {
+        self.height
+    }
+
+    /// Get the tiles a snake of the given `length` would cover, with its head at `pos`
+    /// facing `dir` and its body trailing out behind it. The head comes first.
+    ///
+    /// Returns `None` if the snake would run off an edge that doesn't wrap, or would
+    /// cover itself.
+    pub fn snake_layout(
+        self,
+        pos: Position,
+        dir: Direction,
+        length: usize,
+        wrap: WrapConfig,
+    ) -> Option<Vec<Position>> {
+        let mut cells = vec![pos];
+        for _ in 1..length {
+            let next = self.neighbor(*cells.last().unwrap(), dir.opposite(), wrap)?;
+            if cells.contains(&next) {
+                return None;
+            }
+            cells.push(next);
+        }
+        Some(cells)
+    }
+
+    /// Get the position one step away from `pos` in the direction `dir`.
+    ///
+    /// Returns `None` if that would move off an edge that doesn't wrap.
+    pub fn neighbor(
+        self,
+        pos: Position,
+        dir: Direction,
+        wrap: WrapConfig,
+    ) -> Option<Position> {
+        let Position { x, y } = pos;
+        let Dimensions { width, height } = self;
+        match dir {
+            Direction::North if !wrap.vertical && y + 1 == height => None,
+            Direction::South if !wrap.vertical && y == 0 => None,
+            Direction::East if !wrap.horizontal && x + 1 == width => None,
+            Direction::West if !wrap.horizontal && x == 0 => None,
+            Direction::North => Some(Position {
+                x,
+                y: (y + 1) % height,
+            }),
+            Direction::South => Some(Position {
+                x,
+                y: (y + height - 1) % height,
+            }),
+            Direction::East => Some(Position {
+                x: (x + 1) % width,
+                y,
+            }),
+            Direction::West => Some(Position {
+                x: (x + width - 1) % width,
+                y,
+            }),
+        }
+    }
+
+    /// Count the steps between `a` and `b`, going the short way round any edges that
+    /// wrap and ignoring whatever is in the way.
+    pub fn distance(self, a: Position, b: Position, wrap: WrapConfig) -> usize {
+        let across = |a: usize, b: usize, size: usize, wraps: bool| {
+            let d = a.abs_diff(b);
+            if wraps {
+                d.min(size - d)
+            } else {
+                d
+            }
+        };
+        across(a.x, b.x, self.width, wrap.horizontal)
+            + across(a.y, b.y, self.height, wrap.vertical)
+    }
+
+    /// Get which ring of the border the tile at `index` is in, where the outermost
+    /// ring is `0`.
+    pub fn ring(self, index: usize) -> usize {
+        let (x, y) = (index % self.width, index / self.width);
+        x.min(y).min(self.width - 1 - x).min(self.height - 1 - y)
+    }
+}
+
 /// A position in the tile grid.
-type Position = (usize, usize);
+///
+/// `x` is the column of the tile, and `y` its row.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Serialize)]
+pub struct Position {
+    pub x: usize,
+    pub y: usize,
+}
+
+impl Position {
+    /// Get the number of steps between two positions, moving only north, south, east
+    /// and west, and ignoring any wrapping.
+    pub fn manhattan_distance(self, other: Position) -> usize {
+        self.x.abs_diff(other.x) + self.y.abs_diff(other.y)
+    }
+}
+
+impl From<(usize, usize)> for Position {
+    /// Make a position from an `(x, y)` pair.
+    fn from((x, y): (usize, usize)) -> Self {
+        Position { x, y }
+    }
+}
+
+/// An error encountered while parsing an ASCII map layout.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ParseError {
+    /// The layout doesn't have any rows.
+    Empty,
+
+    /// A row isn't as wide as the first row. Lines are numbered from 1.
+    Ragged {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+
+    /// A character that doesn't describe a tile. Lines and columns are numbered from 1.
+    UnknownTile {
+        line: usize,
+        column: usize,
+        found: char,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "layout is empty"),
+            ParseError::Ragged {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {} is {} tiles wide, but expected {}",
+                line, found, expected
+            ),
+            ParseError::UnknownTile {
+                line,
+                column,
+                found,
+            } => write!(
+                f,
+                "unknown tile {:?} at line {}, column {}",
+                found, line, column
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An error encountered while creating a map.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum MapError {
+    /// There are fewer blank tiles than snakes to place on them.
+    NotEnoughSpawnPoints { needed: usize, available: usize },
+
+    /// The number of starting positions doesn't match the number of snakes.
+    WrongStartCount { snakes: usize, starts: usize },
+
+    /// A snake's start would put it off the map.
+    StartOutOfBounds { x: usize, y: usize },
+
+    /// A snake's start would put it on top of another snake, or of itself.
+    StartOverlaps { x: usize, y: usize },
+
+    /// A snake's start would put it on something other than blank tiles.
+    StartNotBlank { x: usize, y: usize },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::NotEnoughSpawnPoints { needed, available } => write!(
+                f,
+                "not enough space for snakes: {} needed, but only {} available",
+                needed, available
+            ),
+            MapError::WrongStartCount { snakes, starts } => write!(
+                f,
+                "{} starting positions given for {} snakes",
+                starts, snakes
+            ),
+            MapError::StartOutOfBounds { x, y } => {
+                write!(f, "snake starting at ({}, {}) is off the map", x, y)
+            }
+            MapError::StartOverlaps { x, y } => {
+                write!(f, "snake starting at ({}, {}) overlaps a snake", x, y)
+            }
+            MapError::StartNotBlank { x, y } => {
+                write!(f, "snake starting at ({}, {}) isn't on blank tiles", x, y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// A problem with a map layout, as found by [`validate`].
+///
+/// [`validate`]: fn.validate.html
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ValidationError {
+    /// The map has no width or no height.
+    ZeroSize { width: usize, height: usize },
+
+    /// The number of tiles doesn't match the map's dimensions.
+    WrongTileCount { expected: usize, found: usize },
+
+    /// A tile other than `Tile::Blank`, `Tile::Wall` or `Tile::Hazard`, at the given
+    /// index.
+    InvalidTile { index: usize },
+
+    /// There are no blank tiles, so no snake could ever be placed.
+    NoBlankTiles,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::ZeroSize { width, height } => {
+                write!(f, "map must not be empty, but is {}x{}", width, height)
+            }
+            ValidationError::WrongTileCount { expected, found } => {
+                write!(f, "map needs {} tiles, but {} were given", expected, found)
+            }
+            ValidationError::InvalidTile { index } => {
+                write!(f, "tile {} is not blank, a wall or a hazard", index)
+            }
+            ValidationError::NoBlankTiles => write!(f, "map has no blank tiles"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that a layout can be used to create a [`Map`].
+///
+/// The map must have some size, `tiles` must cover it exactly, and it may only contain
+/// blanks, walls and hazards, with at least one blank.
+///
+/// [`Map`]: struct.Map.html
+pub fn validate(
+    width: usize,
+    height: usize,
+    tiles: &[Tile],
+) -> Result<(), ValidationError> {
+    if width == 0 || height == 0 {
+        return Err(ValidationError::ZeroSize { width, height });
+    }
+
+    if tiles.len() != width * height {
+        return Err(ValidationError::WrongTileCount {
+            expected: width * height,
+            found: tiles.len(),
+        });
+    }
+
+    if let Some(index) = tiles
+        .iter()
+        .position(|&t| t != Tile::Blank && t != Tile::Wall && t != Tile::Hazard)
+    {
+        return Err(ValidationError::InvalidTile { index });
+    }
+
+    if !tiles.contains(&Tile::Blank) {
+        return Err(ValidationError::NoBlankTiles);
+    }
+
+    Ok(())
+}
+
+/// Find the sizes of the connected regions of blank tiles in a layout.
+///
+/// The sizes are sorted from largest to smallest.
+pub fn blank_regions(
+    width: usize,
+    height: usize,
+    tiles: &[Tile],
+    wrap: WrapConfig,
+) -> Vec<usize> {
+    let dims = Dimensions { width, height };
+    let mut seen = vec![false; tiles.len()];
+    let mut regions = Vec::new();
+
+    for start in 0..tiles.len() {
+        if seen[start] || tiles[start] != Tile::Blank {
+            continue;
+        }
+
+        // flood fill from here
+        seen[start] = true;
+        let mut size = 0;
+        let mut queue = VecDeque::new();
+        queue.push_back(Position {
+            x: start % width,
+            y: start / width,
+        });
+        while let Some(pos) = queue.pop_front() {
+            size += 1;
+            for &dir in &DIRECTIONS {
+                if let Some(next) = dims.neighbor(pos, dir, wrap) {
+                    let idx = next.x + next.y * width;
+                    if !seen[idx] && tiles[idx] == Tile::Blank {
+                        seen[idx] = true;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        regions.push(size);
+    }
+
+    regions.sort_unstable_by(|a, b| b.cmp(a));
+    regions
+}
 
 /// What a tile is filled with.
 ///
 /// Only one of these things can be in a tile at a time.
-#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Tile {
     /// A snake body, belonging to the snake with given `id`,
@@ -66,12 +442,15 @@ pub enum Tile {
     /// A snake head, belonging to the snake with given `id`, in given `direction`
     SnakeHead { id: SnakeID, dir: Direction },
 
-    /// A doodah to collect
-    Doodah,
+    /// A doodah to collect, which grows the snake that eats it by `value` segments
+    Doodah { value: usize },
 
     /// A wall that cannot be hit or walked through
     Wall,
 
+    /// A hazard that kills any snake moving onto it, just like a wall
+    Hazard,
+
     /// Empty space
     Blank,
 }
@@ -81,7 +460,7 @@ pub type SnakeID = usize;
 
 /// Keep track of where the snake is and where it's going.
 #[derive(Clone, Debug)]
-struct Snake {
+pub struct Snake {
     /// The direction the snake was last heading.
     pub dir: Direction,
 
@@ -93,51 +472,79 @@ struct Snake {
     /// `body[0]` is the end of the tail (if it exists), and higher indices
     /// get closer and closer to the `head` position.
     pub body: VecDeque<Position>,
+
+    /// Points deducted from the snake's score, e.g. for turning.
+    pub penalty: usize,
+
+    /// How long the body was when the snake was created; this isn't counted as score.
+    pub start_len: usize,
+
+    /// How many more segments the snake will grow by over its next steps, from
+    /// doodahs worth more than one.
+    pub growth: usize,
 }
 
 impl Snake {
-    /// Create a new snake.
-    pub fn new(dir: Direction, head: Position) -> Self {
+    /// Create a new snake, with the given `body` already behind its head.
+    pub fn new(dir: Direction, head: Position, body: VecDeque<Position>) -> Self {
         Snake {
             dir,
             head,
-            body: VecDeque::new(),
+            start_len: body.len(),
+            body,
+            penalty: 0,
+            growth: 0,
         }
     }
 
-    /// Get the number of doodahs eaten by this snake.
+    /// Get the value of the doodahs eaten by this snake, less any penalties.
+    ///
+    /// Segments the snake started with don't count, but ones it has yet to grow do.
     pub fn score(&self) -> usize {
-        self.body.len()
+        (self.body.len() + self.growth - self.start_len).saturating_sub(self.penalty)
+    }
+
+    /// Iterate over every position the snake covers, starting with its head and then
+    /// following its body from the neck to the tip of the tail.
+    pub fn segments(&self) -> impl Iterator<Item = Position> + '_ {
+        std::iter::once(self.head).chain(self.body.iter().copied().rev())
+    }
+
+    /// Get the number of tiles the snake covers, including its head.
+    // a snake always has a head, so it's never empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + self.body.len()
+    }
+
+    /// Test if the snake covers the given position.
+    pub fn contains(&self, pos: Position) -> bool {
+        self.head == pos || self.body.contains(&pos)
     }
 
-    /// Move the snake one step in the direction it's facing.
+    /// Move the snake one step, so that its head is at `head`.
     ///
     /// Returns the spot that has now been freed.
-    pub fn step(&mut self, map: Dimensions) -> Position {
+    pub fn step(&mut self, head: Position) -> Position {
         self.body.push_back(self.head);
-        self.head = self.next_head_pos(map);
+        self.head = head;
         self.body.pop_front().unwrap()
     }
 
-    /// Grow the snake one step in the direction it's facing.
+    /// Grow the snake one step, so that its head is at `head`.
     ///
     /// This is like move, except the snake doesn't remove
     /// its last segment, and thus nothing is returned.
-    pub fn grow(&mut self, map: Dimensions) {
+    pub fn grow(&mut self, head: Position) {
         self.body.push_back(self.head);
-        self.head = self.next_head_pos(map);
+        self.head = head;
     }
 
     /// Get the new head position if the snake were to move.
-    pub fn next_head_pos(&self, map: Dimensions) -> Position {
-        let (x, y) = self.head;
-        let Dimensions { width, height } = map;
-        match self.dir {
-            Direction::North => (x, (y + 1) % height),
-            Direction::South => (x, (y + height - 1) % height),
-            Direction::East => ((x + 1) % width, y),
-            Direction::West => ((x + width - 1) % width, y),
-        }
+    ///
+    /// Returns `None` if the snake would move off an edge that doesn't wrap.
+    pub fn next_head_pos(&self, map: Dimensions, wrap: WrapConfig) -> Option<Position> {
+        map.neighbor(self.head, self.dir, wrap)
     }
 
     /// Test if we have collided with another snake.
@@ -153,8 +560,173 @@ impl Snake {
     }
 }
 
-/// The tile grid.
+/// The tiles next to a snake's head, so clients don't have to search the whole map.
+///
+/// Tiles past an edge that doesn't wrap are `None`.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize)]
+pub struct Surroundings {
+    /// Where the snake's head is.
+    pub head: Position,
+
+    /// The tile to the north of the head.
+    pub north: Option<Tile>,
+
+    /// The tile to the east of the head.
+    pub east: Option<Tile>,
+
+    /// The tile to the south of the head.
+    pub south: Option<Tile>,
+
+    /// The tile to the west of the head.
+    pub west: Option<Tile>,
+}
+
+/// A snake that died during a step.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Elimination {
+    /// The snake that died.
+    pub id: SnakeID,
+
+    /// The snake's final score.
+    pub score: usize,
+}
+
+/// How a snake's game came to an end.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DeathCause {
+    /// Moved onto a wall or hazard, or off an edge that doesn't wrap.
+    Wall,
+
+    /// Ran into its own body.
+    SelfCollision,
+
+    /// Ran into the snake with the given `with` id.
+    Collision { with: SnakeID },
+
+    /// Took too long to choose a move.
+    Timeout,
+
+    /// Lost its connection to the server.
+    Disconnect,
+
+    /// Left the game by sending `Quit`.
+    Quit,
+
+    /// Was still alive when the game ended.
+    Survived,
+}
+
+impl fmt::Display for DeathCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeathCause::Wall => write!(f, "hit a wall"),
+            DeathCause::SelfCollision => write!(f, "ran into itself"),
+            DeathCause::Collision { with } => write!(f, "ran into snake {}", with),
+            DeathCause::Timeout => write!(f, "timed out"),
+            DeathCause::Disconnect => write!(f, "disconnected"),
+            DeathCause::Quit => write!(f, "quit"),
+            DeathCause::Survived => write!(f, "survived"),
+        }
+    }
+}
+
+/// How snakes earn their points.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ScoringMode {
+    /// Points only come from the doodahs a snake eats.
+    #[default]
+    DoodahsOnly,
+
+    /// Points only come from staying alive: `per_turn` for every turn survived.
+    SurvivalTime { per_turn: usize },
+
+    /// Points come from both doodahs eaten and `per_turn` for every turn survived.
+    Both { per_turn: usize },
+}
+
+/// How snakes are placed when a map doesn't say where they start.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum SpawnMode {
+    /// Anywhere they fit, at random.
+    #[default]
+    Random,
+
+    /// As far from each other as possible: each snake goes where it's furthest from
+    /// the nearest snake already placed, so nobody starts crowded in by the others.
+    Spread,
+}
+
+/// How a new map is set up, apart from its layout and snakes; see [`Map::new`].
+///
+/// [`Map::new`]: struct.Map.html#method.new
+#[derive(Clone, Debug)]
+pub struct MapOptions {
+    /// Where each snake starts and which way it faces, in the same order as the snakes.
+    ///
+    /// `None` means they're placed wherever they fit instead, as chosen by `spawn`.
+    pub starts: Option<Vec<(Position, Direction)>>,
+
+    /// How snakes are placed when there are no `starts`.
+    pub spawn: SpawnMode,
+
+    /// Which edges of the map wrap around.
+    pub wrap: WrapConfig,
+
+    /// How many doodahs the map tries to keep on the board.
+    pub doodah_count: usize,
+
+    /// How many tiles long snakes start, counting the head.
+    pub initial_length: usize,
+
+    /// Seed for all of the map's random choices, including those made in later steps.
+    ///
+    /// `None` means every map is different.
+    pub seed: Option<u64>,
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            starts: None,
+            spawn: SpawnMode::Random,
+            wrap: WrapConfig::default(),
+            doodah_count: 0,
+            initial_length: 1,
+            seed: None,
+        }
+    }
+}
+
+/// A tile that's different from one map to the next.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TileChange {
+    /// Where the tile is in the map's `tiles`.
+    pub index: usize,
+
+    /// What the tile is now.
+    pub tile: Tile,
+}
+
+/// The changes from one map to the next, for clients that already have the older one.
 #[derive(Clone, Debug, Serialize)]
+pub struct MapDelta<'a> {
+    /// Every tile that changed.
+    pub changes: Vec<TileChange>,
+
+    /// Scores for all snakes in the game.
+    pub scores: &'a HashMap<SnakeID, usize>,
+
+    /// Snakes that died in the step that produced the newer map.
+    pub eliminations: &'a [Elimination],
+}
+
+/// The tile grid.
+///
+/// Only the parts of the map sent to clients are serialized, so a deserialized map
+/// is only good for looking at: it doesn't know where its snakes are going.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Map {
     /// Dimensions of the map.
     #[serde(flatten)]
@@ -169,40 +741,251 @@ pub struct Map {
 
     /// Scores for all snakes in the game.
     pub scores: HashMap<SnakeID, usize>,
+
+    /// Snakes that died in the step that produced this map.
+    pub eliminations: Vec<Elimination>,
+
+    /// How each snake that is no longer playing met its end.
+    #[serde(default)]
+    pub causes: HashMap<SnakeID, DeathCause>,
+
+    /// Snakes deleted since the last step, to be reported in the next one.
+    #[serde(skip)]
+    deleted: Vec<Elimination>,
+
+    /// Which edges of the map wrap around.
+    #[serde(skip)]
+    pub wrap: WrapConfig,
+
+    /// How many doodahs the map tries to keep on the board.
+    #[serde(skip)]
+    doodah_count: usize,
+
+    /// How likely new doodahs are to be worth each value: doodahs are worth `i + 1`
+    /// with a chance proportional to `doodah_weights[i]`.
+    #[serde(skip)]
+    doodah_weights: Vec<u32>,
+
+    /// How many points a snake loses each time it turns.
+    #[serde(skip)]
+    pub turn_penalty: usize,
+
+    /// How snakes earn their points.
+    #[serde(skip)]
+    pub scoring: ScoringMode,
+
+    /// After how many steps the border starts closing in, one ring of tiles at a time.
+    ///
+    /// `None` means the border never closes in.
+    #[serde(skip)]
+    pub shrink_after: Option<usize>,
+
+    /// How many steps apart each ring of the border closes in.
+    #[serde(skip)]
+    pub shrink_interval: usize,
+
+    /// How many steps have been taken so far.
+    #[serde(skip)]
+    turn: usize,
+
+    /// Source of randomness for everything placed on the map.
+    #[serde(skip, default = "StdRng::from_entropy")]
+    rng: StdRng,
+}
+
+/// Maps are equal when they look the same to clients: that is, when everything that's
+/// serialized matches. This means a map loaded back from JSON equals the one saved.
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.dims == other.dims
+            && self.tiles == other.tiles
+            && self.scores == other.scores
+            && self.eliminations == other.eliminations
+            && self.causes == other.causes
+    }
 }
 
 impl Map {
     /// Create a new map with given `width` and `height`, initialised with the provided
-    /// `tiles`.
+    /// `tiles`, and set up as given by `options`. Edges of the map wrap around as given
+    /// by `wrap`, and the map tries to keep `doodah_count` doodahs on the board.
+    ///
+    /// Snakes start `initial_length` tiles long (counting the head), with their body
+    /// trailing out behind them. They start at the positions and directions given in
+    /// `starts`, in the same order as `snakes`. If `starts` is `None`, they are placed
+    /// wherever they fit instead, as chosen by `spawn`.
+    ///
+    /// All random choices, including those made in later steps, come from `seed` if
+    /// it's given, so the same seed and inputs play out the same way.
     ///
     /// # Panics
     ///
     /// The size of the tile map must be the same as `width * height`: that is,
     /// it must cover the whole map. In addition, the only tiles that are permitted are
-    /// [`Tile::Wall`] and [`Tile::Blank`]: any other tiles result in a panic.
+    /// [`Tile::Wall`], [`Tile::Hazard`] and [`Tile::Blank`]: any other tiles result in
+    /// a panic.
+    ///
+    /// # Errors
     ///
+    /// Returns [`MapError::NotEnoughSpawnPoints`] if there isn't room to place every
+    /// snake randomly. If `starts` is given, it must have one entry per snake, and every
+    /// snake must fit on blank tiles without overlapping any other; otherwise the
+    /// [`MapError`] says which start is wrong.
+    ///
+    /// [`MapError`]: enum.MapError.html
+    /// [`MapError::NotEnoughSpawnPoints`]: enum.MapError.html#variant.NotEnoughSpawnPoints
     /// [`Tile::Wall`]: enum.Tile.html#variant.Wall
+    /// [`Tile::Hazard`]: enum.Tile.html#variant.Hazard
     /// [`Tile::Blank`]: enum.Tile.html#variant.Blank
     pub fn new(
         width: usize,
         height: usize,
         tiles: Vec<Tile>,
         snakes: Vec<SnakeID>,
-    ) -> Self {
+        options: MapOptions,
+    ) -> Result<Self, MapError> {
+        let MapOptions {
+            starts,
+            spawn,
+            wrap,
+            doodah_count,
+            initial_length,
+            seed,
+        } = options;
         assert!(tiles.len() == width * height);
-        assert!(tiles.iter().all(|t| t == &Tile::Wall || t == &Tile::Blank));
-
-        let rng = &mut thread_rng();
-        let blank_spots = tiles
+        assert!(tiles
             .iter()
-            .enumerate()
-            .filter(|&(_, t)| t == &Tile::Blank)
-            .map(|(i, _)| (i % width, i / width))
-            .choose_multiple(rng, snakes.len());
+            .all(|t| t == &Tile::Wall || t == &Tile::Hazard || t == &Tile::Blank));
+
+        if let Some(starts) = &starts {
+            if starts.len() != snakes.len() {
+                return Err(MapError::WrongStartCount {
+                    snakes: snakes.len(),
+                    starts: starts.len(),
+                });
+            }
+        }
+
+        let available = tiles.iter().filter(|&t| t == &Tile::Blank).count();
+        if available < snakes.len() {
+            return Err(MapError::NotEnoughSpawnPoints {
+                needed: snakes.len(),
+                available,
+            });
+        }
+
+        let dims = Dimensions { width, height };
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let length = initial_length.max(1);
+        let mut taken = vec![false; tiles.len()];
+        // mark out where a snake would go, if it fits there
+        let mut claim = |pos: Position, dir: Direction| {
+            let Position { x, y } = pos;
+            if x >= width || y >= height {
+                return Err(MapError::StartOutOfBounds { x, y });
+            }
+            let cells = dims.snake_layout(pos, dir, length, wrap).ok_or_else(|| {
+                // a snake can only run into itself by wrapping round
+                let wraps = match dir {
+                    Direction::North | Direction::South => wrap.vertical,
+                    Direction::East | Direction::West => wrap.horizontal,
+                };
+                if wraps {
+                    MapError::StartOverlaps { x, y }
+                } else {
+                    MapError::StartOutOfBounds { x, y }
+                }
+            })?;
+            if cells.iter().any(|&Position { x, y }| taken[y * width + x]) {
+                return Err(MapError::StartOverlaps { x, y });
+            }
+            if cells
+                .iter()
+                .any(|&Position { x, y }| tiles[y * width + x] != Tile::Blank)
+            {
+                return Err(MapError::StartNotBlank { x, y });
+            }
+            for &Position { x, y } in &cells {
+                taken[y * width + x] = true;
+            }
+            Ok((dir, cells))
+        };
+
+        let placed: Vec<_> = match starts {
+            Some(starts) => starts
+                .into_iter()
+                .map(|(pos, dir)| claim(pos, dir))
+                .collect::<Result<_, _>>()?,
+            None => {
+                // try every spot and direction in a random order, until everyone fits
+                let mut candidates: Vec<_> = (0..tiles.len())
+                    .filter(|&i| tiles[i] == Tile::Blank)
+                    .flat_map(|i| {
+                        let pos = Position {
+                            x: i % width,
+                            y: i / width,
+                        };
+                        DIRECTIONS.iter().map(move |&dir| (pos, dir))
+                    })
+                    .collect();
+                candidates.shuffle(&mut rng);
+
+                let placed: Vec<_> = match spawn {
+                    SpawnMode::Random => candidates
+                        .into_iter()
+                        .filter_map(|(pos, dir)| claim(pos, dir).ok())
+                        .take(snakes.len())
+                        .collect(),
+                    SpawnMode::Spread => {
+                        let mut placed: Vec<(Direction, Vec<Position>)> = Vec::new();
+                        while placed.len() < snakes.len() {
+                            // spots far out tend to be by walls, so among equally distant
+                            // ones prefer facing open space; the sort is stable, so any
+                            // other ties stay in their random order
+                            let heads: Vec<_> =
+                                placed.iter().map(|(_, cells)| cells[0]).collect();
+                            candidates.sort_by_key(|&(pos, dir)| {
+                                let nearest = heads
+                                    .iter()
+                                    .map(|&head| dims.distance(head, pos, wrap))
+                                    .min();
+                                let open =
+                                    dims.neighbor(pos, dir, wrap).is_some_and(|next| {
+                                        tiles[next.y * width + next.x] == Tile::Blank
+                                    });
+                                (std::cmp::Reverse(nearest.unwrap_or(0)), !open)
+                            });
+                            match candidates
+                                .iter()
+                                .find_map(|&(pos, dir)| claim(pos, dir).ok())
+                            {
+                                Some(spot) => placed.push(spot),
+                                None => break,
+                            }
+                        }
+                        placed
+                    }
+                };
+                if placed.len() < snakes.len() {
+                    return Err(MapError::NotEnoughSpawnPoints {
+                        needed: snakes.len(),
+                        available: placed.len(),
+                    });
+                }
+                placed
+            }
+        };
 
         let snakes = snakes
             .into_iter()
-            .zip(blank_spots.into_iter().map(|pos| Snake::new(random(), pos)))
+            .zip(placed.into_iter().map(|(dir, mut cells)| {
+                let head = cells.remove(0);
+                Snake::new(dir, head, cells.into_iter().rev().collect())
+            }))
             .collect::<HashMap<_, _>>();
 
         let scores = snakes
@@ -211,34 +994,294 @@ impl Map {
             .collect();
 
         let mut me = Map {
-            dims: Dimensions { width, height },
+            dims,
             tiles,
             scores,
             snakes,
+            eliminations: Vec::new(),
+            causes: HashMap::new(),
+            deleted: Vec::new(),
+            wrap,
+            doodah_count,
+            doodah_weights: vec![1],
+            turn_penalty: 0,
+            scoring: ScoringMode::DoodahsOnly,
+            shrink_after: None,
+            shrink_interval: 1,
+            turn: 0,
+            rng,
         };
         me.place_snakes();
-        me.place_doodah();
-
-        me
-    }
+        me.place_doodahs();
 
-    /// Turn the given snake to the left.
-    pub fn turn_left(&mut self, id: SnakeID) {
-        if let Some(snake) = self.snakes.get_mut(&id) {
-            snake.dir = snake.dir.left();
-        }
+        Ok(me)
     }
 
-    /// Turn the given snake to the right.
-    pub fn turn_right(&mut self, id: SnakeID) {
-        if let Some(snake) = self.snakes.get_mut(&id) {
-            snake.dir = snake.dir.right();
-        }
+    /// Check that a layout can be used to create a map; see [`validate`].
+    ///
+    /// [`validate`]: fn.validate.html
+    pub fn validate(
+        width: usize,
+        height: usize,
+        tiles: &[Tile],
+    ) -> Result<(), ValidationError> {
+        validate(width, height, tiles)
     }
 
-    /// Delete the given snake.
-    pub fn delete_snake(&mut self, id: SnakeID) {
-        self.snakes.remove(&id);
+    /// Parse a layout from ASCII art, where `#` is a wall, `x` is a hazard and `.` is
+    /// blank.
+    ///
+    /// Each line of `text` is a row of the map, and all rows must be the same width.
+    /// Whitespace around each row, as well as empty lines, are ignored.
+    ///
+    /// Returns the width, height, and tiles of the layout, suitable for [`Map::new`].
+    ///
+    /// [`Map::new`]: struct.Map.html#method.new
+    pub fn from_ascii(text: &str) -> Result<(usize, usize, Vec<Tile>), ParseError> {
+        let mut width = None;
+        let mut height = 0;
+        let mut tiles = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let expected = *width.get_or_insert_with(|| line.chars().count());
+            let found = line.chars().count();
+            if found != expected {
+                return Err(ParseError::Ragged {
+                    line: i + 1,
+                    expected,
+                    found,
+                });
+            }
+
+            for (j, c) in line.chars().enumerate() {
+                tiles.push(match c {
+                    '#' => Tile::Wall,
+                    'x' => Tile::Hazard,
+                    '.' => Tile::Blank,
+                    found => {
+                        return Err(ParseError::UnknownTile {
+                            line: i + 1,
+                            column: j + 1,
+                            found,
+                        })
+                    }
+                });
+            }
+            height += 1;
+        }
+
+        match width {
+            Some(width) => Ok((width, height, tiles)),
+            None => Err(ParseError::Empty),
+        }
+    }
+
+    /// Render the map as ASCII art, one line per row.
+    ///
+    /// Walls are `#`, hazards are `x`, blank tiles are `.`, and doodahs are `*`. Snake
+    /// heads are shown as the last digit of their ID, and their bodies as `o`.
+    pub fn to_ascii(&self) -> String {
+        self.render(|id, _| std::char::from_digit((id % 10) as u32, 10).unwrap())
+    }
+
+    /// Render the map as ASCII art like [`to_ascii`], but with snake heads pointing
+    /// the way they're facing.
+    ///
+    /// Rows are in the same order as the tiles, so north is towards the bottom: a
+    /// snake heading north is shown as `v`, and one heading south as `^`. East and
+    /// west are `>` and `<`.
+    ///
+    /// [`to_ascii`]: #method.to_ascii
+    pub fn to_ascii_facing(&self) -> String {
+        self.render(|_, dir| match dir {
+            Direction::North => 'v',
+            Direction::East => '>',
+            Direction::South => '^',
+            Direction::West => '<',
+        })
+    }
+
+    /// Render the map as ASCII art, drawing snake heads with `head`.
+    fn render<F: Fn(SnakeID, Direction) -> char>(&self, head: F) -> String {
+        let mut text = String::with_capacity((self.dims.width + 1) * self.dims.height);
+        for row in self.tiles.chunks(self.dims.width) {
+            for &tile in row {
+                text.push(match tile {
+                    Tile::SnakeBody { .. } => 'o',
+                    Tile::SnakeHead { id, dir } => head(id, dir),
+                    Tile::Doodah { .. } => '*',
+                    Tile::Wall => '#',
+                    Tile::Hazard => 'x',
+                    Tile::Blank => '.',
+                });
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Turn the given snake to the left.
+    pub fn turn_left(&mut self, id: SnakeID) {
+        if let Some(snake) = self.snakes.get_mut(&id) {
+            snake.dir = snake.dir.left();
+            snake.penalty += self.turn_penalty;
+        }
+    }
+
+    /// Turn the given snake to the right.
+    pub fn turn_right(&mut self, id: SnakeID) {
+        if let Some(snake) = self.snakes.get_mut(&id) {
+            snake.dir = snake.dir.right();
+            snake.penalty += self.turn_penalty;
+        }
+    }
+
+    /// Delete the given snake, recording why it's gone.
+    pub fn delete_snake(&mut self, id: SnakeID, cause: DeathCause) {
+        if self.snakes.remove(&id).is_some() {
+            let score = self.scores.get(&id).copied().unwrap_or(0);
+            self.deleted.push(Elimination { id, score });
+            self.causes.insert(id, cause);
+        }
+    }
+
+    /// Record every snake still alive as having survived the game.
+    pub fn mark_survivors(&mut self) {
+        for &id in self.snakes.keys() {
+            self.causes.insert(id, DeathCause::Survived);
+        }
+    }
+
+    /// Get the position and tile one step away from `pos` in the direction `dir`,
+    /// wrapping around the edges of the map that wrap.
+    ///
+    /// Returns `None` if that would move off an edge that doesn't wrap.
+    pub fn neighbor(&self, pos: Position, dir: Direction) -> Option<(Position, Tile)> {
+        self.dims
+            .neighbor(pos, dir, self.wrap)
+            .map(|next| (next, self.tiles[self.to_index(next)]))
+    }
+
+    /// Get the position and tile in each direction from `pos`, in the order north, east,
+    /// south and west, as given by [`neighbor`].
+    ///
+    /// Directions that would move off an edge that doesn't wrap have `None`.
+    ///
+    /// [`neighbor`]: #method.neighbor
+    pub fn neighbors(&self, pos: Position) -> [(Direction, Option<(Position, Tile)>); 4] {
+        [
+            Direction::North,
+            Direction::East,
+            Direction::South,
+            Direction::West,
+        ]
+        .map(|dir| (dir, self.neighbor(pos, dir)))
+    }
+
+    /// Get the tiles next to a living snake's head.
+    pub fn surroundings(&self, id: SnakeID) -> Option<Surroundings> {
+        let head = self.snakes.get(&id)?.head;
+        let tile = |dir| self.neighbor(head, dir).map(|(_, tile)| tile);
+        Some(Surroundings {
+            head,
+            north: tile(Direction::North),
+            east: tile(Direction::East),
+            south: tile(Direction::South),
+            west: tile(Direction::West),
+        })
+    }
+
+    /// Get the positions of every doodah on the map, in index order.
+    pub fn doodah_positions(&self) -> Vec<Position> {
+        self.positions_of(|tile| matches!(tile, Tile::Doodah { .. }))
+    }
+
+    /// Get the positions of every wall on the map, in index order.
+    pub fn wall_positions(&self) -> Vec<Position> {
+        self.positions_of(|tile| tile == Tile::Wall)
+    }
+
+    /// Get the positions of every blank tile on the map, in index order.
+    pub fn blank_positions(&self) -> Vec<Position> {
+        self.positions_of(|tile| tile == Tile::Blank)
+    }
+
+    /// Get a living snake.
+    pub fn snake(&self, id: SnakeID) -> Option<&Snake> {
+        self.snakes.get(&id)
+    }
+
+    /// Get the position of a living snake's head.
+    pub fn snake_head_position(&self, id: SnakeID) -> Option<Position> {
+        self.snakes.get(&id).map(|snake| snake.head)
+    }
+
+    /// Get the positions of every tile matching `pred`, in index order.
+    fn positions_of(&self, pred: impl Fn(Tile) -> bool) -> Vec<Position> {
+        let width = self.dims.width;
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|&(_, &t)| pred(t))
+            .map(|(i, _)| Position {
+                x: i % width,
+                y: i / width,
+            })
+            .collect()
+    }
+
+    /// Get every position that can be reached from `from` by moving through blank
+    /// tiles and doodahs, wrapping around the edges of the map that wrap. Walls,
+    /// hazards and snakes block the way.
+    ///
+    /// `from` itself is always included (whatever is on it), and comes first; the
+    /// rest are in order of distance from it. Returns nothing if `from` is off the map.
+    pub fn reachable_cells(&self, from: Position) -> Vec<Position> {
+        let mut cells = Vec::new();
+        self.search(from, |pos| {
+            cells.push(pos);
+            false
+        });
+        cells
+    }
+
+    /// Test if `to` can be reached from `from`, as in `reachable_cells`.
+    pub fn path_exists(&self, from: Position, to: Position) -> bool {
+        self.search(from, |pos| pos == to)
+    }
+
+    /// Breadth-first search of the open tiles reachable from `from`, stopping early if
+    /// `found` returns `true` for a position. Returns whether the search was stopped.
+    fn search(&self, from: Position, mut found: impl FnMut(Position) -> bool) -> bool {
+        if from.x >= self.dims.width || from.y >= self.dims.height {
+            return false;
+        }
+
+        let mut seen = vec![false; self.tiles.len()];
+        let mut queue = VecDeque::new();
+        seen[self.to_index(from)] = true;
+        queue.push_back(from);
+        while let Some(pos) = queue.pop_front() {
+            if found(pos) {
+                return true;
+            }
+            for &dir in &DIRECTIONS {
+                if let Some((next, tile)) = self.neighbor(pos, dir) {
+                    let idx = self.to_index(next);
+                    let open = matches!(tile, Tile::Blank | Tile::Doodah { .. });
+                    if !seen[idx] && open {
+                        seen[idx] = true;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        false
     }
 
     /// Test if a snake is still alive.
@@ -246,22 +1289,133 @@ impl Map {
         self.snakes.get(&id).is_some()
     }
 
+    /// Count the snakes still alive.
+    pub fn alive_count(&self) -> usize {
+        self.snakes.len()
+    }
+
+    /// Get how many steps have been taken so far.
+    pub fn turn(&self) -> usize {
+        self.turn
+    }
+
+    /// Get the number of doodahs the map tries to keep on the board.
+    pub fn doodah_count(&self) -> usize {
+        self.doodah_count
+    }
+
+    /// Change the number of doodahs the map tries to keep on the board.
+    ///
+    /// Extra doodahs are placed immediately. If the count is lowered, existing doodahs
+    /// are left alone until they get eaten.
+    pub fn set_doodah_count(&mut self, count: usize) -> Result<(), &'static str> {
+        let space = self
+            .tiles
+            .iter()
+            .filter(|&&t| matches!(t, Tile::Blank | Tile::Doodah { .. }))
+            .count();
+        if count > space {
+            return Err("not enough free space for that many doodahs");
+        }
+
+        self.doodah_count = count;
+        self.place_doodahs();
+        Ok(())
+    }
+
+    /// Change how likely new doodahs are to be worth each value: a doodah is worth
+    /// `i + 1` with a chance proportional to `weights[i]`. If every weight is zero,
+    /// doodahs are worth one.
+    ///
+    /// Doodahs already on the board are given new values too, so this is best done
+    /// before the first step.
+    pub fn set_doodah_weights(&mut self, weights: Vec<u32>) {
+        self.doodah_weights = weights;
+        for idx in 0..self.tiles.len() {
+            if let Tile::Doodah { .. } = self.tiles[idx] {
+                let value = self.doodah_value();
+                self.tiles[idx] = Tile::Doodah { value };
+            }
+        }
+    }
+
+    /// Get the ranking of a snake by score, where `1` is the best.
+    ///
+    /// Snakes with equal scores share the same rank.
+    pub fn rank(&self, id: SnakeID) -> Option<usize> {
+        let score = *self.scores.get(&id)?;
+        Some(1 + self.scores.values().filter(|&&s| s > score).count())
+    }
+
+    /// Get the changes needed to turn the `old` map into this one.
+    ///
+    /// # Panics
+    ///
+    /// Both maps must have the same dimensions.
+    pub fn diff<'a>(&'a self, old: &Map) -> MapDelta<'a> {
+        assert!(self.dims == old.dims);
+
+        let changes = self
+            .tiles
+            .iter()
+            .zip(old.tiles.iter())
+            .enumerate()
+            .filter(|&(_, (new, old))| new != old)
+            .map(|(index, (&tile, _))| TileChange { index, tile })
+            .collect();
+
+        MapDelta {
+            changes,
+            scores: &self.scores,
+            eliminations: &self.eliminations,
+        }
+    }
+
+    /// Update the tiles with changes from a [`MapDelta`], as made by [`diff`].
+    ///
+    /// [`MapDelta`]: struct.MapDelta.html
+    /// [`diff`]: #method.diff
+    pub fn apply_changes(&mut self, changes: &[TileChange]) {
+        for &TileChange { index, tile } in changes {
+            self.tiles[index] = tile;
+        }
+    }
+
     /// Convert from a position to a tile index.
-    fn to_index(&self, (x, y): Position) -> usize {
+    fn to_index(&self, Position { x, y }: Position) -> usize {
         x + y * self.dims.width
     }
 
     /// Get the new map after a time step.
-    pub fn step(mut self) -> Result<Self, HashMap<SnakeID, usize>> {
+    ///
+    /// Once no snakes are left the game is over, and the final map is returned as an
+    /// error instead.
+    pub fn step(mut self) -> Result<Self, Box<Self>> {
         // rebuild tile map, getting rid of the snakes
         self.cleanup_board();
 
-        // move the snake and see if they got the doodah
-        let got_doodah = self.move_snakes();
+        // close in the border, so snakes moving onto it die like on any other wall
+        self.turn += 1;
+        self.close_border();
+
+        // move the snake and see if they got any doodahs
+        let alive: Vec<_> = self.snakes.keys().copied().collect();
+        let got_doodahs = self.move_snakes();
+
+        // keep track of who died this step
+        let mut eliminations = std::mem::take(&mut self.deleted);
+        for id in alive {
+            if !self.snakes.contains_key(&id) {
+                let score = self.scores.get(&id).copied().unwrap_or(0);
+                eliminations.push(Elimination { id, score });
+            }
+        }
+        eliminations.sort_unstable_by_key(|e| e.id);
+        self.eliminations = eliminations;
 
         // if we're out of snakes, we're done
         if self.snakes.is_empty() {
-            return Err(self.scores);
+            return Err(Box::new(self));
         }
 
         // fill in the tiles with the still living snakes
@@ -270,18 +1424,18 @@ impl Map {
         // fix up the scores
         self.update_scores();
 
-        // replace the doodah if it was picked up
-        if let Some(coord) = got_doodah {
+        // replace the doodahs that were picked up
+        for coord in got_doodahs {
             // if it wasn't covered by a snake, get rid of it first
             let idx = self.to_index(coord);
-            if let Tile::Doodah = self.tiles[idx] {
+            if let Tile::Doodah { .. } = self.tiles[idx] {
                 self.tiles[idx] = Tile::Blank;
             }
-
-            // place down a new doodah
-            self.place_doodah();
         }
 
+        // place down new doodahs
+        self.place_doodahs();
+
         // return the new details
         Ok(self)
     }
@@ -296,6 +1450,37 @@ impl Map {
         }
     }
 
+    /// Count the rings of the border that have closed in once `turn` steps are taken.
+    fn closed_rings(&self, turn: usize) -> usize {
+        match self.shrink_after {
+            Some(after) if turn >= after => {
+                (turn - after) / self.shrink_interval.max(1) + 1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Turn the blank tiles and doodahs in every closed ring of the border into walls.
+    ///
+    /// Should be called after `cleanup_board', since snakes would otherwise cover up
+    /// tiles in the rings.
+    fn close_border(&mut self) {
+        let closed = self.closed_rings(self.turn);
+        if closed == 0 {
+            return;
+        }
+
+        let dims = self.dims;
+        for (idx, tile) in self.tiles.iter_mut().enumerate() {
+            match tile {
+                Tile::Blank | Tile::Doodah { .. } if dims.ring(idx) < closed => {
+                    *tile = Tile::Wall
+                }
+                _ => (),
+            }
+        }
+    }
+
     /// Place all snake parts onto the board
     fn place_snakes(&mut self) {
         for (&id, snake) in self.snakes.iter() {
@@ -312,65 +1497,1050 @@ impl Map {
     ///
     /// Should be called after `cleanup_board'.
     ///
-    /// If a snake got the doodah, returns the doodah's position. Assumes only
-    /// one doodah exists at a time.
-    fn move_snakes(&mut self) -> Option<Position> {
-        // move snakes one step, removing snakes that hit walls
-        let mut got_doodah = None;
+    /// Returns the positions of all doodahs that were eaten.
+    fn move_snakes(&mut self) -> Vec<Position> {
+        // remember where everyone was, to catch snakes passing through each other
+        let old_heads: HashMap<_, _> = self
+            .snakes
+            .iter()
+            .map(|(&id, snake)| (id, snake.head))
+            .collect();
+
+        // move snakes one step, removing snakes that hit walls, hazards or unwrapped edges
+        let mut got_doodahs = Vec::new();
+        let mut causes = HashMap::new();
         let mut snake_copy = std::mem::replace(&mut self.snakes, HashMap::new());
-        snake_copy.retain(|_, snake| {
-            let new_head = snake.next_head_pos(self.dims);
+        snake_copy.retain(|&id, snake| {
+            let new_head = match snake.next_head_pos(self.dims, self.wrap) {
+                Some(pos) => pos,
+                None => {
+                    causes.insert(id, DeathCause::Wall);
+                    return false;
+                }
+            };
             let head_idx = self.to_index(new_head);
             match self.tiles.get(head_idx).unwrap() {
-                Tile::Doodah => {
-                    snake.grow(self.dims);
-                    got_doodah = Some(new_head);
+                Tile::Doodah { value } => {
+                    // the rest of the value is grown over the next steps
+                    snake.grow(new_head);
+                    snake.growth += value.saturating_sub(1);
+                    got_doodahs.push(new_head);
+                    true
+                }
+                Tile::Blank if snake.growth > 0 => {
+                    snake.growth -= 1;
+                    snake.grow(new_head);
                     true
                 }
                 Tile::Blank => {
-                    snake.step(self.dims);
+                    snake.step(new_head);
                     true
                 }
-                Tile::Wall => false,
+                Tile::Wall | Tile::Hazard => {
+                    causes.insert(id, DeathCause::Wall);
+                    false
+                }
                 _ => panic!("Must call `cleanup_board` first!"),
             }
         });
 
-        // remove snakes that have collided with each other
+        // remove snakes that have collided with each other; this only looks at where
+        // every snake ended up, so it doesn't matter which order they moved in
         self.snakes = snake_copy.clone();
-        self.snakes.retain(|id, snake| {
-            !snake_copy.iter().any(|(oid, other)| {
-                if id == oid {
-                    snake.has_self_collided()
-                } else {
-                    snake.has_collided(other)
+        self.snakes.retain(|&id, snake| {
+            if snake.has_self_collided() {
+                causes.insert(id, DeathCause::SelfCollision);
+                return false;
+            }
+
+            let hit = snake_copy.iter().find(|&(&oid, other)| {
+                // snakes without a tail could otherwise swap places head-on
+                let swapped =
+                    old_heads[&id] == other.head && old_heads[&oid] == snake.head;
+                oid != id && (swapped || snake.has_collided(other))
+            });
+            match hit {
+                Some((&with, _)) => {
+                    causes.insert(id, DeathCause::Collision { with });
+                    false
                 }
-            })
+                None => true,
+            }
         });
 
-        got_doodah
+        self.causes.extend(causes);
+        got_doodahs
     }
 
     /// Update the scores for living snakes
+    ///
+    /// Every living snake has survived all the turns so far, so survival points are
+    /// worked out from the turn count; dead snakes keep the score they died with.
     fn update_scores(&mut self) {
+        let turn = self.turn;
+        let survival = |per_turn: usize| turn * per_turn;
         for (&id, snake) in self.snakes.iter() {
-            self.scores.insert(id, snake.score());
+            let score = match self.scoring {
+                ScoringMode::DoodahsOnly => snake.score(),
+                ScoringMode::SurvivalTime { per_turn } => survival(per_turn),
+                ScoringMode::Both { per_turn } => snake.score() + survival(per_turn),
+            };
+            self.scores.insert(id, score);
         }
     }
 
-    /// Place a doodah randomly on a blank tile, if one exists.
-    fn place_doodah(&mut self) {
-        let new_spot = self
+    /// Place doodahs randomly on blank tiles until there are `doodah_count` of them.
+    ///
+    /// Tiles in the next ring of the border to close in are left alone, so doodahs
+    /// aren't walled up before snakes can get them.
+    fn place_doodahs(&mut self) {
+        let existing = self
+            .tiles
+            .iter()
+            .filter(|&&t| matches!(t, Tile::Doodah { .. }))
+            .count();
+        let closing = self.closed_rings(self.turn + self.shrink_interval.max(1));
+        let dims = self.dims;
+        let new_spots = self
             .tiles
             .iter()
             .enumerate()
-            .filter(|(_, &tile)| tile == Tile::Blank)
+            .filter(|&(i, &tile)| tile == Tile::Blank && dims.ring(i) >= closing)
             .map(|(i, _)| i)
-            .choose(&mut thread_rng());
+            .choose_multiple(&mut self.rng, self.doodah_count.saturating_sub(existing));
+
+        // if there aren't enough free spots, don't worry about it
+        for idx in new_spots {
+            let value = self.doodah_value();
+            self.tiles[idx] = Tile::Doodah { value };
+        }
+    }
+
+    /// Pick how much a new doodah is worth, as weighted by `doodah_weights`.
+    fn doodah_value(&mut self) -> usize {
+        let values: Vec<_> = (1..).zip(self.doodah_weights.iter().copied()).collect();
+        values
+            .choose_weighted(&mut self.rng, |&(_, weight)| weight)
+            .map_or(1, |&(value, _)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opposite_directions() {
+        use Direction::*;
+        assert_eq!(North.opposite(), South);
+        assert_eq!(East.opposite(), West);
+        assert_eq!(South.opposite(), North);
+        assert_eq!(West.opposite(), East);
+    }
+
+    #[test]
+    fn turning_directions() {
+        use Direction::*;
+        let turns = [
+            (North, West, East),
+            (East, North, South),
+            (South, East, West),
+            (West, South, North),
+        ];
+        for &(dir, left, right) in &turns {
+            assert_eq!(dir.left(), left);
+            assert_eq!(dir.right(), right);
+        }
+    }
+
+    #[test]
+    fn directions_are_sampled_evenly() {
+        let mut rng = StdRng::seed_from_u64(501);
+        let mut counts = [0; 4];
+        for _ in 0..8000 {
+            let dir: Direction = rng.gen();
+            counts[DIRECTIONS.iter().position(|&d| d == dir).unwrap()] += 1;
+        }
+        for (dir, &count) in DIRECTIONS.iter().zip(&counts) {
+            assert!(
+                (1800..=2200).contains(&count),
+                "{:?} sampled {} times",
+                dir,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn snakes_spawn_facing_every_direction() {
+        let mut counts = [0; 4];
+        for seed in 0..400 {
+            let options = MapOptions {
+                seed: Some(seed),
+                ..MapOptions::default()
+            };
+            let map = Map::new(5, 5, vec![Tile::Blank; 25], vec![0], options).unwrap();
+            let dir = map.snake(0).unwrap().dir;
+            counts[DIRECTIONS.iter().position(|&d| d == dir).unwrap()] += 1;
+        }
+        for (dir, &count) in DIRECTIONS.iter().zip(&counts) {
+            assert!(
+                (60..=140).contains(&count),
+                "{:?} spawned {} times",
+                dir,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn direction_helpers_agree() {
+        for &d in &DIRECTIONS {
+            assert_eq!(d.opposite().opposite(), d);
+            assert_eq!(d.right().right(), d.opposite());
+            assert_eq!(d.left().left(), d.opposite());
+            assert_eq!(d.left().right(), d);
+            assert_eq!(d.right().left(), d);
+            assert_ne!(d.opposite(), d);
+        }
+    }
+
+    /// A map with the layout drawn in `ascii` and no doodahs, with snakes `initial_length`
+    /// long starting where `starts` says.
+    fn map_with_starts(
+        ascii: &str,
+        starts: Vec<((usize, usize), Direction)>,
+        wrap: WrapConfig,
+        initial_length: usize,
+    ) -> Result<Map, MapError> {
+        let (width, height, tiles) = Map::from_ascii(ascii).unwrap();
+        let snakes = (0..starts.len()).collect();
+        let starts = starts.into_iter().map(|(pos, dir)| (pos.into(), dir));
+        let options = MapOptions {
+            starts: Some(starts.collect()),
+            wrap,
+            initial_length,
+            seed: Some(0),
+            ..MapOptions::default()
+        };
+        Map::new(width, height, tiles, snakes, options)
+    }
+
+    #[test]
+    fn snakes_start_where_asked() {
+        let starts = vec![((1, 0), Direction::East), ((2, 2), Direction::North)];
+        let map = map_with_starts("....\n....\n....", starts, true.into(), 2).unwrap();
+
+        let first = map.snake(0).unwrap();
+        assert_eq!(first.head, Position { x: 1, y: 0 });
+        assert_eq!(first.dir, Direction::East);
+        assert_eq!(
+            first.segments().collect::<Vec<_>>(),
+            [(1, 0).into(), (0, 0).into()]
+        );
+        let second = map.snake(1).unwrap();
+        assert_eq!(second.head, Position { x: 2, y: 2 });
+        assert_eq!(second.segments().nth(1), Some((2, 1).into()));
+        assert_eq!(map.to_ascii(), "o0..\n..o.\n..1.\n");
+    }
+
+    #[test]
+    fn start_count_must_match_snakes() {
+        let (width, height, tiles) = Map::from_ascii("...").unwrap();
+        let options = MapOptions {
+            starts: Some(vec![((0, 0).into(), Direction::East)]),
+            ..MapOptions::default()
+        };
+        let result = Map::new(width, height, tiles, vec![0, 1], options);
+        assert_eq!(
+            result.unwrap_err(),
+            MapError::WrongStartCount {
+                snakes: 2,
+                starts: 1
+            }
+        );
+    }
 
-        // if there's no free spot, don't worry about it
-        if let Some(idx) = new_spot {
-            self.tiles[idx] = Tile::Doodah;
+    #[test]
+    fn start_on_wall_is_refused() {
+        let starts = vec![((1, 0), Direction::East)];
+        let result = map_with_starts(".#.", starts, true.into(), 1);
+        assert_eq!(result.unwrap_err(), MapError::StartNotBlank { x: 1, y: 0 });
+
+        // the body counts too
+        let starts = vec![((2, 0), Direction::East)];
+        let result = map_with_starts(".#.", starts, true.into(), 2);
+        assert_eq!(result.unwrap_err(), MapError::StartNotBlank { x: 2, y: 0 });
+    }
+
+    #[test]
+    fn overlapping_starts_are_refused() {
+        let starts = vec![((1, 0), Direction::East), ((1, 0), Direction::West)];
+        let result = map_with_starts("....", starts, true.into(), 1);
+        assert_eq!(result.unwrap_err(), MapError::StartOverlaps { x: 1, y: 0 });
+
+        // the second snake's tail runs into the first
+        let starts = vec![((1, 0), Direction::East), ((3, 0), Direction::East)];
+        let result = map_with_starts("....", starts, true.into(), 3);
+        assert_eq!(result.unwrap_err(), MapError::StartOverlaps { x: 3, y: 0 });
+
+        // too long to fit round the map
+        let starts = vec![((1, 0), Direction::East)];
+        let result = map_with_starts("...", starts, true.into(), 4);
+        assert_eq!(result.unwrap_err(), MapError::StartOverlaps { x: 1, y: 0 });
+    }
+
+    #[test]
+    fn starts_off_the_map_are_refused() {
+        let starts = vec![((3, 0), Direction::East)];
+        let result = map_with_starts("...", starts, true.into(), 1);
+        assert_eq!(
+            result.unwrap_err(),
+            MapError::StartOutOfBounds { x: 3, y: 0 }
+        );
+
+        // the tail would hang off an edge that doesn't wrap
+        let starts = vec![((0, 0), Direction::East)];
+        let result = map_with_starts("...", starts, false.into(), 2);
+        assert_eq!(
+            result.unwrap_err(),
+            MapError::StartOutOfBounds { x: 0, y: 0 }
+        );
+    }
+
+    #[test]
+    fn step_records_eliminations() {
+        let starts = vec![((1, 0), Direction::West), ((1, 1), Direction::East)];
+        let map = map_with_starts("#...\n....", starts, true.into(), 1).unwrap();
+
+        let mut map = map.step().unwrap();
+        assert_eq!(map.eliminations, [Elimination { id: 0, score: 0 }]);
+        assert_eq!(map.causes[&0], DeathCause::Wall);
+        assert!(!map.is_alive(0));
+        assert!(map.is_alive(1));
+
+        // snakes taken out between steps are reported with the next one
+        map.delete_snake(1, DeathCause::Quit);
+        let map = map.step().unwrap_err();
+        assert_eq!(map.eliminations, [Elimination { id: 1, score: 0 }]);
+        assert_eq!(map.causes[&1], DeathCause::Quit);
+        assert_eq!(map.alive_count(), 0);
+    }
+
+    #[test]
+    fn step_without_deaths_has_no_eliminations() {
+        let starts = vec![((1, 0), Direction::East)];
+        let map = map_with_starts("....", starts, true.into(), 1).unwrap();
+        let map = map.step().unwrap();
+        assert!(map.eliminations.is_empty());
+        assert_eq!(map.snake_head_position(0), Some((2, 0).into()));
+    }
+
+    /// A seeded game of three snakes on an open `width` by `height` map with a few
+    /// things to eat, played for up to `steps` steps with the snakes turning at random.
+    /// Gives every map, starting with the first.
+    fn play(width: usize, height: usize, steps: usize, seed: u64) -> Vec<Map> {
+        let options = MapOptions {
+            doodah_count: 4,
+            initial_length: 2,
+            seed: Some(seed),
+            ..MapOptions::default()
+        };
+        let tiles = vec![Tile::Blank; width * height];
+        let mut map = Map::new(width, height, tiles, vec![0, 1, 2], options).unwrap();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut maps = vec![map.clone()];
+        for _ in 0..steps {
+            for id in 0..3 {
+                match rng.gen_range(0, 3) {
+                    0 => map.turn_left(id),
+                    1 => map.turn_right(id),
+                    _ => {}
+                }
+            }
+            match map.step() {
+                Ok(next) => map = next,
+                Err(last) => {
+                    maps.push(*last);
+                    break;
+                }
+            }
+            maps.push(map.clone());
+        }
+        maps
+    }
+
+    #[test]
+    fn applying_deltas_rebuilds_each_map() {
+        let maps = play(8, 8, 40, 3);
+        let mut client = maps[0].clone();
+        for pair in maps.windows(2) {
+            // go through the wire format, as a client would
+            let json = serde_json::to_string(&pair[1].diff(&pair[0])).unwrap();
+            let delta: serde_json::Value = serde_json::from_str(&json).unwrap();
+            let changes: Vec<TileChange> =
+                serde_json::from_value(delta["changes"].clone()).unwrap();
+            client.apply_changes(&changes);
+            assert_eq!(client.tiles, pair[1].tiles);
+        }
+    }
+
+    #[test]
+    fn delta_changes_are_objects() {
+        let maps = play(8, 8, 1, 3);
+        let delta = serde_json::to_value(maps[1].diff(&maps[0])).unwrap();
+        let change = &delta["changes"][0];
+        assert!(change["index"].is_u64());
+        assert!(change["tile"]["type"].is_string());
+    }
+
+    #[test]
+    fn deltas_are_smaller_than_maps() {
+        let maps = play(20, 16, 20, 5);
+        let (mut full, mut deltas) = (0, 0);
+        for pair in maps.windows(2) {
+            full += serde_json::to_string(&pair[1]).unwrap().len();
+            deltas += serde_json::to_string(&pair[1].diff(&pair[0]))
+                .unwrap()
+                .len();
+        }
+        println!(
+            "{} steps: {} bytes of maps, {} bytes of deltas ({:.1}% saved)",
+            maps.len() - 1,
+            full,
+            deltas,
+            100.0 * (full - deltas) as f64 / full as f64
+        );
+        // only a few tiles change each step, out of 320
+        assert!(deltas * 5 < full);
+    }
+
+    /// Where a snake starts, which way it's heading, and where it ends up.
+    type Move = ((usize, usize), Direction, (usize, usize));
+
+    /// Each edge of a 3x3 map, as a snake heading off it from the middle of that edge,
+    /// and where it comes back in if the edge wraps.
+    const EDGES: [Move; 4] = [
+        ((1, 2), Direction::North, (1, 0)),
+        ((2, 1), Direction::East, (0, 1)),
+        ((1, 0), Direction::South, (1, 2)),
+        ((0, 1), Direction::West, (2, 1)),
+    ];
+
+    #[test]
+    fn snakes_wrap_round_every_edge() {
+        for &(start, dir, end) in &EDGES {
+            let map =
+                map_with_starts("...\n...\n...", vec![(start, dir)], true.into(), 1);
+            let map = map.unwrap().step().unwrap();
+            assert_eq!(map.snake_head_position(0), Some(end.into()), "{:?}", dir);
+            assert!(map.eliminations.is_empty());
+        }
+    }
+
+    #[test]
+    fn snakes_die_off_every_edge_without_wrapping() {
+        for &(start, dir, _) in &EDGES {
+            let map =
+                map_with_starts("...\n...\n...", vec![(start, dir)], false.into(), 1);
+            let map = map.unwrap().step().unwrap_err();
+            assert_eq!(map.causes[&0], DeathCause::Wall, "{:?}", dir);
+            assert_eq!(map.eliminations, [Elimination { id: 0, score: 0 }]);
+        }
+    }
+
+    #[test]
+    fn edges_wrap_separately() {
+        let wrap = WrapConfig {
+            horizontal: true,
+            vertical: false,
+        };
+        for &(start, dir, end) in &EDGES {
+            let map =
+                map_with_starts("...\n...\n...", vec![(start, dir)], wrap, 1).unwrap();
+            match dir {
+                Direction::East | Direction::West => {
+                    let map = map.step().unwrap();
+                    assert_eq!(map.snake_head_position(0), Some(end.into()));
+                }
+                Direction::North | Direction::South => {
+                    let map = map.step().unwrap_err();
+                    assert_eq!(map.causes[&0], DeathCause::Wall);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cylinder_wraps_sideways_but_not_off_the_top() {
+        let cylinder = WrapConfig {
+            horizontal: true,
+            vertical: false,
+        };
+        let starts = vec![((3, 2), Direction::East)];
+        let mut map = map_with_starts("....\n....\n....", starts, cylinder, 1).unwrap();
+
+        map = map.step().unwrap();
+        assert_eq!(map.snake_head_position(0), Some((0, 2).into()));
+        map.turn_left(0);
+        assert_eq!(map.snake(0).unwrap().dir, Direction::North);
+        let map = map.step().unwrap_err();
+        assert_eq!(map.causes[&0], DeathCause::Wall);
+
+        // going off the bottom is just as deadly
+        let starts = vec![((1, 0), Direction::South)];
+        let map = map_with_starts("....\n....\n....", starts, cylinder, 1).unwrap();
+        assert_eq!(map.step().unwrap_err().causes[&0], DeathCause::Wall);
+    }
+
+    #[test]
+    fn cylinder_distance_only_wraps_sideways() {
+        let dims = Dimensions {
+            width: 10,
+            height: 10,
+        };
+        let cylinder = WrapConfig {
+            horizontal: true,
+            vertical: false,
+        };
+        let (a, b) = ((0, 0).into(), (9, 9).into());
+        assert_eq!(dims.distance(a, b, cylinder), 1 + 9);
+        assert_eq!(dims.distance(a, b, true.into()), 2);
+        assert_eq!(dims.distance(a, b, false.into()), 18);
+    }
+
+    #[test]
+    fn turning_costs_points() {
+        let starts = vec![((0, 0), Direction::East), ((0, 2), Direction::East)];
+        let mut map =
+            map_with_starts(".....\n.....\n.....\n.....", starts, true.into(), 1)
+                .unwrap();
+        map.turn_penalty = 1;
+        // one doodah each, right in front of them
+        map.tiles[1] = Tile::Doodah { value: 3 };
+        map.tiles[11] = Tile::Doodah { value: 3 };
+        map = map.step().unwrap();
+        assert_eq!(map.scores[&0], 3);
+        assert_eq!(map.scores[&1], 3);
+
+        // the same steps, except one snake turns twice along the way
+        map.turn_left(1);
+        map = map.step().unwrap();
+        map.turn_right(1);
+        map = map.step().unwrap();
+        assert_eq!(map.scores[&0], 3);
+        assert_eq!(map.scores[&1], 1);
+
+        // scores don't go below zero
+        for _ in 0..4 {
+            map.turn_left(1);
+        }
+        let map = map.step().unwrap();
+        assert_eq!(map.scores[&1], 0);
+    }
+
+    #[test]
+    fn turning_prints_nothing() {
+        // the turns are made in a copy of this test running on its own, so its output
+        // can be seen without the harness capturing it
+        const CHILD: &str = "SNAKE_ARENA_TURN_TEST_CHILD";
+        if std::env::var_os(CHILD).is_some() {
+            let starts = vec![((1, 1), Direction::East)];
+            let mut map =
+                map_with_starts("...\n...\n...", starts, true.into(), 1).unwrap();
+            println!("<turns>");
+            for _ in 0..8 {
+                map.turn_right(0);
+                map.turn_left(0);
+            }
+            map.turn_right(5);
+            println!("</turns>");
+            return;
+        }
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "game::tests::turning_prints_nothing",
+                "--nocapture",
+            ])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let start = stdout.find("<turns>\n").expect("turns weren't made") + 8;
+        let end = stdout.find("</turns>").unwrap();
+        assert_eq!(&stdout[start..end], "");
+    }
+
+    #[test]
+    fn snakes_swapping_places_head_on_both_die() {
+        // listed either way round, so the outcome can't hang on the order snakes move in
+        let east = ((1, 0), Direction::East);
+        let west = ((2, 0), Direction::West);
+        for starts in &[vec![east, west], vec![west, east]] {
+            let map = map_with_starts("....", starts.clone(), false.into(), 1).unwrap();
+            let map = map.step().unwrap_err();
+            assert!(!map.is_alive(0));
+            assert!(!map.is_alive(1));
+            assert_eq!(map.causes[&0], DeathCause::Collision { with: 1 });
+            assert_eq!(map.causes[&1], DeathCause::Collision { with: 0 });
+        }
+    }
+
+    #[test]
+    fn snakes_meeting_in_one_cell_both_die() {
+        let starts = vec![((0, 0), Direction::East), ((2, 0), Direction::West)];
+        let map = map_with_starts("...\n...", starts, false.into(), 1).unwrap();
+        let map = map.step().unwrap_err();
+        assert_eq!(map.alive_count(), 0);
+        assert_eq!(map.causes[&0], DeathCause::Collision { with: 1 });
+        assert_eq!(map.causes[&1], DeathCause::Collision { with: 0 });
+    }
+
+    #[test]
+    fn long_snakes_spawn_clear_of_walls() {
+        // the only way a snake four long fits is lying along the gap, so nearly every
+        // spot and direction tried first runs into a wall or off the map
+        let (width, height, tiles) = Map::from_ascii("#....#\n#.####").unwrap();
+        for &spawn in &[SpawnMode::Random, SpawnMode::Spread] {
+            for seed in 0..20 {
+                let options = MapOptions {
+                    spawn,
+                    wrap: false.into(),
+                    initial_length: 4,
+                    seed: Some(seed),
+                    ..MapOptions::default()
+                };
+                let map =
+                    Map::new(width, height, tiles.clone(), vec![0], options).unwrap();
+                let ascii = map.to_ascii();
+                assert!(
+                    ascii == "#0ooo#\n#.####\n" || ascii == "#ooo0#\n#.####\n",
+                    "{}",
+                    ascii
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn snakes_too_long_for_the_map_are_refused() {
+        let (width, height, tiles) = Map::from_ascii("#....#\n#.####").unwrap();
+        let options = MapOptions {
+            wrap: false.into(),
+            initial_length: 5,
+            seed: Some(0),
+            ..MapOptions::default()
+        };
+        let err = Map::new(width, height, tiles, vec![0], options).unwrap_err();
+        assert_eq!(
+            err,
+            MapError::NotEnoughSpawnPoints {
+                needed: 1,
+                available: 0
+            }
+        );
+    }
+
+    #[test]
+    fn same_seed_plays_out_the_same() {
+        // the whole history must match, down to where every doodah landed; the maps are
+        // compared as JSON values, since the order scores are written out in can differ
+        let record = |seed| {
+            play(10, 10, 60, seed)
+                .iter()
+                .map(|map| (serde_json::to_value(map).unwrap(), map.to_ascii_facing()))
+                .collect::<Vec<_>>()
+        };
+        let history = record(7);
+        assert!(history.len() > 10);
+        assert_eq!(history, record(7));
+        assert_ne!(history, record(8));
+    }
+
+    #[test]
+    fn eaten_doodahs_are_replaced() {
+        let maps = play(8, 8, 80, 3);
+        for map in &maps {
+            let doodahs = map
+                .tiles
+                .iter()
+                .filter(|t| matches!(t, Tile::Doodah { .. }))
+                .count();
+            assert_eq!(doodahs, 4, "{}", map.to_ascii());
+        }
+        // make sure there was something to replace
+        let last = maps.last().unwrap();
+        assert!(last.scores.values().sum::<usize>() > 0);
+    }
+
+    #[test]
+    fn ascii_shows_each_step() {
+        let starts = vec![((1, 1), Direction::East)];
+        let mut map =
+            map_with_starts("#....\n.....\n.....", starts, true.into(), 2).unwrap();
+        map.tiles[1] = Tile::Doodah { value: 1 };
+
+        let mut drawn = vec![map.to_ascii_facing()];
+        map.turn_left(0);
+        for _ in 0..3 {
+            map = map.step().unwrap();
+            drawn.push(map.to_ascii_facing());
+        }
+        // heading off the bottom, round to the top, and growing on the doodah there
+        let expected = [
+            "#*...\n\
+             o>...\n\
+             .....\n",
+            "#*...\n\
+             .o...\n\
+             .v...\n",
+            "#v...\n\
+             .o...\n\
+             .o...\n",
+            "#o...\n\
+             .v...\n\
+             .o...\n",
+        ];
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn running_off_the_map_or_into_a_hazard_is_hitting_a_wall() {
+        let starts = vec![((0, 0), Direction::West), ((0, 1), Direction::East)];
+        let map = map_with_starts("...\n.x.", starts, false.into(), 1).unwrap();
+        let map = map.step().unwrap_err();
+        assert_eq!(map.causes[&0], DeathCause::Wall);
+        assert_eq!(map.causes[&1], DeathCause::Wall);
+    }
+
+    #[test]
+    fn turning_back_on_itself_is_self_collision() {
+        let starts = vec![((5, 3), Direction::East)];
+        let mut map =
+            map_with_starts(&".......\n".repeat(7), starts, false.into(), 6).unwrap();
+        for _ in 0..2 {
+            map.turn_left(0);
+            map = map.step().unwrap();
+        }
+        map.turn_left(0);
+        let map = map.step().unwrap_err();
+        assert_eq!(map.causes[&0], DeathCause::SelfCollision);
+    }
+
+    #[test]
+    fn running_into_another_body_is_a_collision_with_it() {
+        let starts = vec![((1, 2), Direction::South), ((2, 1), Direction::East)];
+        let map = map_with_starts(&"....\n".repeat(5), starts, false.into(), 3).unwrap();
+        let map = map.step().unwrap();
+        assert_eq!(map.causes[&0], DeathCause::Collision { with: 1 });
+        assert!(map.is_alive(1));
+    }
+
+    #[test]
+    fn snakes_left_at_the_end_survived() {
+        let starts = vec![((0, 0), Direction::East), ((0, 1), Direction::East)];
+        let mut map = map_with_starts("....\n....", starts, false.into(), 1).unwrap();
+        map.delete_snake(1, DeathCause::Quit);
+        map.mark_survivors();
+        assert_eq!(map.causes[&0], DeathCause::Survived);
+        assert_eq!(map.causes[&1], DeathCause::Quit);
+    }
+
+    /// The layout of the built-in Boxed room.
+    const BOXED: &str = "
+        ##########
+        #........#
+        #........#
+        #........#
+        #........#
+        #........#
+        #........#
+        #........#
+        #........#
+        ##########
+    ";
+
+    #[test]
+    fn whole_box_is_reachable_from_inside() {
+        for &wrap in &[false, true] {
+            let map = map_with_starts(BOXED, vec![], wrap.into(), 1).unwrap();
+            let cells = map.reachable_cells((1, 1).into());
+            assert_eq!(cells.len(), 64);
+            assert_eq!(cells[0], (1, 1).into());
+            assert!(cells[1..3].contains(&(2, 1).into()));
+            assert!(cells[1..3].contains(&(1, 2).into()));
+            assert_eq!(cells[63], (8, 8).into());
+            assert!(cells
+                .iter()
+                .all(|&Position { x, y }| (1..9).contains(&x) && (1..9).contains(&y)));
+
+            assert!(map.path_exists((1, 1).into(), (8, 8).into()));
+            assert!(!map.path_exists((1, 1).into(), (0, 0).into()));
+        }
+    }
+
+    #[test]
+    fn searches_only_leave_where_they_start_for_open_tiles() {
+        let map = map_with_starts(BOXED, vec![], true.into(), 1).unwrap();
+        // a corner is walled in, even round the wrapping edges
+        assert_eq!(map.reachable_cells((0, 0).into()), [(0, 0).into()]);
+        // but anywhere else on the border is next to the inside of the box
+        let cells = map.reachable_cells((4, 9).into());
+        assert_eq!(cells.len(), 65);
+        assert_eq!(cells[1], (4, 8).into());
+        assert!(map.reachable_cells((10, 0).into()).is_empty());
+        assert!(!map.path_exists((0, 0).into(), (1, 1).into()));
+    }
+
+    #[test]
+    fn snakes_wall_off_the_box() {
+        // a snake standing across the middle of the box cuts it in two
+        let starts = vec![((5, 8), Direction::North)];
+        let map = map_with_starts(BOXED, starts, false.into(), 8).unwrap();
+        let left = map.reachable_cells((1, 1).into());
+        assert_eq!(left.len(), 32);
+        assert!(left.iter().all(|pos| pos.x < 5));
+        assert_eq!(map.reachable_cells((8, 8).into()).len(), 24);
+        assert!(!map.path_exists((1, 1).into(), (8, 8).into()));
+
+        // the search can still start from the head
+        let from_head = map.reachable_cells((5, 8).into());
+        assert_eq!(from_head.len(), 1 + 32 + 24);
+        assert!(map.path_exists((5, 8).into(), (8, 1).into()));
+    }
+
+    #[test]
+    fn positions_of_each_kind_of_tile() {
+        let starts = vec![((1, 1), Direction::East)];
+        let mut map =
+            map_with_starts("#..x\n...#\n#...", starts, false.into(), 2).unwrap();
+        map.tiles[6] = Tile::Doodah { value: 1 };
+        map.tiles[9] = Tile::Doodah { value: 2 };
+
+        let at = |cells: &[(usize, usize)]| -> Vec<Position> {
+            cells.iter().map(|&pos| pos.into()).collect()
+        };
+        assert_eq!(map.wall_positions(), at(&[(0, 0), (3, 1), (0, 2)]));
+        assert_eq!(map.doodah_positions(), at(&[(2, 1), (1, 2)]));
+        assert_eq!(map.blank_positions(), at(&[(1, 0), (2, 0), (2, 2), (3, 2)]));
+        assert_eq!(map.snake_head_position(0), Some((1, 1).into()));
+        assert_eq!(map.snake_head_position(1), None);
+
+        // a snake's head is gone with it
+        map.delete_snake(0, DeathCause::Quit);
+        assert_eq!(map.snake_head_position(0), None);
+    }
+
+    /// A three by three map with something different on most sides of the middle.
+    fn neighborly(wrap: bool) -> Map {
+        let mut map = map_with_starts("#x.\n...\n.#.", vec![], wrap.into(), 1).unwrap();
+        map.tiles[3] = Tile::Doodah { value: 1 };
+        map
+    }
+
+    #[test]
+    fn neighbors_of_the_middle() {
+        use Direction::*;
+        let expected = [
+            (North, Some(((1, 2).into(), Tile::Wall))),
+            (East, Some(((2, 1).into(), Tile::Blank))),
+            (South, Some(((1, 0).into(), Tile::Hazard))),
+            (West, Some(((0, 1).into(), Tile::Doodah { value: 1 }))),
+        ];
+        for &wrap in &[false, true] {
+            assert_eq!(neighborly(wrap).neighbors((1, 1).into()), expected);
+        }
+    }
+
+    #[test]
+    fn neighbors_of_the_corners() {
+        use Direction::*;
+        let map = neighborly(true);
+        assert_eq!(
+            map.neighbors((0, 0).into()),
+            [
+                (North, Some(((0, 1).into(), Tile::Doodah { value: 1 }))),
+                (East, Some(((1, 0).into(), Tile::Hazard))),
+                (South, Some(((0, 2).into(), Tile::Blank))),
+                (West, Some(((2, 0).into(), Tile::Blank))),
+            ]
+        );
+        assert_eq!(
+            map.neighbors((2, 2).into()),
+            [
+                (North, Some(((2, 0).into(), Tile::Blank))),
+                (East, Some(((0, 2).into(), Tile::Blank))),
+                (South, Some(((2, 1).into(), Tile::Blank))),
+                (West, Some(((1, 2).into(), Tile::Wall))),
+            ]
+        );
+
+        // without wrapping, there's nothing past the edges
+        let map = neighborly(false);
+        assert_eq!(
+            map.neighbors((0, 0).into()),
+            [
+                (North, Some(((0, 1).into(), Tile::Doodah { value: 1 }))),
+                (East, Some(((1, 0).into(), Tile::Hazard))),
+                (South, None),
+                (West, None),
+            ]
+        );
+        assert_eq!(
+            map.neighbors((2, 2).into()),
+            [
+                (North, None),
+                (East, None),
+                (South, Some(((2, 1).into(), Tile::Blank))),
+                (West, Some(((1, 2).into(), Tile::Wall))),
+            ]
+        );
+    }
+
+    #[test]
+    fn scoring_modes_add_up_over_steps() {
+        // snake 0 goes round and round, eating on the way; snake 1 soon runs off the map
+        let wrap = WrapConfig {
+            horizontal: true,
+            vertical: false,
+        };
+        let scores = |scoring| {
+            let starts = vec![((0, 0), Direction::East), ((3, 0), Direction::North)];
+            let mut map = map_with_starts("......\n......", starts, wrap, 1).unwrap();
+            map.scoring = scoring;
+            map.tiles[2] = Tile::Doodah { value: 3 };
+            let mut scores = Vec::new();
+            for _ in 0..5 {
+                map = map.step().unwrap();
+                scores.push((map.scores[&0], map.scores[&1]));
+            }
+            assert!(!map.is_alive(1));
+            scores
+        };
+
+        assert_eq!(
+            scores(ScoringMode::DoodahsOnly),
+            [(0, 0), (3, 0), (3, 0), (3, 0), (3, 0)]
+        );
+        assert_eq!(
+            scores(ScoringMode::SurvivalTime { per_turn: 2 }),
+            [(2, 2), (4, 2), (6, 2), (8, 2), (10, 2)]
+        );
+        assert_eq!(
+            scores(ScoringMode::Both { per_turn: 2 }),
+            [(2, 2), (7, 2), (9, 2), (11, 2), (13, 2)]
+        );
+    }
+
+    #[test]
+    fn spread_snakes_start_far_apart_in_the_box() {
+        let (width, height, tiles) = Map::from_ascii(BOXED).unwrap();
+        for seed in 0..50 {
+            let options = MapOptions {
+                spawn: SpawnMode::Spread,
+                wrap: false.into(),
+                seed: Some(seed),
+                ..MapOptions::default()
+            };
+            let map =
+                Map::new(width, height, tiles.clone(), vec![0, 1], options).unwrap();
+            let heads = (
+                map.snake_head_position(0).unwrap(),
+                map.snake_head_position(1).unwrap(),
+            );
+            // opposite corners of the inside are 14 apart
+            let distance = map.dims.distance(heads.0, heads.1, map.wrap);
+            assert!(distance >= 8, "seed {}: only {} apart", seed, distance);
+
+            // and neither is put facing straight into a wall
+            let map = map.step().unwrap();
+            assert_eq!(map.alive_count(), 2, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn doodahs_worth_more_score_at_once_and_grow_over_time() {
+        let starts = vec![((0, 0), Direction::East)];
+        let mut map = map_with_starts("......", starts, false.into(), 1).unwrap();
+        map.tiles[1] = Tile::Doodah { value: 3 };
+
+        let mut lens = vec![];
+        for _ in 0..4 {
+            map = map.step().unwrap();
+            assert_eq!(map.scores[&0], 3);
+            lens.push(map.snake(0).unwrap().len());
+        }
+        assert_eq!(lens, [2, 3, 4, 4]);
+    }
+
+    #[test]
+    fn doodah_weights_set_what_doodahs_are_worth() {
+        let starts = vec![((0, 0), Direction::East)];
+        let mut map = map_with_starts("...\n...", starts, false.into(), 1).unwrap();
+        for &idx in &[2, 3, 5] {
+            map.tiles[idx] = Tile::Doodah { value: 1 };
+        }
+        let values = |map: &Map| {
+            map.tiles
+                .iter()
+                .filter_map(|t| match t {
+                    Tile::Doodah { value } => Some(*value),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        map.set_doodah_weights(vec![0, 0, 1]);
+        assert_eq!(values(&map), [3, 3, 3]);
+
+        map.set_doodah_weights(vec![0, 0]);
+        assert_eq!(values(&map), [1, 1, 1]);
+    }
+
+    #[test]
+    fn snakes_know_what_they_cover() {
+        let starts = vec![((2, 1), Direction::East)];
+        let mut map =
+            map_with_starts("....\n....\n....", starts, false.into(), 3).unwrap();
+        map.turn_left(0);
+        let map = map.step().unwrap();
+
+        let snake = map.snake(0).unwrap();
+        assert_eq!(snake.len(), 3);
+        assert_eq!(
+            snake.segments().collect::<Vec<_>>(),
+            [(2, 2).into(), (2, 1).into(), (1, 1).into()]
+        );
+        assert!(snake.contains((1, 1).into()));
+        assert!(!snake.contains((0, 1).into()));
+
+        // dead snakes are gone
+        let map = map.step().unwrap_err();
+        assert!(map.snake(0).is_none());
+    }
+
+    #[test]
+    fn turning_round_over_two_steps_is_safe() {
+        let turns: [fn(&mut Map, SnakeID); 2] = [Map::turn_left, Map::turn_right];
+        for turn in &turns {
+            let starts = vec![((2, 2), Direction::East)];
+            let mut map = map_with_starts(
+                ".....\n.....\n.....\n.....\n.....",
+                starts,
+                false.into(),
+                2,
+            )
+            .unwrap();
+            for _ in 0..2 {
+                turn(&mut map, 0);
+                map = map.step().unwrap();
+            }
+            // facing back the way it came, but in the next lane over
+            assert_eq!(map.snake(0).unwrap().dir, Direction::West);
+            assert!(map.is_alive(0));
+            assert!(!map.causes.contains_key(&0));
         }
     }
 }
@@ -2,12 +2,17 @@
 
 extern crate markup;
 
-use crate::game::SnakeID;
-use crate::room::{self, Room, State, WaitingList};
+use crate::api;
+use crate::game::{Direction, Map, SnakeID, Tile};
+use crate::leaderboard::Standing;
+use crate::room::{Room, RoomList, State, WaitingList};
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, RwLock};
+
+use warp::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use warp::Reply;
 
 markup::define! {
     Page(contents: Vec<Box<dyn markup::Render>>, alert: Option<(String, String)>) {
@@ -75,7 +80,7 @@ markup::define! {
         }
     }
 
-    Index(rooms: Vec<(String, String, String, usize)>, waiters: Vec<String>) {
+    Index(rooms: Vec<(usize, String, String, String, usize)>, waiters: Vec<String>) {
         h1 { "Snake Arena: Control Panel" }
         hr;
         h3 { "Available Rooms" }
@@ -90,7 +95,7 @@ markup::define! {
                 }
             }
             tbody {
-                @for (i, (n, d, s, p)) in rooms.iter().enumerate() {
+                @for (i, n, d, s, p) in rooms.iter() {
                     tr {
                         th[scope = "row"] { {i} }
                         td { a[href = format!("/room/{}/", i)] { {n} } }
@@ -101,7 +106,8 @@ markup::define! {
                 }
             }
         }
-        h3 { "Waiters" }
+        p { a[href = "/leaderboard"] { "See the leaderboard across all rooms." } }
+        h3 { "Waiters (" {waiters.len()} ")" }
         p { "Navigate to a room's page if you want to subscribe a connection
              to the room." }
         ul {
@@ -111,14 +117,57 @@ markup::define! {
         }
     }
 
-    RoomHeader(id: usize, name: String, desc: String) {
+    LeaderboardTable(standings: Vec<Standing>) {
+        h1 { "Leaderboard" }
+        p.lead { "Points from every finished game, totalled by player name." }
+        @if standings.is_empty() {
+            p { "No games have finished yet." }
+        } else {
+            table.table {
+                thead."thead-light" {
+                    tr {
+                        th[scope = "col"] { "Rank" }
+                        th[scope = "col"] { "Name" }
+                        th[scope = "col"] { "Points" }
+                        th[scope = "col"] { "Games" }
+                    }
+                }
+                tbody {
+                    @for (i, s) in standings.iter().enumerate() {
+                        tr {
+                            th[scope = "row"] { {i + 1} }
+                            td { {&s.name} }
+                            td { {s.points} }
+                            td { {s.games} }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    RoomHeader(id: usize, name: String, desc: String, max_turns: Option<usize>) {
         h1 { "Room #" {id} " — " {name} }
         p.lead { {desc} }
+        @if let Some(max) = max_turns {
+            p { b { "Turn limit:" } " " {max} }
+        } else {
+            p { b { "Turn limit:" } " none" }
+        }
     }
 
-    RoomWaiting(players: Vec<(String, String)>) {
+    RoomWaiting(players: Vec<(String, String)>, max_players: usize, bots: usize) {
         p { b { "Room status:" } " waiting to begin." }
-        {RoomControlButtons { include_start: !players.is_empty() }}
+        p { b { "Player cap:" } " " {players.len()} " / " {max_players} }
+        @if *bots > 0 {
+            p { b { "Bots:" } " " {bots} " join each game" }
+        }
+        {RoomControlButtons {
+            include_start: !players.is_empty() || *bots > 0,
+            include_end: false,
+            paused: None,
+        }}
+        script { {markup::raw(LIVE_START)} }
         hr;
         h3 { "In queue" }
         @if players.is_empty() {
@@ -155,10 +204,36 @@ markup::define! {
         }
     }
 
-    RoomPlaying(scores: Vec<(SnakeID, String, usize)>) {
-        p { b { "Room status:" } " in progress." }
-        {RoomControlButtons { include_start: false }}
+    RoomPlaying(
+        scores: Vec<(SnakeID, String, usize)>,
+        doodahs: usize,
+        board: String,
+        paused: bool,
+        step: usize,
+        max_turns: Option<usize>,
+    ) {
+        p { b { "Room status:" } {if *paused { " paused." } else { " in progress." }} }
+        @if let Some(max) = max_turns {
+            p { b { "Turn:" } " " {step} " / " {max} }
+        } else {
+            p { b { "Turn:" } " " {step} }
+        }
+        {RoomControlButtons { include_start: false, include_end: true, paused: Some(*paused) }}
+        form."form-inline"."mt-2"[method = "post", action = "./doodahs"] {
+            label."mr-2"[for = "doodah-count"] { "Doodahs:" }
+            input."form-control"."mr-2"[
+                type = "number",
+                name = "count",
+                id = "doodah-count",
+                min = 0,
+                value = {doodahs},
+                required? = true,
+            ];
+            button.btn."btn-outline-primary"[type = "submit"] { "Set" }
+        }
         hr;
+        h3 { "Board" }
+        div."mb-3" { {markup::raw(board)} }
         h3 { "Current scores" }
         table.table {
             thead."thead-light" {
@@ -173,16 +248,17 @@ markup::define! {
                     tr {
                         th[scope = "row"] { {i} }
                         td { {a} }
-                        td { {s} }
+                        td[id = {format!("score-{}", i)}] { {s} }
                     }
                 }
             }
         }
+        script { {markup::raw(LIVE_SCORES)} }
     }
 
-    RoomFinished(scores: Vec<(String, usize)>) {
+    RoomFinished(scores: Vec<(String, usize, String)>) {
         p { b { "Room status:" } " finished." }
-        {RoomControlButtons { include_start: false }}
+        {RoomControlButtons { include_start: false, include_end: false, paused: None }}
         hr;
         h3 { "Final scores" }
         table.table {
@@ -190,22 +266,25 @@ markup::define! {
                 tr {
                     th[scope = "col"] { "Address" }
                     th[scope = "col"] { "Score" }
+                    th[scope = "col"] { "Fate" }
                 }
             }
             tbody {
-                @for (a, s) in scores.iter() {
+                @for (a, s, c) in scores.iter() {
                     tr {
                         td { {a} }
                         td { {s} }
+                        td { {c} }
                     }
                 }
             }
         }
     }
 
-    RoomControlButtons(include_start: bool) {
-        a.btn."mb-2"."btn-outline-info"[href="./history"] { "Get room history (JSON)" }
-        form[method = "post"] {
+    RoomControlButtons(include_start: bool, include_end: bool, paused: Option<bool>) {
+        a.btn."mb-2"."mr-2"."btn-outline-info"[href="./history"] { "Get room history (JSON)" }
+        a.btn."mb-2"."btn-outline-info"[href="./spectate"] { "Watch live" }
+        form[method = "post", action = "./"] {
             button.btn."mr-2".{
                 if *include_start { "btn-primary" } else { "btn-secondary" }
             } [
@@ -216,19 +295,48 @@ markup::define! {
             button.btn."btn-success"."mr-2"[
                 onclick = "window.location.href=window.location.href;"
             ] { "Refresh" }
+            @if *include_end {
+                button.btn."btn-warning"."mr-2"[
+                    type = "submit",
+                    name = "end_room",
+                    onclick = "return confirm('End this game with the scores as they are?');",
+                ] { "Force End" }
+            }
+            @if let Some(paused) = paused {
+                @if *paused {
+                    button.btn."btn-info"."mr-2"[
+                        type = "submit",
+                        name = "resume_room",
+                    ] { "Resume" }
+                } else {
+                    button.btn."btn-outline-info"."mr-2"[
+                        type = "submit",
+                        name = "pause_room",
+                    ] { "Pause" }
+                }
+            }
+            button.btn."btn-outline-primary"."mr-2"[
+                type = "submit",
+                name = "restart_room",
+            ] { "Restart" }
             button.btn."btn-danger"."mr-2"[
                 type = "submit",
                 name = "reset_room",
             ] { "Reset " }
+            button.btn."btn-outline-danger"."mr-2"[
+                type = "submit",
+                name = "delete_room",
+                onclick = "return confirm('Delete this room?');",
+            ] { "Delete" }
         }
     }
 
-    WaitDropdown(waiters: Vec<(String, String)>) {
+    WaitDropdown(waiters: Vec<(String, String)>, can_subscribe: bool) {
         h3 { "Waiters" }
         @if waiters.is_empty() {
             p { "There are no connections waiting for a room." }
         } else {
-            form[method = "post"] {
+            form[method = "post", action = "./"] {
                 div."form-group" {
                     @for (i, (addr, name)) in waiters.iter().enumerate() {
                         div."form-check" {
@@ -248,11 +356,13 @@ markup::define! {
                 button.btn."btn-primary"."mr-2"[
                     type = "submit",
                     name = "subscribe",
+                    disabled? = !*can_subscribe,
                 ] { "Subscribe" }
                 button.btn."btn-outline-primary"."mr-2"[
                     type = "submit",
                     name = "subscribe_all",
                     onclick = "$('input').prop('required', false)",
+                    disabled? = !*can_subscribe,
                 ] { "Subscribe All" }
                 button.btn."btn-secondary"."mr-2"[
                     type = "submit",
@@ -267,23 +377,194 @@ markup::define! {
         }
     }
 
+    Spectate(id: usize, name: String) {
+        h1 { "Room #" {id} " — " {name} }
+        p.lead[id = "spectate-status"] { "Waiting for the game to start..." }
+        canvas."border"[id = "spectate-board", width = 640, height = 512] {}
+        script { {markup::raw(SPECTATE)} }
+    }
+
     NotFound() {
         p { "This is not the page you were looking for." }
     }
 }
 
-pub fn index(rooms: &[Arc<Mutex<Room>>], waiting_list: Arc<WaitingList>) -> String {
+/// Keep the scores of a game in progress up to date, and show the final scores once
+/// it's over.
+const LIVE_SCORES: &str = "
+    const events = new EventSource('./events');
+    events.onmessage = function (e) {
+        const scores = JSON.parse(e.data).scores;
+        for (const id in scores) {
+            const cell = document.getElementById('score-' + id);
+            if (cell) {
+                cell.textContent = scores[id];
+            }
+        }
+    };
+    events.addEventListener('done', function () {
+        events.close();
+        window.location.href = './';
+    });
+";
+
+/// Switch to the game in progress once a waiting room starts playing.
+const LIVE_START: &str = "
+    const events = new EventSource('./events');
+    events.onmessage = function () {
+        events.close();
+        window.location.href = './';
+    };
+";
+
+/// Draw the maps sent to spectators of a room.
+///
+/// Tiles are drawn with north at the top, so the map looks the same as in the clients.
+const SPECTATE: &str = "
+    const board = document.getElementById('spectate-board');
+    const status = document.getElementById('spectate-status');
+    const ctx = board.getContext('2d');
+    const colours = {
+        Blank: '#f8f9fa', Wall: '#343a40', Hazard: '#dc3545', Doodah: '#ffc107',
+        SnakeHead: '#1e7e34', SnakeBody: '#28a745',
+    };
+
+    const url = new URL('./watch', window.location.href);
+    url.protocol = url.protocol === 'https:' ? 'wss:' : 'ws:';
+    const socket = new WebSocket(url);
+    let step = 0;
+
+    socket.onmessage = function (e) {
+        const msg = JSON.parse(e.data);
+        if (msg.state === 'done') {
+            const scores = Object.entries(msg.scores)
+                .map(function (s) { return 'snake ' + s[0] + ': ' + s[1]; });
+            status.textContent = 'Game over. Final scores — ' + scores.join(', ');
+            return;
+        }
+
+        step += 1;
+        status.textContent = 'Step ' + step;
+        const size = Math.floor(Math.min(board.width / msg.width, board.height / msg.height));
+        ctx.clearRect(0, 0, board.width, board.height);
+        msg.tiles.forEach(function (tile, i) {
+            const x = i % msg.width;
+            const y = msg.height - 1 - Math.floor(i / msg.width);
+            ctx.fillStyle = colours[tile.type];
+            ctx.fillRect(x * size, y * size, size - 1, size - 1);
+        });
+    };
+    socket.onclose = function () {
+        status.textContent += ' (disconnected)';
+    };
+";
+
+/// Size of each tile in a rendered map, in SVG user units.
+const TILE_SIZE: usize = 16;
+
+/// Pick a colour for a snake, so that each snake on the board looks different.
+fn snake_colour(id: SnakeID, head: bool) -> String {
+    // stepping around the colour wheel by the golden angle keeps neighbours apart
+    let hue = (id * 137) % 360;
+    format!("hsl({}, 70%, {}%)", hue, if head { 30 } else { 50 })
+}
+
+/// Render a map as an inline SVG image.
+///
+/// Each tile is drawn as a square, with north at the top so the map looks the same as
+/// in the clients. Snake heads are drawn darker than their bodies, with a triangle
+/// pointing the way they're facing.
+pub fn render_map(map: &Map) -> String {
+    let (width, height) = (map.dims.width(), map.dims.height());
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\" \
+         style=\"max-width: 100%; max-height: 70vh;\">",
+        width * TILE_SIZE,
+        height * TILE_SIZE,
+    );
+
+    for (i, &tile) in map.tiles.iter().enumerate() {
+        let x = (i % width) * TILE_SIZE;
+        let y = (height - 1 - i / width) * TILE_SIZE;
+        let fill = match tile {
+            Tile::Blank => "#f8f9fa".to_owned(),
+            Tile::Wall => "#343a40".to_owned(),
+            Tile::Hazard => "#dc3545".to_owned(),
+            Tile::Doodah { .. } => "#ffc107".to_owned(),
+            Tile::SnakeBody { id, .. } => snake_colour(id, false),
+            Tile::SnakeHead { id, .. } => snake_colour(id, true),
+        };
+        let _ = write!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+            x,
+            y,
+            TILE_SIZE - 1,
+            TILE_SIZE - 1,
+            fill,
+        );
+
+        if let Tile::SnakeHead { dir, .. } = tile {
+            // which way is forward, and which is to the side, with y pointing down
+            let (dx, dy) = match dir {
+                Direction::North => (0, -1),
+                Direction::East => (1, 0),
+                Direction::South => (0, 1),
+                Direction::West => (-1, 0),
+            };
+            let centre = (TILE_SIZE as i32 - 1) / 2;
+            let (cx, cy) = (x as i32 + centre, y as i32 + centre);
+            let _ = write!(
+                svg,
+                "<polygon points=\"{},{} {},{} {},{}\" fill=\"#ffffff\"/>",
+                cx + 5 * dx,
+                cy + 5 * dy,
+                cx - 3 * dx - 4 * dy,
+                cy - 3 * dy + 4 * dx,
+                cx - 3 * dx + 4 * dy,
+                cy - 3 * dy - 4 * dx,
+            );
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Wrap a rendered page into a reply with consistent headers.
+///
+/// The control panel changes from moment to moment, so pages are marked to never be
+/// cached; otherwise browsers may happily show a stale room state.
+pub fn reply(page: String) -> impl Reply {
+    let reply = warp::reply::with_header(page, CONTENT_TYPE, "text/html; charset=utf-8");
+    warp::reply::with_header(reply, CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+}
+
+pub async fn index(
+    rooms: &RwLock<RoomList>,
+    waiting_list: Arc<WaitingList>,
+    alert: Option<(String, String)>,
+) -> String {
+    let waiters: Vec<_> = waiting_list
+        .waiters()
+        .await
+        .iter()
+        .map(|(addr, name)| format!("{} — {}", addr, name))
+        .collect();
+
     let rooms: Vec<_> = rooms
+        .read()
+        .unwrap()
         .iter()
-        .cloned()
-        .map(|room| {
+        .map(|(id, room)| {
             let room_inner = room.lock().unwrap();
             let (state, members) = match room_inner.get_state() {
-                State::Waiting { players } => ("Waiting", players.len()),
+                State::Waiting { players, .. } => ("Waiting", players.len()),
                 State::Playing { players, .. } => ("Playing", players.len()),
-                State::Finished { scores } => ("Finished", scores.len()),
+                State::Finished { scores, .. } => ("Finished", scores.len()),
             };
             (
+                id,
                 room_inner.name.clone(),
                 room_inner.description.clone(),
                 state.to_owned(),
@@ -292,26 +573,27 @@ pub fn index(rooms: &[Arc<Mutex<Room>>], waiting_list: Arc<WaitingList>) -> Stri
         })
         .collect();
 
-    let waiters: Vec<_> = waiting_list
-        .waiters()
-        .iter()
-        .map(|(addr, name)| format!("{} — {}", addr, name))
-        .collect();
-
     let index = Box::new(Index { rooms, waiters });
     Page {
         contents: vec![index],
-        alert: None,
+        alert,
     }
     .to_string()
 }
 
-pub fn room_page(
+pub async fn room_page(
     id: usize,
     room: Arc<Mutex<Room>>,
     waiting_list: Arc<WaitingList>,
     alert: Option<(String, String)>,
 ) -> String {
+    let waiters = waiting_list
+        .waiters()
+        .await
+        .into_iter()
+        .map(|(addr, name)| (addr.to_string(), name))
+        .collect();
+
     let mut contents: Vec<Box<dyn markup::Render>> = Vec::new();
 
     let room_inner = room.lock().unwrap();
@@ -319,15 +601,26 @@ pub fn room_page(
         id,
         name: room_inner.name.clone(),
         desc: room_inner.description.clone(),
+        max_turns: room_inner.max_turns,
     }));
 
+    // only a waiting room with space left can take more players
+    let mut can_subscribe = false;
     match room_inner.get_state() {
-        State::Waiting { players } => contents.push(Box::new(RoomWaiting {
-            players: players
-                .iter()
-                .map(|(addr, name)| (addr.to_string(), name.clone()))
-                .collect(),
-        })),
+        State::Waiting {
+            players,
+            max_players,
+        } => {
+            can_subscribe = players.len() < max_players;
+            contents.push(Box::new(RoomWaiting {
+                players: players
+                    .iter()
+                    .map(|(addr, name)| (addr.to_string(), name.clone()))
+                    .collect(),
+                max_players,
+                bots: room_inner.bots,
+            }));
+        }
         State::Playing { map, players } => {
             let map = map.lock().unwrap();
             let mut scores: Vec<_> = players
@@ -336,110 +629,95 @@ pub fn room_page(
                 .map(|(id, addr)| (id, addr, *map.scores.get(&id).unwrap_or(&0)))
                 .collect();
             scores.sort_unstable_by_key(|&(id, _, _)| id);
-            contents.push(Box::new(RoomPlaying { scores }));
+            contents.push(Box::new(RoomPlaying {
+                scores,
+                doodahs: map.doodah_count(),
+                board: render_map(&map),
+                paused: room_inner.is_paused(),
+                step: map.turn(),
+                max_turns: room_inner.max_turns,
+            }));
         }
-        State::Finished { scores } => {
+        State::Finished { scores, causes } => {
             contents.push(Box::new(RoomFinished {
                 scores: scores
                     .iter()
-                    .map(|(a, (n, s))| (format!("{} — {}", a, n), *s))
+                    .map(|(a, (n, s))| {
+                        let cause =
+                            causes.get(a).map_or_else(String::new, |c| c.to_string());
+                        (format!("{} — {}", a, n), *s, cause)
+                    })
                     .collect(),
             }));
         }
     }
 
-    let waiters = waiting_list
-        .waiters()
-        .into_iter()
-        .map(|(addr, name)| (addr.to_string(), name))
-        .collect();
-    contents.push(Box::new(WaitDropdown { waiters }));
+    contents.push(Box::new(WaitDropdown {
+        waiters,
+        can_subscribe,
+    }));
     Page { contents, alert }.to_string()
 }
 
+pub fn leaderboard_page(rooms: &RoomList) -> String {
+    let standings = api::leaderboard(rooms);
+    Page {
+        contents: vec![Box::new(LeaderboardTable { standings })],
+        alert: None,
+    }
+    .to_string()
+}
+
+pub fn spectate_page(id: usize, room: Arc<Mutex<Room>>) -> String {
+    let spectate = Box::new(Spectate {
+        id,
+        name: room.lock().unwrap().name.clone(),
+    });
+    Page {
+        contents: vec![spectate],
+        alert: None,
+    }
+    .to_string()
+}
+
+fn to_alert(r: Result<String, String>) -> Option<(String, String)> {
+    match r {
+        Ok(msg) => Some(("success".to_owned(), msg)),
+        Err(msg) => Some(("danger".to_owned(), msg)),
+    }
+}
+
 #[allow(clippy::implicit_hasher)]
-pub fn room_request(
+pub async fn room_request(
     id: usize,
     room: Arc<Mutex<Room>>,
     waiting: Arc<WaitingList>,
     form: HashMap<String, String>,
+    rooms: Arc<RwLock<RoomList>>,
 ) -> String {
-    fn fix<E: ToString>(e: E) -> String {
-        e.to_string()
+    // the room's page is gone, so show the remaining rooms instead
+    if form.contains_key("delete_room") {
+        let result = api::delete_room(&rooms, &waiting, id)
+            .await
+            .ok_or_else(|| "No such room.".to_owned());
+        return index(&rooms, waiting, to_alert(result)).await;
     }
 
-    fn to_alert_success<T: ToString>(t: T) -> Option<(String, String)> {
-        Some(("success".to_owned(), t.to_string()))
-    }
-    fn to_alert_error<T: ToString>(t: T) -> Option<(String, String)> {
-        Some(("danger".to_owned(), t.to_string()))
-    }
-    fn to_alert<T: ToString, U: ToString>(r: Result<T, U>) -> Option<(String, String)> {
-        match r {
-            Ok(msg) => to_alert_success(msg),
-            Err(msg) => to_alert_error(msg),
-        }
-    }
-
-    let alert = if form.contains_key("subscribe") {
-        let room_inner = &mut room.lock().unwrap();
-        to_alert(
-            form.get("waiter")
-                .ok_or_else(|| "missing field: waiter".to_owned())
-                .and_then(|addr| addr.parse::<SocketAddr>().map_err(fix))
-                .and_then(|addr| waiting.subscribe(&addr, room_inner).map_err(fix))
-                .map(|_| "Subscribed connection to room."),
-        )
-    } else if form.contains_key("subscribe_all") {
-        let room_inner = &mut room.lock().unwrap();
-        let count = waiting.len();
-        if count > 0 {
-            to_alert(
-                waiting.subscribe_all(room_inner).map(
-                    move |_| format!("Subscribed {} connection(s) to the room.", count)),
-            )
-        } else {
-            to_alert_error("No current connections to subscribe.")
-        }
-    } else if form.contains_key("unsubscribe") {
-        let room_inner = &mut room.lock().unwrap();
-        to_alert(
-            form.get("waiter")
-                .ok_or_else(|| "missing field: waiter".to_owned())
-                .and_then(|addr| addr.parse::<SocketAddr>().map_err(fix))
-                .and_then(|addr| room_inner.unsubscribe(&addr, &waiting).map_err(fix))
-                .map(|_| "Removed connection from room."),
-        )
-    } else if form.contains_key("kill") {
-        to_alert(
-            form.get("waiter")
-                .ok_or_else(|| "missing field: waiter".to_owned())
-                .and_then(|addr| addr.parse::<SocketAddr>().map_err(fix))
-                .map(|addr| {
-                    if waiting.remove(&addr) {
-                        "Successfully killed the connection."
-                    } else {
-                        "Nothing to do."
-                    }
-                }),
-        )
-    } else if form.contains_key("kill_all") {
-        waiting.clear();
-        to_alert_success("Success!")
-    } else if form.contains_key("start_room") {
-        if room::run(room.clone()) {
-            to_alert_success("Started room execution.")
-        } else {
-            to_alert_error("Room failed to start.")
-        }
-    } else if form.contains_key("reset_room") {
-        let room_inner = &mut room.lock().unwrap();
-        to_alert(room_inner.reset().map(|_| "Room reset successfully."))
-    } else {
-        None
-    };
+    let alert = api::room_action(&room, &waiting, &form)
+        .await
+        .and_then(to_alert);
+    room_page(id, room, waiting, alert).await
+}
 
-    room_page(id, room, waiting, alert)
+#[allow(clippy::implicit_hasher)]
+pub async fn room_doodahs(
+    id: usize,
+    room: Arc<Mutex<Room>>,
+    waiting: Arc<WaitingList>,
+    form: HashMap<String, String>,
+) -> String {
+    let alert = to_alert(api::form_doodah_count(&room, &form));
+    room_page(id, room, waiting, alert).await
 }
 
 pub fn page_not_found() -> String {
@@ -449,3 +727,50 @@ pub fn page_not_found() -> String {
     }
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::MapOptions;
+
+    #[test]
+    fn map_renders_a_rect_per_tile_and_a_triangle_per_head() {
+        let (width, height, tiles) = Map::from_ascii("#....\n..x..\n.....").unwrap();
+        let options = MapOptions {
+            starts: Some(vec![
+                ((2, 0).into(), Direction::East),
+                ((2, 2).into(), Direction::West),
+            ]),
+            doodah_count: 2,
+            initial_length: 2,
+            seed: Some(0),
+            ..MapOptions::default()
+        };
+        let map = Map::new(width, height, tiles, vec![0, 1], options).unwrap();
+
+        let svg = render_map(&map);
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>"));
+        assert_eq!(svg.matches("<rect ").count(), 15);
+        assert_eq!(svg.matches("<polygon ").count(), 2);
+        assert_eq!(svg.matches("fill=\"#343a40\"").count(), 1);
+        assert_eq!(svg.matches("fill=\"#dc3545\"").count(), 1);
+        assert_eq!(svg.matches("fill=\"#ffc107\"").count(), 2);
+    }
+
+    #[test]
+    fn playing_room_shows_the_turn_against_the_limit() {
+        let playing = |max_turns| {
+            RoomPlaying {
+                scores: vec![],
+                doodahs: 1,
+                board: String::new(),
+                paused: false,
+                step: 12,
+                max_turns,
+            }
+            .to_string()
+        };
+        assert!(playing(Some(100)).contains("<p><b>Turn:</b> 12 / 100</p>"));
+        assert!(playing(None).contains("<p><b>Turn:</b> 12</p>"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Keep a tally of scores across every game played on the server.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+/// A player's totals across every finished game, as listed by
+/// [`Leaderboard::standings`].
+///
+/// [`Leaderboard::standings`]: struct.Leaderboard.html#method.standings
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct Standing {
+    /// The name the player connected with.
+    pub name: String,
+
+    /// Points scored over all their games.
+    pub points: usize,
+
+    /// How many games they've finished.
+    pub games: usize,
+}
+
+/// Scores from finished games, totalled by player name.
+///
+/// Players are told apart only by name, so everyone who connects with the same name
+/// shares a line. The tally outlives the games, so resetting or deleting a room
+/// doesn't lose its scores.
+#[derive(Debug, Default)]
+pub struct Leaderboard {
+    totals: HashMap<String, (usize, usize)>,
+}
+
+impl Leaderboard {
+    /// Create an empty leaderboard
+    pub fn new() -> Self {
+        Leaderboard::default()
+    }
+
+    /// Add the final scores of a game, given by player name.
+    pub fn record<'a>(&mut self, scores: impl IntoIterator<Item = (&'a str, usize)>) {
+        for (name, score) in scores {
+            let (points, games) = self.totals.entry(name.to_owned()).or_default();
+            *points += score;
+            *games += 1;
+        }
+    }
+
+    /// List every player's totals, best first.
+    ///
+    /// Players are ranked by total points. Ties go to whoever played fewer games, so a
+    /// better average wins, and then to names in alphabetical order.
+    pub fn standings(&self) -> Vec<Standing> {
+        let mut standings: Vec<_> = self
+            .totals
+            .iter()
+            .map(|(name, &(points, games))| Standing {
+                name: name.clone(),
+                points,
+                games,
+            })
+            .collect();
+        standings.sort_unstable_by(|a, b| {
+            b.points
+                .cmp(&a.points)
+                .then(a.games.cmp(&b.games))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        standings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn standing(name: &str, points: usize, games: usize) -> Standing {
+        Standing {
+            name: name.to_owned(),
+            points,
+            games,
+        }
+    }
+
+    #[test]
+    fn scores_add_up_by_name() {
+        let mut leaderboard = Leaderboard::new();
+        assert!(leaderboard.standings().is_empty());
+
+        leaderboard.record(vec![("a", 3), ("b", 5)]);
+        leaderboard.record(vec![("a", 4), ("c", 0)]);
+        leaderboard.record(vec![("a", 0)]);
+        assert_eq!(
+            leaderboard.standings(),
+            [
+                standing("a", 7, 3),
+                standing("b", 5, 1),
+                standing("c", 0, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_go_to_fewer_games_then_by_name() {
+        let mut leaderboard = Leaderboard::new();
+        leaderboard.record(vec![("d", 6), ("c", 6), ("b", 3), ("a", 2)]);
+        leaderboard.record(vec![("b", 3), ("a", 4), ("e", 1)]);
+        assert_eq!(
+            leaderboard.standings(),
+            [
+                standing("c", 6, 1),
+                standing("d", 6, 1),
+                standing("a", 6, 2),
+                standing("b", 6, 2),
+                standing("e", 1, 1),
+            ]
+        );
+    }
+}
@@ -1,55 +1,209 @@
-extern crate futures;
-extern crate tokio;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use std::sync::Arc;
-use std::io::BufReader;
-
-use tokio::io;
-use tokio::net::TcpStream;
-use tokio::prelude::*;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time;
+use tracing::Instrument;
 
+pub mod api;
+pub mod bot;
+pub mod config;
 pub mod game;
-pub mod room;
+#[cfg(feature = "html-panel")]
 pub mod html;
+pub mod leaderboard;
+pub mod replay;
+pub mod room;
+
+use room::{RoomList, WaitingList};
+
+/// A connection to a client, whether a plain TCP socket or one wrapped in TLS.
+///
+/// This stands in for a trait alias, so that the rest of the server doesn't need to
+/// know which kind of connection it's talking over.
+pub trait Stream:
+    AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send + Sync + 'static
+{
+}
+
+impl<T> Stream for T where
+    T: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send + Sync + 'static
+{
+}
+
+/// How long to wait after a client's name for a `SPECTATE <room_id>` line.
+///
+/// Players don't send anything until their game starts, so they only notice this as a
+/// short delay before they join the waiting list.
+const SPECTATE_GRACE: Duration = Duration::from_millis(100);
+
+/// Longest name a client may connect with, in characters.
+const MAX_NAME_LEN: usize = 32;
+
+/// A reason a client's name was refused.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum NameError {
+    /// There's nothing left of the name once whitespace and control characters are
+    /// removed.
+    Empty,
 
-use room::WaitingList;
+    /// The name is longer than `MAX_NAME_LEN` characters.
+    TooLong { length: usize },
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "name must not be empty"),
+            NameError::TooLong { length } => write!(
+                f,
+                "name must be at most {} characters, but is {}",
+                MAX_NAME_LEN, length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Clean up the name a client connected with.
+///
+/// Control characters are removed and surrounding whitespace is trimmed; what's left
+/// must not be empty or longer than `MAX_NAME_LEN` characters.
+pub fn validate_name(raw: &str) -> Result<String, NameError> {
+    let name: String = raw.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+
+    let length = name.chars().count();
+    if length == 0 {
+        Err(NameError::Empty)
+    } else if length > MAX_NAME_LEN {
+        Err(NameError::TooLong { length })
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+/// Send a client an error message before its connection is dropped.
+async fn refuse<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &str) -> io::Result<()> {
+    let json = serde_json::json!({ "state": "error", "msg": msg });
+    writer.write_all(format!("{}\n", json).as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Work out which room a `SPECTATE <room_id>` line asks for.
+fn spectate_request(line: &str) -> Option<usize> {
+    line.trim().strip_prefix("SPECTATE ")?.trim().parse().ok()
+}
 
 pub fn process_socket(
-    socket: TcpStream,
+    socket: impl Stream,
+    addr: SocketAddr,
     waiting: Arc<WaitingList>,
-) -> std::io::Result<()> {
-    let addr = socket.peer_addr()?;
-    println!("Processing new connection {}...", addr);
-
-    socket.set_nodelay(true)?;
-    let (reader, writer) = socket.split();
-    let reader = BufReader::new(reader);
-
-    let get_name = io::read_until(reader, b'\n', Vec::new())
-        .and_then(move |(reader, vec)| {
-            if vec.len() == 0 {
-                Err(io::Error::from(io::ErrorKind::BrokenPipe))
-            } else {
-                match String::from_utf8(vec) {
-                    Ok(s) => {
-                        waiting.insert(addr, s, reader, writer);
-                        Ok(())
-                    }
-                    Err(e) => {
-                        Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
-                    }
-                }
+    rooms: Arc<RwLock<RoomList>>,
+) {
+    let span = tracing::info_span!("connection", %addr);
+    let _entered = span.enter();
+    tracing::info!("Processing new connection...");
+
+    let (reader, mut writer) = io::split(Box::new(socket) as Box<dyn Stream>);
+    let mut reader = BufReader::new(reader);
+
+    let get_name = async move {
+        let mut vec = Vec::new();
+        if reader.read_until(b'\n', &mut vec).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        let name = String::from_utf8(vec)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let name = match validate_name(&name) {
+            Ok(name) => name,
+            Err(e) => {
+                refuse(&mut writer, &e.to_string()).await?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+
+        // anything already sent by a player is left in the buffer for the game
+        let spectating = match time::timeout(SPECTATE_GRACE, reader.fill_buf()).await {
+            Ok(buf) => buf?.starts_with(b"SPECTATE "),
+            Err(_) => false,
+        };
+        if !spectating {
+            // a player who lost their connection to a game can pick up where they left off
+            let reconnected = rooms.read().unwrap().reconnect(addr, name, reader, writer);
+            if let Err((name, reader, writer)) = reconnected {
+                waiting.insert(addr, name, reader, writer).await;
+            }
+            return Ok(());
+        }
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let room = spectate_request(&line).and_then(|id| rooms.read().unwrap().get(id));
+        match room {
+            Some(room) => {
+                room::spectate(&room, addr, reader, writer);
+                Ok(())
+            }
+            None => {
+                refuse(&mut writer, "no such room").await?;
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "no such room"))
             }
-        });
+        }
+    };
 
-    tokio::spawn(get_name.then(move |result| {
-        if let Err(e) = result {
-            println!("Connection {} aborted with error: {}", addr, e);
-        } else {
-            println!("Connection handled: {}", addr);
+    tokio::spawn(
+        async move {
+            if let Err(e) = get_name.await {
+                metrics::counter!("snake_arena_errors_total").increment(1);
+                tracing::warn!("Connection aborted with error: {}", e);
+            } else {
+                tracing::info!("Connection handled");
+            }
         }
-        Ok(())
-    }));
+        .instrument(span.clone()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_trimmed() {
+        assert_eq!(validate_name("  snek \r").unwrap(), "snek");
+        assert_eq!(validate_name("two words").unwrap(), "two words");
+    }
+
+    #[test]
+    fn empty_names_are_refused() {
+        assert_eq!(validate_name(""), Err(NameError::Empty));
+        assert_eq!(validate_name(" \t "), Err(NameError::Empty));
+        assert_eq!(validate_name("\u{7}\u{1b}"), Err(NameError::Empty));
+    }
+
+    #[test]
+    fn overlong_names_are_refused() {
+        let longest = "é".repeat(MAX_NAME_LEN);
+        assert_eq!(validate_name(&longest).unwrap(), longest);
+        let name = format!("{}s", longest);
+        assert_eq!(
+            validate_name(&name),
+            Err(NameError::TooLong {
+                length: MAX_NAME_LEN + 1
+            })
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn control_characters_are_stripped() {
+        assert_eq!(validate_name("sn\u{0}e\u{1b}[31mk").unwrap(), "sne[31mk");
+        assert_eq!(validate_name("\u{8}\u{8}admin\n").unwrap(), "admin");
+        // what's stripped doesn't count towards the length
+        let name = format!("{}\u{7f}", "a".repeat(MAX_NAME_LEN));
+        assert_eq!(validate_name(&name).unwrap(), "a".repeat(MAX_NAME_LEN));
+    }
 }
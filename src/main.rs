@@ -1,159 +1,560 @@
-extern crate tokio;
-
 #[macro_use]
 extern crate lazy_static;
 
-use server::game::Tile;
+use server::api::{self, Created, HistoryLength, HistoryQuery, NewRoom, WaiterRequest};
+use server::config::{self, ConfigError};
+use server::game::Map;
+#[cfg(feature = "html-panel")]
 use server::html;
-use server::room::{Room, WaitingList};
+use server::room::{Room, RoomBuilder, RoomList, WaitingList};
 
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use tokio::net::TcpListener;
-use tokio::prelude::*;
-use tokio::runtime::Runtime;
+#[cfg(feature = "tls")]
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 
 #[macro_use]
 extern crate warp;
-use warp::{http::StatusCode, Filter, Rejection, Reply};
+use warp::{http::StatusCode, Filter, Reply};
+
+use clap::Parser;
+use futures::{future, TryFutureExt};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
 
+use std::convert::Infallible;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Run a snake arena server.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Address to serve the control panel and JSON endpoints on
+    #[arg(long, env = "SNAKE_HTTP_ADDR", default_value = "0.0.0.0:80")]
+    http_addr: SocketAddr,
+
+    /// Address for game clients to connect to
+    #[arg(long, env = "SNAKE_GAME_ADDR", default_value = "0.0.0.0:3001")]
+    game_addr: SocketAddr,
+
+    /// File to load room definitions from [default: rooms.toml, falling back to the
+    /// built-in rooms if it doesn't exist or defines no rooms]
+    #[arg(long, env = "SNAKE_ARENA_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Directory to save the history of every finished game to, one JSON file per
+    /// game [default: don't save games]
+    #[arg(long, env = "SNAKE_ARENA_HISTORY_DIR")]
+    history_dir: Option<PathBuf>,
+
+    /// Most detailed messages to log: off, error, warn, info, debug or trace. Filters
+    /// given in the RUST_LOG environment variable take precedence
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Seconds between pings to idle game clients; clients that can't be pinged are
+    /// disconnected
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+
+    /// Seconds a client may sit in the waiting list before it is disconnected;
+    /// the waiting list is checked once a minute
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: u64,
+
+    /// Certificate chain to serve game clients over TLS with, as a PEM file; needs
+    /// --tls-key as well [default: plain TCP]
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "SNAKE_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key for the certificate given by --tls-cert, as a PEM file
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "SNAKE_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
 
 lazy_static! {
-    static ref ROOMS: Vec<Arc<Mutex<Room>>> = create_rooms();
+    static ref CLI: Cli = Cli::parse();
 }
 
+/// Every room, which can change while the server is running.
+type Rooms = Arc<RwLock<RoomList>>;
+
 fn manage_rooms(
+    rooms: Rooms,
     waiting_list: Arc<WaitingList>,
-) -> impl warp::Filter<Extract = (impl Reply,), Error = Rejection> {
+    metrics: PrometheusHandle,
+) -> impl warp::Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
     let with_waitlist = warp::any().map(move || waiting_list.clone());
     use warp::reject::not_found;
 
-    let get_room = |id| {
-        ROOMS
-            .get(id)
-            .cloned()
-            .map(|r| (id, r))
-            .ok_or_else(not_found)
-    };
+    let list = rooms.clone();
+    let with_rooms = warp::any().map(move || list.clone());
 
-    let index = warp::path::end()
-        .and(with_waitlist.clone())
-        .map(|waitlist: Arc<WaitingList>| html::index(&ROOMS, waitlist))
-        .map(warp::reply::html);
+    let get_room = move |id| {
+        future::ready(
+            rooms
+                .read()
+                .unwrap()
+                .get(id)
+                .map(|r| (id, r))
+                .ok_or_else(not_found),
+        )
+    };
 
-    let room_page = path!["room" / usize]
+    let room_request = path!["room" / usize]
         .and(warp::path::end())
-        .and(warp::get2())
-        .and_then(get_room)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and_then(get_room.clone())
         .untuple_one()
         .and(with_waitlist.clone())
-        .and(warp::any().map(|| None))
-        .map(html::room_page)
-        .map(warp::reply::html);
+        .and(warp::body::form())
+        .and(with_rooms.clone());
 
-    let room_request = path!["room" / usize]
+    let room_doodahs = path!["room" / usize / "doodahs"]
         .and(warp::path::end())
-        .and(warp::post2())
+        .and(warp::post())
         .and(warp::body::content_length_limit(1024))
-        .and_then(get_room)
+        .and_then(get_room.clone())
         .untuple_one()
         .and(with_waitlist.clone())
-        .and(warp::body::form())
-        .map(html::room_request)
-        .map(warp::reply::html);
+        .and(warp::body::form());
 
     let room_history = path!["room" / usize / "history"]
         .and(warp::path::end())
-        .and(warp::get2())
-        .and_then(get_room)
+        .and(warp::get())
+        .and_then(get_room.clone())
+        .and(warp::query::<HistoryQuery>())
+        .map(|(_, room): (_, Arc<Mutex<Room>>), query: HistoryQuery| {
+            warp::reply::json(&room.lock().unwrap().history_since(query.since))
+        });
+
+    // pages of history, with a header saying where the next page starts
+    let get = get_room.clone();
+    let room_history_from = path!["room" / usize / "history" / usize]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(move |id, from| get(id).map_ok(move |room| (room, from)))
+        .map(|((_, room), from): ((_, Arc<Mutex<Room>>), usize)| {
+            let room = room.lock().unwrap();
+            let next = room.history_len().max(from);
+            let reply = warp::reply::json(&room.history_since(from));
+            warp::reply::with_header(reply, "X-Next-From", next.to_string())
+        });
+
+    let room_history_length = path!["room" / usize / "history" / "length"]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| {
+            let length = room.lock().unwrap().history_len();
+            warp::reply::json(&HistoryLength { length })
+        });
+
+    let get = get_room.clone();
+    let room_history_step = path!["room" / usize / "history" / "step" / usize]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(move |id, step| get(id).map_ok(move |room| (room, step)))
+        .map(|((_, room), step): ((_, Arc<Mutex<Room>>), usize)| {
+            api::history_step(&room, step)
+        });
+
+    let room_state = path!["room" / usize / "state"]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| warp::reply::json(&api::state(&room)));
+
+    let room_events = path!["room" / usize / "events"]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_room.clone())
         .map(|(_, room): (_, Arc<Mutex<Room>>)| {
-            warp::reply::json(&room.lock().unwrap().history)
+            let events = api::events(&room);
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
         });
 
-    let err_404 = warp::any()
-        .map(html::page_not_found)
-        .map(warp::reply::html)
-        .map(|reply| warp::reply::with_status(reply, StatusCode::NOT_FOUND));
+    let room_watch = path!["room" / usize / "watch"]
+        .and(warp::path::end())
+        .and_then(get_room.clone())
+        .and(warp::ws())
+        .map(|(_, room): (_, Arc<Mutex<Room>>), ws: warp::ws::Ws| {
+            let events = room.lock().unwrap().events();
+            ws.on_upgrade(move |socket| api::watch(socket, events))
+        });
+
+    let rooms = path!["rooms"]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_rooms.clone())
+        .map(|rooms: Rooms| warp::reply::json(&api::rooms(&rooms.read().unwrap())));
+
+    let metrics = path!["metrics"]
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_rooms.clone())
+        .and(with_waitlist.clone())
+        .then(move |rooms: Rooms, waitlist: Arc<WaitingList>| {
+            let metrics = metrics.clone();
+            async move {
+                let text = api::metrics(&metrics, &rooms, &waitlist).await;
+                warp::reply::with_header(
+                    text,
+                    "content-type",
+                    "text/plain; version=0.0.4",
+                )
+            }
+        });
+
+    let room_create = path!["rooms"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(with_rooms.clone())
+        .and(warp::body::json())
+        .map(|rooms: Rooms, new: NewRoom| {
+            let result = api::create_room(&rooms, new);
+            let status = if result.is_ok() {
+                StatusCode::CREATED
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            warp::reply::with_status(warp::reply::json(&Created::from(result)), status)
+        });
+
+    let room_delete = path!["room" / usize]
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_rooms.clone())
+        .and(with_waitlist.clone())
+        .then(|id, rooms: Rooms, waitlist: Arc<WaitingList>| async move {
+            let (result, status) = match api::delete_room(&rooms, &waitlist, id).await {
+                Some(msg) => (Ok(msg), StatusCode::OK),
+                None => (Err("No such room.".to_owned()), StatusCode::NOT_FOUND),
+            };
+            warp::reply::with_status(api::reply(result), status)
+        });
+
+    let room_start = path!["room" / usize / "start"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::start(&room)));
+
+    let room_reset = path!["room" / usize / "reset"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::reset(&room)));
+
+    let room_restart = path!["room" / usize / "restart"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::restart(&room)));
+
+    let room_end = path!["room" / usize / "end"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::force_end(&room)));
+
+    let room_pause = path!["room" / usize / "pause"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::pause(&room)));
+
+    let room_resume = path!["room" / usize / "resume"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .map(|(_, room): (_, Arc<Mutex<Room>>)| api::reply(api::resume(&room)));
 
-    index
-        .or(room_page)
-        .or(room_request)
+    let room_subscribe = path!["room" / usize / "subscribe"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and_then(get_room.clone())
+        .and(with_waitlist.clone())
+        .and(warp::body::json())
+        .then(
+            |(_, room): (_, Arc<Mutex<Room>>),
+             waitlist: Arc<WaitingList>,
+             req: WaiterRequest| async move {
+                api::reply(api::subscribe(&room, &waitlist, req.waiter).await)
+            },
+        );
+
+    let room_subscribe_all = path!["room" / usize / "subscribe_all"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(get_room.clone())
+        .and(with_waitlist.clone())
+        .then(
+            |(_, room): (_, Arc<Mutex<Room>>), waitlist: Arc<WaitingList>| async move {
+                api::reply(api::subscribe_all(&room, &waitlist).await)
+            },
+        );
+
+    let room_unsubscribe = path!["room" / usize / "unsubscribe"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and_then(get_room.clone())
+        .and(with_waitlist.clone())
+        .and(warp::body::json())
+        .then(
+            |(_, room): (_, Arc<Mutex<Room>>),
+             waitlist: Arc<WaitingList>,
+             req: WaiterRequest| async move {
+                api::reply(api::unsubscribe(&room, &waitlist, req.waiter).await)
+            },
+        );
+
+    let waiters_kill = path!["waiters" / "kill"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(with_waitlist.clone())
+        .and(warp::body::json())
+        .then(
+            |waitlist: Arc<WaitingList>, req: WaiterRequest| async move {
+                api::reply(api::kill(&waitlist, req.waiter).await)
+            },
+        );
+
+    let waiters_kill_all = path!["waiters" / "kill_all"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_waitlist.clone())
+        .then(|waitlist: Arc<WaitingList>| async move {
+            api::reply(api::kill_all(&waitlist).await)
+        });
+
+    let validate_layout = path!["validate-layout"]
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(api::validate_layout)
+        .map(|report| warp::reply::json(&report));
+
+    let actions = room_start
+        .or(room_reset)
+        .or(room_restart)
+        .or(room_end)
+        .or(room_pause)
+        .or(room_resume)
+        .or(room_subscribe)
+        .or(room_subscribe_all)
+        .or(room_unsubscribe)
+        .or(waiters_kill)
+        .or(waiters_kill_all)
+        .or(validate_layout);
+
+    #[cfg(feature = "html-panel")]
+    let (controls, err_404) = {
+        let index = warp::path::end()
+            .and(with_rooms.clone())
+            .and(with_waitlist.clone())
+            .then(|rooms: Rooms, waitlist: Arc<WaitingList>| async move {
+                html::index(&rooms, waitlist, None).await
+            })
+            .map(html::reply);
+
+        let room_page = path!["room" / usize]
+            .and(warp::path::end())
+            .and(warp::get())
+            .and_then(get_room.clone())
+            .untuple_one()
+            .and(with_waitlist.clone())
+            .and(warp::any().map(|| None))
+            .then(html::room_page)
+            .map(html::reply);
+
+        let spectate = path!["room" / usize / "spectate"]
+            .and(warp::path::end())
+            .and(warp::get())
+            .and_then(get_room.clone())
+            .untuple_one()
+            .map(html::spectate_page)
+            .map(html::reply);
+
+        let leaderboard = path!["leaderboard"]
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_rooms.clone())
+            .map(|rooms: Rooms| html::leaderboard_page(&rooms.read().unwrap()))
+            .map(html::reply);
+
+        let room_request = room_request.then(html::room_request).map(html::reply);
+        let room_doodahs = room_doodahs.then(html::room_doodahs).map(html::reply);
+
+        let err_404 = warp::any()
+            .map(html::page_not_found)
+            .map(html::reply)
+            .map(|reply| warp::reply::with_status(reply, StatusCode::NOT_FOUND));
+
+        (
+            index
+                .or(room_page)
+                .or(spectate)
+                .or(leaderboard)
+                .or(room_request)
+                .or(room_doodahs),
+            err_404,
+        )
+    };
+
+    // without the control panel, the control actions just report back in JSON
+    #[cfg(not(feature = "html-panel"))]
+    let (controls, err_404) = {
+        let room_request = room_request
+            .then(api::room_request)
+            .map(|outcome| warp::reply::json(&outcome));
+        let room_doodahs = room_doodahs
+            .map(api::room_doodahs)
+            .map(|outcome| warp::reply::json(&outcome));
+        let leaderboard = path!["leaderboard"]
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_rooms.clone())
+            .map(|rooms: Rooms| {
+                warp::reply::json(&api::leaderboard(&rooms.read().unwrap()))
+            });
+
+        let err_404 = warp::any()
+            .map(|| warp::reply::with_status("Not found", StatusCode::NOT_FOUND));
+
+        (room_request.or(room_doodahs).or(leaderboard), err_404)
+    };
+
+    controls
+        .or(actions)
         .or(room_history)
+        .or(room_history_from)
+        .or(room_history_length)
+        .or(room_history_step)
+        .or(room_state)
+        .or(room_events)
+        .or(room_watch)
+        .or(rooms)
+        .or(room_create)
+        .or(room_delete)
+        .or(metrics)
         .or(err_404)
 }
 
+const SIMPLE: &str = "
+    #####
+    .....
+    .....
+    .....
+    .....
+";
+
+const LARGE: &str = "
+    ............#.......
+    ............#.......
+    ....###.....#.......
+    ....#.......#.......
+    ....#.#..#.....##...
+    .........#.....##...
+    ...#.....#..#####...
+    ............###.....
+    ............###.....
+    ###.####....####.###
+    .......#.........#..
+    .......#............
+    .......#....#.......
+    .......#....#....#..
+    ########....#....###
+    ............#.......
+";
+
+const BOXED: &str = "
+    ##########
+    #........#
+    #........#
+    #........#
+    #........#
+    #........#
+    #........#
+    #........#
+    #........#
+    ##########
+";
+
+const SPECKLED: &str = "
+    ........
+    ....#...
+    .##.....
+    ......#.
+    ..#..##.
+    ......#.
+    .#.#....
+    ........
+";
+
+/// Start building a room from one of the built-in layouts.
+fn layout(text: &str) -> RoomBuilder {
+    let (width, height, tiles) =
+        Map::from_ascii(text).expect("built-in room layouts should be valid");
+    Room::builder().width(width).height(height).tiles(tiles)
+}
+
+/// Finish building one of the built-in rooms.
+fn finish(builder: RoomBuilder) -> Room {
+    builder.build().expect("built-in rooms should be valid")
+}
+
 /// Create a simple room
 fn create_simple() -> Arc<Mutex<Room>> {
-    use Tile::*;
-    Arc::new(Mutex::new(Room::new(
-        5, 5, vec![
-            Wall,  Wall,  Wall,  Wall,  Wall,
-            Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank,
-        ], None, 500, "Simple",
-        "A very small and simple room for testing with."
-    )))
+    let mut room = finish(
+        layout(SIMPLE)
+            .max_turns(500)
+            .doodah_count(1)
+            .name("Simple")
+            .description("A very small and simple room for testing with."),
+    );
+    room.free_space_per_snake = Some(4);
+    Arc::new(Mutex::new(room))
 }
 
 /// Create a large room
 fn create_large() -> Arc<Mutex<Room>> {
-    use Tile::*;
-    Arc::new(Mutex::new(Room::new(
-        20, 16, vec![
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Wall,  Blank, Wall,  Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Blank, Blank, Blank,
-            Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Wall,  Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
-            Wall,  Wall,  Wall,  Blank, Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Wall,  Blank, Wall,  Wall,  Wall,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank,
-            Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-        ], None, 12_000, "Large",
-        "A very large room with interesting wall placing."
+    Arc::new(Mutex::new(finish(
+        layout(LARGE)
+            .max_turns(12_000)
+            .doodah_count(3)
+            .name("Large")
+            .description("A very large room with interesting wall placing."),
     )))
 }
 
-#[rustfmt::skip]
-fn create_rooms() -> Vec<Arc<Mutex<Room>>> {
-    use Tile::*;
-    let boxed = Arc::new(Mutex::new(Room::new(
-        10, 10, vec![
-            Wall, Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
-            Wall, Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,
-        ], None, 1_000, "Boxed",
-        "A moderate-sized room that is boxed in around the outside."
+/// The built-in rooms, used when there's no room definitions file.
+fn builtin_rooms() -> Vec<Arc<Mutex<Room>>> {
+    let boxed = Arc::new(Mutex::new(finish(
+        layout(BOXED)
+            .max_turns(1_000)
+            .doodah_count(1)
+            .name("Boxed")
+            .description("A moderate-sized room that is boxed in around the outside."),
     )));
 
-    let speckled = Arc::new(Mutex::new(Room::new(
-        8, 8, vec![
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank,
-            Blank, Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank,
-            Blank, Blank, Wall,  Blank, Blank, Wall,  Wall,  Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank,
-            Blank, Wall,  Blank, Wall,  Blank, Blank, Blank, Blank,
-            Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
-        ], None, 4_000, "Speckled",
-        "A medium-sized room with random walls placed in the centre."
+    let speckled = Arc::new(Mutex::new(finish(
+        layout(SPECKLED)
+            .max_turns(4_000)
+            .doodah_count(1)
+            .name("Speckled")
+            .description("A medium-sized room with random walls placed in the centre."),
     )));
 
     vec![
@@ -174,28 +575,323 @@ fn create_rooms() -> Vec<Arc<Mutex<Room>>> {
     ]
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Preparing rooms...");
-    lazy_static::initialize(&ROOMS);
+/// Where to look for room definitions if none are given; see the `config` module for
+/// the format.
+const ROOMS_FILE: &str = "rooms.toml";
+
+/// How often to look for clients that have been waiting longer than `--idle-timeout`.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to give the last messages to clients to be sent when shutting down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Wait for a request to stop the server: Ctrl-C, or SIGTERM on Unix.
+///
+/// If the signal handler can't be installed this never returns.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Couldn't listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Couldn't listen for Ctrl-C: {}", e);
+            future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Load the rooms from `config`, or from `ROOMS_FILE` if it exists.
+///
+/// The built-in rooms are used if the file doesn't define any.
+fn create_rooms(config: Option<&Path>) -> Result<Vec<Arc<Mutex<Room>>>, String> {
+    let path = config.unwrap_or_else(|| Path::new(ROOMS_FILE));
+    match config::load_rooms(path) {
+        Ok(rooms) if rooms.is_empty() => {
+            tracing::info!("No rooms in {}; using the built-in rooms", path.display());
+            Ok(builtin_rooms())
+        }
+        Ok(rooms) => {
+            tracing::info!("Loaded {} room(s) from {}", rooms.len(), path.display());
+            Ok(rooms
+                .into_iter()
+                .map(|room| Arc::new(Mutex::new(room)))
+                .collect())
+        }
+        Err(ConfigError::Io(ref e))
+            if config.is_none() && e.kind() == ErrorKind::NotFound =>
+        {
+            tracing::info!("No {} found; using the built-in rooms", ROOMS_FILE);
+            Ok(builtin_rooms())
+        }
+        Err(e) => Err(format!("Failed to load {}: {}", path.display(), e)),
+    }
+}
+
+/// Set up TLS for game clients from a PEM certificate chain and private key.
+#[cfg(feature = "tls")]
+fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, String> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Couldn't open {}: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Couldn't read {}: {}", cert_path.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| format!("Couldn't read {}: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("No private key found in {}", key_path.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Couldn't use the TLS certificate: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    lazy_static::initialize(&CLI);
+    let filter = EnvFilter::builder()
+        .with_default_directive(CLI.log_level.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let metrics = PrometheusBuilder::new().install_recorder()?;
+    api::describe_metrics();
+
+    tracing::info!("Preparing rooms...");
+    let mut rooms = match create_rooms(CLI.config.as_deref()) {
+        Ok(rooms) => RoomList::from(rooms),
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    rooms.set_history_dir(CLI.history_dir.clone());
+    let rooms = Arc::new(RwLock::new(rooms));
     let waiting_list = Arc::new(WaitingList::new());
 
     let serve_waitlist = waiting_list.clone();
-    let s_addr = "0.0.0.0:3001".parse()?;
-    let socket = TcpListener::bind(&s_addr)?;
-    println!("Execution server listening on {}", s_addr);
-    let tcp_srv = socket
-        .incoming()
-        .for_each(move |socket| server::process_socket(socket, serve_waitlist.clone()))
-        .map_err(|e| eprintln!("Error occurred: {:?}", e));
-
-    let w_addr = "0.0.0.0:80".parse::<SocketAddr>()?;
-    let warp_srv = warp::serve(manage_rooms(waiting_list)).bind(w_addr);
-    println!("HTTP server listening on {}", w_addr);
-
-    let mut rt = Runtime::new()?;
-    rt.spawn(tcp_srv);
-    rt.spawn(warp_srv);
-    rt.shutdown_on_idle().wait().unwrap();
+    let serve_rooms = rooms.clone();
+    let listener = match TcpListener::bind(CLI.game_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!(
+                "Couldn't listen for game clients on {}: {}",
+                CLI.game_addr,
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "tls")]
+    let tls = match (&CLI.tls_cert, &CLI.tls_key) {
+        (Some(cert), Some(key)) => match tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    tracing::info!("Execution server listening on {}", listener.local_addr()?);
+    let tcp_srv = async move {
+        loop {
+            let result = listener.accept().await.and_then(|(socket, addr)| {
+                socket.set_nodelay(true).map(|_| (socket, addr))
+            });
+            let (socket, addr) = match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Error occurred: {:?}", e);
+                    continue;
+                }
+            };
+
+            // the handshake happens in the background, so a slow client can't hold
+            // up everyone else connecting
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &tls {
+                let accept = tls.accept(socket);
+                let waitlist = serve_waitlist.clone();
+                let rooms = serve_rooms.clone();
+                tokio::spawn(async move {
+                    match accept.await {
+                        Ok(socket) => {
+                            server::process_socket(socket, addr, waitlist, rooms)
+                        }
+                        Err(e) => tracing::warn!(%addr, "TLS handshake failed: {}", e),
+                    }
+                });
+                continue;
+            }
+
+            server::process_socket(
+                socket,
+                addr,
+                serve_waitlist.clone(),
+                serve_rooms.clone(),
+            );
+        }
+    };
+
+    // check on idle clients, so dead ones don't hold up the waiting list
+    let ping_waitlist = waiting_list.clone();
+    let ping_rooms = rooms.clone();
+    let ping_srv = async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(CLI.ping_interval));
+        loop {
+            interval.tick().await;
+            ping_waitlist.ping().await;
+            for (_, room) in ping_rooms.read().unwrap().iter() {
+                room.lock().unwrap().ping();
+            }
+        }
+    };
+
+    // drop anyone who never gets subscribed to a room
+    let stale_waitlist = waiting_list.clone();
+    let idle_timeout = Duration::from_secs(CLI.idle_timeout);
+    let stale_srv = async move {
+        let mut interval = tokio::time::interval(STALE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            stale_waitlist.timeout_stale_entries(idle_timeout).await;
+        }
+    };
+
+    let server = warp::serve(manage_rooms(rooms.clone(), waiting_list.clone(), metrics));
+    let (w_addr, warp_srv) = match server.try_bind_ephemeral(CLI.http_addr) {
+        Ok(bound) => bound,
+        Err(e) => {
+            tracing::error!("Couldn't serve HTTP on {}: {}", CLI.http_addr, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("HTTP server listening on {}", w_addr);
+
+    // stop accepting connections once asked to, then wind down the games
+    tokio::select! {
+        _ = future::join4(tcp_srv, warp_srv, ping_srv, stale_srv) => {}
+        _ = shutdown_signal() => {}
+    }
+    tracing::info!("Shutting down; signal again to exit immediately");
+    tokio::spawn(async {
+        shutdown_signal().await;
+        std::process::exit(1);
+    });
+
+    for (_, room) in rooms.read().unwrap().iter() {
+        room.lock().unwrap().shut_down();
+    }
+    waiting_list.shut_down().await;
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::game::{MapOptions, Tile};
+
+    /// The layouts as they were written out tile by tile, before they were drawn.
+    #[rustfmt::skip]
+    fn hand_written() -> Vec<(&'static str, usize, usize, Vec<Tile>)> {
+        use Tile::*;
+        vec![
+            (SIMPLE, 5, 5, vec![
+                Wall,  Wall,  Wall,  Wall,  Wall,
+                Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank,
+            ]),
+            (LARGE, 20, 16, vec![
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Wall,  Blank, Wall,  Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Blank, Blank, Blank,
+                Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Wall,  Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
+                Wall,  Wall,  Wall,  Blank, Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,  Wall,  Blank, Wall,  Wall,  Wall,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank,
+                Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Wall,  Wall,  Wall,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+            ]),
+            (BOXED, 10, 10, vec![
+                Wall, Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank, Wall,
+                Wall, Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,  Wall,
+            ]),
+            (SPECKLED, 8, 8, vec![
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Wall,  Blank, Blank, Blank,
+                Blank, Wall,  Wall,  Blank, Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank,
+                Blank, Blank, Wall,  Blank, Blank, Wall,  Wall,  Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Wall,  Blank,
+                Blank, Wall,  Blank, Wall,  Blank, Blank, Blank, Blank,
+                Blank, Blank, Blank, Blank, Blank, Blank, Blank, Blank,
+            ]),
+        ]
+    }
+
+    #[test]
+    fn drawn_layouts_match_the_hand_written_rooms() {
+        for (text, width, height, tiles) in hand_written() {
+            assert_eq!(Map::from_ascii(text).unwrap(), (width, height, tiles));
+        }
+    }
+
+    #[test]
+    fn drawn_layouts_draw_back_the_same() {
+        for (text, _, _, _) in hand_written() {
+            let (width, height, tiles) = Map::from_ascii(text).unwrap();
+            let map =
+                Map::new(width, height, tiles, vec![], MapOptions::default()).unwrap();
+            let drawn: Vec<_> = text
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .collect();
+            assert_eq!(map.to_ascii(), drawn.join("\n") + "\n");
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Save and load the history of a finished game.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::game::Map;
+
+/// Load a game's history, as served by `/room/<id>/history`, from a file.
+pub fn load_history(path: &Path) -> Result<Vec<Map>, serde_json::Error> {
+    let file = File::open(path).map_err(serde_json::Error::io)?;
+    serde_json::from_reader(BufReader::new(file))
+}
+
+/// Save a game's history to a new file in `dir`, in the form [`load_history`] reads.
+///
+/// The file is named after the room and when it was saved, like
+/// `room-3-1700000000000.json` (in milliseconds since the Unix epoch). The directory
+/// is created if it doesn't exist yet. Returns the path of the new file.
+///
+/// [`load_history`]: fn.load_history.html
+pub fn save_history(dir: &Path, room_id: usize, history: &[Map]) -> io::Result<PathBuf> {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    let path = dir.join(format!("room-{}-{}.json", room_id, millis));
+
+    fs::create_dir_all(dir)?;
+    let mut writer = BufWriter::new(File::create(&path)?);
+    serde_json::to_writer(&mut writer, history)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{Direction, MapOptions, Tile};
+
+    #[test]
+    fn saved_history_loads_back() {
+        let (width, height, tiles) = Map::from_ascii("....\n.#..\n....").unwrap();
+        let options = MapOptions {
+            starts: Some(vec![((0, 0).into(), Direction::East)]),
+            doodah_count: 2,
+            seed: Some(7),
+            ..MapOptions::default()
+        };
+        let mut history = vec![Map::new(width, height, tiles, vec![0], options).unwrap()];
+        for _ in 0..3 {
+            let next = history.last().unwrap().clone().step().unwrap();
+            history.push(next);
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("snake-arena-{}", std::process::id()));
+        let path = save_history(&dir, 3, &history).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("room-3-") && name.ends_with(".json"));
+
+        let loaded = load_history(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded, history);
+        assert_eq!(loaded[3].to_ascii(), history[3].to_ascii());
+    }
+
+    #[test]
+    fn maps_differing_in_what_clients_see_are_unequal() {
+        let (width, height, tiles) = Map::from_ascii("....\n....").unwrap();
+        let map = Map::new(width, height, tiles, vec![], MapOptions::default()).unwrap();
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(serde_json::from_str::<Map>(&json).unwrap(), map);
+
+        let mut other = map.clone();
+        other.tiles[3] = Tile::Wall;
+        assert_ne!(other, map);
+        let mut other = map.clone();
+        other.scores.insert(0, 1);
+        assert_ne!(other, map);
+    }
+}
@@ -1,23 +1,34 @@
 //! A game room.
 
-use std::collections::HashMap;
-use std::io::{BufReader, Error, ErrorKind};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
-use tokio::io;
-use tokio::net::TcpStream;
-use tokio::prelude::*;
-use tokio::sync::{mpsc, oneshot};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock};
+use tokio::time;
 
-use futures::future::Either;
+use futures::{future, FutureExt};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 
-use crate::game::{Map, SnakeID, Tile};
+use crate::bot::{Bot, Greedy};
+use crate::config::{ConfigError, RoomConfig};
+use crate::game::{
+    self, DeathCause, Map, MapOptions, ScoringMode, SnakeID, SpawnMode, Tile,
+    ValidationError, WrapConfig,
+};
+use crate::leaderboard::Leaderboard;
+use crate::replay;
+use crate::Stream;
 
 /// Possible requests we can get from the clients
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
-enum Request {
+pub enum Request {
     /// Turn their snake left
     Left,
 
@@ -26,26 +37,215 @@ enum Request {
 
     /// Let their snake go forwards
     Forward,
+
+    /// Ask for their current standing; this doesn't use up their turn
+    Status,
+
+    /// Ask for later maps to be sent as changes from the previous one; this doesn't use
+    /// up their turn
+    Deltas,
+
+    /// Say they're ready for the game to start; this doesn't use up their turn
+    Ready,
+
+    /// Leave the game, giving up their snake
+    Quit,
+}
+
+impl Request {
+    /// Whether this request uses up the client's turn.
+    fn is_move(self) -> bool {
+        match self {
+            Request::Left | Request::Right | Request::Forward | Request::Quit => true,
+            Request::Status | Request::Deltas | Request::Ready => false,
+        }
+    }
 }
 
-type Reader = BufReader<io::ReadHalf<TcpStream>>;
-type Writer = io::WriteHalf<TcpStream>;
+type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+type Writer = WriteHalf<Box<dyn Stream>>;
 type NamedSocket = (String, Reader, Writer);
 
-/// People that are waiting for a room
+/// The message sent to idle clients to check they're still connected.
+const PING: &[u8] = b"{\"state\":\"ping\"}\n";
+
+/// How often clients are told how long is left of the countdown before a game.
+const COUNTDOWN_TICK: Duration = Duration::from_millis(500);
+
+/// How long a client may take over a turn by default, in rooms without a shorter
+/// `move_timeout`, before it's dropped from the game.
+const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The last message sent to clients that aren't in a game when the server stops.
+const SHUTDOWN: &[u8] = b"{\"state\":\"error\",\"msg\":\"server is shutting down\"}\n";
+
+/// Send a ping to an idle client, without waiting on the connection.
+///
+/// Returns `false` if the connection is dead. If the ping doesn't fit in the send
+/// buffer the client can't have been reading for a long time, so that counts too;
+/// if nothing at all could be sent the client gets another chance next time.
+fn ping(writer: &mut Writer) -> bool {
+    match try_write(writer, PING) {
+        Ok(n) => n == PING.len(),
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+    }
+}
+
+/// Write to a client without waiting on the connection.
+///
+/// Returns how much was sent, or a `WouldBlock` error if nothing could be sent
+/// straight away.
+fn try_write(writer: &mut Writer, buf: &[u8]) -> std::io::Result<usize> {
+    let result = writer
+        .write(buf)
+        .now_or_never()
+        .unwrap_or_else(|| Err(ErrorKind::WouldBlock.into()));
+    // a TLS connection may hold on to what it was given until it's flushed
+    let _ = writer.flush().now_or_never();
+    result
+}
+
+/// A player's connection during a game.
+struct Client {
+    /// The player's snake.
+    id: SnakeID,
+
+    /// Messages to send to the player.
+    tx: mpsc::UnboundedSender<String>,
+
+    /// Requests the player has made.
+    rx: mpsc::UnboundedReceiver<Request>,
+
+    /// Whether the player has asked to be sent map deltas.
+    deltas: bool,
+
+    /// Whether the player has left the game; their connection is closed rather than
+    /// waiting for the game to end.
+    quit: bool,
+
+    /// Whether the player's connection goes back to the room once the game is done
+    /// with it, rather than being closed; shared with the task running the connection.
+    stay: Arc<AtomicBool>,
+}
+
+/// Every room on the server.
+///
+/// Rooms are given ids in the order they're added, and keep them as other rooms come
+/// and go, so links to a room stay valid. The final scores of games in every room are
+/// added to the list's [`Leaderboard`].
+///
+/// [`Leaderboard`]: ../leaderboard/struct.Leaderboard.html
+#[derive(Debug, Default)]
+pub struct RoomList {
+    rooms: BTreeMap<usize, Arc<Mutex<Room>>>,
+    next_id: usize,
+    leaderboard: Arc<Mutex<Leaderboard>>,
+    history_dir: Option<PathBuf>,
+}
+
+impl RoomList {
+    /// Create an empty list of rooms
+    pub fn new() -> Self {
+        RoomList::default()
+    }
+
+    /// Add a room to the list, returning its id.
+    pub fn push(&mut self, room: Arc<Mutex<Room>>) -> usize {
+        let id = self.next_id;
+        let mut room_inner = room.lock().unwrap();
+        room_inner.id = id;
+        room_inner.leaderboard = self.leaderboard.clone();
+        room_inner.history_dir = self.history_dir.clone();
+        drop(room_inner);
+        self.rooms.insert(id, room);
+        self.next_id += 1;
+        id
+    }
+
+    /// Get the room with the given id.
+    pub fn get(&self, id: usize) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.get(&id).cloned()
+    }
+
+    /// Remove the room with the given id from the list, returning it.
+    ///
+    /// Its id is never given to another room.
+    pub fn remove(&mut self, id: usize) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.remove(&id)
+    }
+
+    /// Iterate over the rooms and their ids, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Arc<Mutex<Room>>)> {
+        self.rooms.iter().map(|(&id, room)| (id, room))
+    }
+
+    /// Hand a connection to whichever room has a snake waiting for a player of that
+    /// name to reconnect; see [`reconnect`].
+    ///
+    /// The socket is given back if no room is waiting for them.
+    ///
+    /// [`reconnect`]: fn.reconnect.html
+    pub fn reconnect(
+        &self,
+        addr: SocketAddr,
+        name: String,
+        reader: Reader,
+        writer: Writer,
+    ) -> Result<(), NamedSocket> {
+        let mut socket = (name, reader, writer);
+        for room in self.rooms.values() {
+            socket = match reconnect(room, addr, socket) {
+                Ok(()) => return Ok(()),
+                Err(socket) => socket,
+            };
+        }
+        Err(socket)
+    }
+
+    /// Set where every room, including ones added later, saves the history of its
+    /// finished games; see [`Room::history_dir`].
+    ///
+    /// [`Room::history_dir`]: struct.Room.html#structfield.history_dir
+    pub fn set_history_dir(&mut self, dir: Option<PathBuf>) {
+        for room in self.rooms.values() {
+            room.lock().unwrap().history_dir = dir.clone();
+        }
+        self.history_dir = dir;
+    }
+
+    /// The tally of scores from games finished in any of the rooms.
+    pub fn leaderboard(&self) -> &Mutex<Leaderboard> {
+        &self.leaderboard
+    }
+}
+
+impl From<Vec<Arc<Mutex<Room>>>> for RoomList {
+    fn from(rooms: Vec<Arc<Mutex<Room>>>) -> Self {
+        let mut list = RoomList::new();
+        for room in rooms {
+            list.push(room);
+        }
+        list
+    }
+}
+
+/// People that are waiting for a room, along with when they started waiting
+///
+/// The list is locked asynchronously, so waiting on it doesn't hold up other tasks.
+/// When a room is needed too, the list is locked first.
 #[derive(Debug, Default)]
-pub struct WaitingList(Mutex<HashMap<SocketAddr, NamedSocket>>);
+pub struct WaitingList(RwLock<HashMap<SocketAddr, (NamedSocket, Instant)>>);
 
 impl WaitingList {
     /// Create the waiting list
     pub fn new() -> Self {
-        WaitingList(Mutex::new(HashMap::new()))
+        WaitingList(RwLock::new(HashMap::new()))
     }
 
     /// Insert the socket into the list.
     ///
     /// Returns `true` if it overwrote an existing waiter.
-    pub fn insert(
+    pub async fn insert(
         &self,
         addr: SocketAddr,
         name: String,
@@ -53,18 +253,33 @@ impl WaitingList {
         writer: Writer,
     ) -> bool {
         self.0
-            .lock()
-            .unwrap()
-            .insert(addr, (name, reader, writer))
+            .write()
+            .await
+            .insert(addr, ((name, reader, writer), Instant::now()))
             .is_some()
     }
 
     /// Moves the waiter to the given room.
-    pub fn subscribe(&self, addr: &SocketAddr, room: &mut Room) -> std::io::Result<()> {
-        let mut data = self.0.lock().unwrap();
+    pub async fn subscribe(
+        &self,
+        addr: &SocketAddr,
+        room: &Mutex<Room>,
+    ) -> std::io::Result<()> {
+        let mut data = self.0.write().await;
+        let mut room = room.lock().unwrap();
         if let Some(waiter) = data.remove(addr) {
             if let RoomState::Waiting = room.state {
-                room.players.insert(*addr, waiter);
+                if room.is_full() {
+                    data.insert(*addr, waiter);
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "provided room is already full",
+                    ));
+                }
+                let (socket, _) = waiter;
+                let name = socket.0.clone();
+                room.players.insert(*addr, socket);
+                room.announce_joined(*addr, &name);
                 Ok(())
             } else {
                 data.insert(*addr, waiter);
@@ -82,11 +297,26 @@ impl WaitingList {
     }
 
     /// Moves all waiters to the given room.
-    pub fn subscribe_all(&self, room: &mut Room) -> std::io::Result<()> {
+    pub async fn subscribe_all(&self, room: &Mutex<Room>) -> std::io::Result<()> {
+        let mut data = self.0.write().await;
+        let mut room = room.lock().unwrap();
         if let RoomState::Waiting = room.state {
-            let mut data = self.0.lock().unwrap();
+            if room.players.len() + data.len() > room.max_players() {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "not enough space in the room for all waiters",
+                ));
+            }
             let mut data = std::mem::replace(&mut *data, HashMap::new());
-            room.players.extend(data.drain());
+            let joined: Vec<_> = data
+                .iter()
+                .map(|(&addr, ((name, _, _), _))| (addr, name.clone()))
+                .collect();
+            room.players
+                .extend(data.drain().map(|(addr, (socket, _))| (addr, socket)));
+            for (addr, name) in joined {
+                room.announce_joined(addr, &name);
+            }
             Ok(())
         } else {
             Err(Error::new(
@@ -96,31 +326,107 @@ impl WaitingList {
         }
     }
 
+    /// Moves a player in the given room back to the waiting list.
+    pub async fn unsubscribe(
+        &self,
+        addr: &SocketAddr,
+        room: &Mutex<Room>,
+    ) -> std::io::Result<()> {
+        let mut data = self.0.write().await;
+        let mut room = room.lock().unwrap();
+        let socket = room
+            .players
+            .remove(addr)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "address not in room"))?;
+        room.announce(&serde_json::json!({ "state": "left", "addr": addr }), None);
+        data.insert(*addr, (socket, Instant::now()));
+        Ok(())
+    }
+
     /// Removes a socket from the waiting list.
     ///
     /// Returns `true` if it removed something.
-    pub fn remove(&self, addr: &SocketAddr) -> bool {
-        self.0.lock().unwrap().remove(addr).is_some()
+    pub async fn remove(&self, addr: &SocketAddr) -> bool {
+        self.0.write().await.remove(addr).is_some()
     }
 
     /// Clear the waiting list.
-    pub fn clear(&self) {
-        self.0.lock().unwrap().clear();
+    pub async fn clear(&self) {
+        self.0.write().await.clear();
     }
 
     /// Get the list of people in the waiting list
-    pub fn waiters(&self) -> Vec<(SocketAddr, String)> {
+    pub async fn waiters(&self) -> Vec<(SocketAddr, String)> {
         self.0
-            .lock()
-            .unwrap()
+            .read()
+            .await
             .iter()
-            .map(|(&addr, (name, _, _))| (addr, name.clone()))
+            .map(|(&addr, ((name, _, _), _))| (addr, name.clone()))
             .collect()
     }
 
+    /// Ping everyone waiting, dropping the connections that turn out to be dead.
+    ///
+    /// Returns how many connections were dropped.
+    pub async fn ping(&self) -> usize {
+        let mut data = self.0.write().await;
+        let before = data.len();
+        data.retain(|addr, ((name, _, writer), _)| {
+            let alive = ping(writer);
+            if !alive {
+                tracing::info!(%addr, "Dropping {} after a failed ping", name);
+            }
+            alive
+        });
+        before - data.len()
+    }
+
+    /// Drop everyone who has been waiting for longer than `max_age`.
+    ///
+    /// Returns how many connections were dropped.
+    pub async fn timeout_stale_entries(&self, max_age: Duration) -> usize {
+        let mut data = self.0.write().await;
+        let before = data.len();
+        data.retain(|addr, ((name, _, _), since)| {
+            let stale = since.elapsed() > max_age;
+            if stale {
+                tracing::info!(%addr, "Dropping {} after waiting too long", name);
+            }
+            !stale
+        });
+        before - data.len()
+    }
+
+    /// How long the socket has been in the waiting list, if it's there at all.
+    pub async fn age_of(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.0
+            .read()
+            .await
+            .get(addr)
+            .map(|(_, since)| since.elapsed())
+    }
+
     /// Get the number of people waiting
-    pub fn len(&self) -> usize {
-        self.0.lock().unwrap().len()
+    pub async fn len(&self) -> usize {
+        self.0.read().await.len()
+    }
+
+    /// Whether nobody is waiting
+    pub async fn is_empty(&self) -> bool {
+        self.0.read().await.is_empty()
+    }
+
+    /// Whether the socket is in the waiting list
+    pub async fn contains(&self, addr: &SocketAddr) -> bool {
+        self.0.read().await.contains_key(addr)
+    }
+
+    /// Tell everyone waiting that the server is stopping, and drop their connections.
+    pub async fn shut_down(&self) {
+        for (_, ((_, _, mut writer), _)) in self.0.write().await.drain() {
+            // they're being disconnected anyway, so it doesn't matter if this fails
+            let _ = try_write(&mut writer, SHUTDOWN);
+        }
     }
 }
 
@@ -130,10 +436,18 @@ enum RoomState {
     Playing {
         map: Arc<Mutex<Map>>,
         addrs: HashMap<SocketAddr, (String, SnakeID)>,
+        /// The snakes the server plays, which are kept off the leaderboard.
+        bots: HashSet<SnakeID>,
         breaker: oneshot::Sender<()>,
+        paused: watch::Sender<bool>,
+        reconnects: mpsc::UnboundedSender<Client>,
+        /// The `stay` flags of the game's players, so their connections can be
+        /// stopped from coming back if the game is reset.
+        stays: Vec<Arc<AtomicBool>>,
     },
     Finished {
         scores: HashMap<SocketAddr, (String, usize)>,
+        causes: HashMap<SocketAddr, DeathCause>,
     },
 }
 
@@ -141,6 +455,7 @@ enum RoomState {
 pub enum State {
     Waiting {
         players: Vec<(SocketAddr, String)>,
+        max_players: usize,
     },
     Playing {
         map: Arc<Mutex<Map>>,
@@ -148,23 +463,188 @@ pub enum State {
     },
     Finished {
         scores: HashMap<SocketAddr, (String, usize)>,
+        causes: HashMap<SocketAddr, DeathCause>,
     },
 }
 
+/// Something that happened in a room, for anyone watching it.
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    /// The game took a step, giving the new map as JSON.
+    Step(Arc<String>),
+
+    /// The game finished or was reset, giving the final scores as JSON.
+    Done(Arc<String>),
+}
+
+/// The rules in effect for a game, as sent to clients when it starts.
+#[derive(Clone, Debug, Serialize)]
+pub struct Rules {
+    /// Map width
+    pub width: usize,
+
+    /// Map height
+    pub height: usize,
+
+    /// Which edges of the map wrap around.
+    pub wrap: WrapConfig,
+
+    /// Least time between turns, in milliseconds.
+    pub timestep_ms: Option<u64>,
+
+    /// How long clients have to respond each turn, in milliseconds.
+    pub move_timeout_ms: Option<u64>,
+
+    /// How long a client may take over a turn, however long the room waits between
+    /// steps, before it's dropped from the game, in milliseconds.
+    pub client_timeout_ms: u64,
+
+    /// How long a player who loses their connection has to come back and take their
+    /// snake again, in milliseconds.
+    pub reconnect_window_ms: u64,
+
+    /// How long clients have to say they're ready before the game starts, in
+    /// milliseconds.
+    pub countdown_ms: u64,
+
+    /// Maximum number of turns in a game, if any.
+    pub max_turns: Option<usize>,
+
+    /// How many unparseable lines a client may send before it's disconnected.
+    pub max_protocol_errors: usize,
+
+    /// How many points a snake loses each time it turns.
+    pub turn_penalty: usize,
+
+    /// How snakes earn their points.
+    pub scoring: ScoringMode,
+
+    /// After how many turns the border starts closing in, if it ever does.
+    pub shrink_after: Option<usize>,
+
+    /// How many turns apart each ring of the border closes in.
+    pub shrink_interval: usize,
+
+    /// How many doodahs are kept on the board at once.
+    pub doodah_count: usize,
+
+    /// How likely doodahs are to be worth each value; a doodah is worth `i + 1` with
+    /// a chance proportional to `doodah_weights[i]`.
+    pub doodah_weights: Vec<u32>,
+
+    /// How many tiles long snakes start, including their head.
+    pub initial_length: usize,
+}
+
 /// The room that snakes play in
 #[derive(Debug)]
 pub struct Room {
     state: RoomState,
     players: HashMap<SocketAddr, NamedSocket>,
 
+    /// Snakes in the game in progress whose players lost their connection, with the
+    /// players' names and when they were lost, waiting for them to come back.
+    ///
+    /// Players may share a name, so this is kept by snake.
+    pending_reconnects: BTreeMap<SnakeID, (String, Instant)>,
+
     pub history: Vec<Map>,
 
+    /// Most maps to keep in `history`; older ones are dropped as new ones come in.
+    ///
+    /// `None` means the whole game is kept.
+    pub history_limit: Option<usize>,
+
+    /// Directory to save the history of each finished game to, as a JSON file; see
+    /// [`replay::save_history`].
+    ///
+    /// Only the maps still in `history` are saved, so with a `history_limit` the start
+    /// of a long game is missing from the file. `None` means games are only kept in
+    /// memory.
+    ///
+    /// [`replay::save_history`]: ../replay/fn.save_history.html
+    pub history_dir: Option<PathBuf>,
+
     /// How long between each snake movement.
     /// `None` means it just goes as soon as it receives all results.
     pub timestep: Option<Duration>,
 
+    /// How long the server waits for each client's move in a turn; a client that
+    /// hasn't answered by then is dropped and its snake removed.
+    ///
+    /// `None` means clients have the `timestep` to answer in, if there is one.
+    pub move_timeout: Option<Duration>,
+
+    /// The most time a client may take over a turn, however it's spent, before its
+    /// connection is closed and its snake removed.
+    ///
+    /// This keeps a hung client from holding up everyone else, even when there's no
+    /// `move_timeout` or `timestep`.
+    pub client_timeout: Duration,
+
+    /// How long a player who loses their connection during a game has to connect again
+    /// under the same name and take back their snake, which carries on straight ahead
+    /// in the meantime; see [`reconnect`].
+    ///
+    /// Zero means their snake is removed straight away.
+    ///
+    /// [`reconnect`]: fn.reconnect.html
+    pub reconnect_window: Duration,
+
+    /// How long players have to send `Ready` before the first step; anyone who hasn't
+    /// by then is dropped. The game starts early once everyone is ready.
+    ///
+    /// Zero means the game starts straight away.
+    pub countdown: Duration,
+
     /// Maximum number of turns in a game.
-    pub max_turns: usize,
+    ///
+    /// Once it's reached the game ends, and any surviving snakes keep their scores.
+    /// `None` means games only end once every snake has died.
+    pub max_turns: Option<usize>,
+
+    /// Number of players at which the game starts by itself.
+    ///
+    /// `None` means the game has to be started by hand.
+    pub auto_start: Option<usize>,
+
+    /// Whether the game starts by itself once the room has as many players as it
+    /// accepts; see [`max_players`].
+    ///
+    /// [`max_players`]: #method.max_players
+    pub start_when_full: bool,
+
+    /// How many of the server's own snakes join each game, after the players.
+    ///
+    /// They're played by [`Greedy`] bots, and listed under the unspecified address
+    /// `0.0.0.0` with their snake ID as the port, since they have no connection.
+    ///
+    /// [`Greedy`]: ../bot/struct.Greedy.html
+    pub bots: usize,
+
+    /// Whether players stay connected once their game is over, back in the room and
+    /// ready for the next one, rather than being disconnected; see [`restart`].
+    ///
+    /// [`restart`]: #method.restart
+    pub keep_players: bool,
+
+    /// How many unparseable lines a client may send before it's disconnected.
+    ///
+    /// Tolerated lines are logged and treated as `Forward`.
+    pub max_protocol_errors: usize,
+
+    /// Most players the room accepts.
+    ///
+    /// `None` means the only limits are the ones from the number of blank tiles; see
+    /// [`max_players`].
+    ///
+    /// [`max_players`]: #method.max_players
+    pub player_limit: Option<usize>,
+
+    /// Minimum number of blank tiles each snake should have to itself.
+    ///
+    /// When set, the room refuses players beyond what its blank tiles can support.
+    pub free_space_per_snake: Option<usize>,
 
     /// Map width
     pub width: usize,
@@ -172,7 +652,46 @@ pub struct Room {
     /// Map height
     pub height: usize,
 
-    /// Initial tile state; this should just be `Tile::Blank` and `Tile::Wall`.
+    /// Which edges of the map wrap around; `false.into()` makes every edge lethal.
+    pub wrap: WrapConfig,
+
+    /// How many points a snake loses each time it turns.
+    pub turn_penalty: usize,
+
+    /// How snakes earn their points: from doodahs, from staying alive, or both.
+    pub scoring: ScoringMode,
+
+    /// After how many turns the border starts closing in, turning one ring of tiles
+    /// into walls every `shrink_interval` turns.
+    ///
+    /// `None` means the border never closes in.
+    pub shrink_after: Option<usize>,
+
+    /// How many turns apart each ring of the border closes in.
+    pub shrink_interval: usize,
+
+    /// How many doodahs are kept on the board at once.
+    pub doodah_count: usize,
+
+    /// How likely doodahs are to be worth each value; a doodah is worth `i + 1` with
+    /// a chance proportional to `doodah_weights[i]`.
+    ///
+    /// The default of `[1]` makes every doodah worth one.
+    pub doodah_weights: Vec<u32>,
+
+    /// How many tiles long snakes start, including their head.
+    pub initial_length: usize,
+
+    /// How snakes are placed at the start of a game.
+    pub spawn: SpawnMode,
+
+    /// Seed for the game's randomness, so games can be replayed exactly.
+    ///
+    /// `None` means every game is different.
+    pub seed: Option<u64>,
+
+    /// Initial tile state; this should just be `Tile::Blank`, `Tile::Wall` and
+    /// `Tile::Hazard`.
     pub tiles: Vec<Tile>,
 
     /// The name of the room.
@@ -180,359 +699,2629 @@ pub struct Room {
 
     /// The description for the room.
     pub description: String,
+
+    /// Where game updates are sent, for anyone watching the room.
+    events: broadcast::Sender<RoomEvent>,
+
+    /// The id the room was given when it was added to a [`RoomList`].
+    ///
+    /// [`RoomList`]: struct.RoomList.html
+    id: usize,
+
+    /// How many maps have been dropped from the front of `history`.
+    history_trimmed: usize,
+
+    /// Where final scores are tallied; shared by every room in a [`RoomList`].
+    ///
+    /// [`RoomList`]: struct.RoomList.html
+    leaderboard: Arc<Mutex<Leaderboard>>,
 }
 
 impl Room {
     /// Create a room with the given initial map state.
+    ///
+    /// # Panics
+    ///
+    /// The layout must pass [`game::validate`]; use [`Room::builder`] to get an error
+    /// instead.
+    ///
+    /// [`game::validate`]: ../game/fn.validate.html
+    /// [`Room::builder`]: #method.builder
     pub fn new<S1: Into<String>, S2: Into<String>>(
         width: usize,
         height: usize,
         tiles: Vec<Tile>,
-        timestep: Option<Duration>,
-        max_turns: usize,
         name: S1,
         description: S2,
     ) -> Self {
+        if let Err(e) = game::validate(width, height, &tiles) {
+            panic!("invalid room layout: {}", e);
+        }
+
         Room {
             state: RoomState::Waiting,
             players: HashMap::new(),
+            pending_reconnects: BTreeMap::new(),
             history: Vec::new(),
-            timestep,
-            max_turns,
+            history_limit: None,
+            history_dir: None,
+            timestep: None,
+            move_timeout: None,
+            client_timeout: DEFAULT_CLIENT_TIMEOUT,
+            reconnect_window: Duration::from_secs(0),
+            countdown: Duration::from_secs(0),
+            max_turns: None,
+            auto_start: None,
+            start_when_full: false,
+            bots: 0,
+            keep_players: false,
+            max_protocol_errors: 3,
+            player_limit: None,
+            free_space_per_snake: None,
             width,
             height,
+            wrap: WrapConfig::default(),
+            turn_penalty: 0,
+            scoring: ScoringMode::DoodahsOnly,
+            shrink_after: None,
+            shrink_interval: 1,
+            doodah_count: 0,
+            doodah_weights: vec![1],
+            initial_length: 1,
+            spawn: SpawnMode::Random,
+            seed: None,
             tiles,
             name: name.into(),
             description: description.into(),
+            events: broadcast::channel(16).0,
+            id: 0,
+            history_trimmed: 0,
+            leaderboard: Arc::default(),
         }
     }
 
-    /// Remove a socket from the waiting list.
-    pub fn unsubscribe(
-        &mut self,
-        addr: &SocketAddr,
-        list: &WaitingList,
-    ) -> std::io::Result<()> {
-        self.players
-            .remove(addr)
-            .map(|(name, reader, writer)| list.insert(*addr, name, reader, writer))
-            .map(|_| ())
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "address not in room"))
+    /// Create a room from its definition, as loaded from a configuration file.
+    pub fn from_config(config: RoomConfig) -> Result<Self, ConfigError> {
+        config.build()
     }
 
-    /// Reset the room to its initial state.
+    /// Start building a room; see [`RoomBuilder`].
     ///
-    /// This removes all players and subscribers, resets the map, and goes back to the
-    /// `Waiting` state.
-    pub fn reset(&mut self) -> Result<(), &'static str> {
-        self.players.clear();
-        self.history.clear();
-        let old_state = std::mem::replace(&mut self.state, RoomState::Waiting);
+    /// [`RoomBuilder`]: struct.RoomBuilder.html
+    pub fn builder() -> RoomBuilder {
+        RoomBuilder::default()
+    }
 
-        match old_state {
-            RoomState::Playing { breaker, .. } => {
-                breaker.send(()).map_err(|_| "failed to send reset signal")
-            }
-            _ => Ok(()),
+    /// Get the rules that games in this room are played with.
+    pub fn rules(&self) -> Rules {
+        Rules {
+            width: self.width,
+            height: self.height,
+            wrap: self.wrap,
+            timestep_ms: self.timestep.map(|t| t.as_millis() as u64),
+            move_timeout_ms: self.move_deadline().map(|t| t.as_millis() as u64),
+            client_timeout_ms: self.client_timeout.as_millis() as u64,
+            reconnect_window_ms: self.reconnect_window.as_millis() as u64,
+            countdown_ms: self.countdown.as_millis() as u64,
+            max_turns: self.max_turns,
+            max_protocol_errors: self.max_protocol_errors,
+            turn_penalty: self.turn_penalty,
+            scoring: self.scoring,
+            shrink_after: self.shrink_after,
+            shrink_interval: self.shrink_interval,
+            doodah_count: self.doodah_count,
+            doodah_weights: self.doodah_weights.clone(),
+            initial_length: self.initial_length,
         }
     }
 
-    /// Return the current room state.
-    pub fn get_state(&self) -> State {
-        match &self.state {
-            RoomState::Waiting => State::Waiting {
-                players: self
-                    .players
-                    .iter()
-                    .map(|(&addr, (name, _, _))| (addr, name.clone()))
-                    .collect(),
-            },
-            RoomState::Playing { map, addrs, .. } => State::Playing {
-                map: map.clone(),
-                players: addrs.clone(),
-            },
-            RoomState::Finished { scores } => State::Finished {
-                scores: scores.clone(),
-            },
-        }
+    /// Count the blank tiles in the initial map.
+    pub fn blank_count(&self) -> usize {
+        self.tiles.iter().filter(|&&t| t == Tile::Blank).count()
     }
-}
 
-/// Helper to turn errors into `std::io::ErrorKind::BrokenPipe`
-fn to_broken_pipe<E: ToString>(e: E) -> Error {
-    Error::new(ErrorKind::BrokenPipe, e.to_string())
-}
+    /// Get the maximum number of players the room accepts.
+    ///
+    /// Every snake needs a blank tile to spawn on, so this is never more than the
+    /// number of blank tiles, less the ones the room's `bots` take. `player_limit` and
+    /// `free_space_per_snake` can lower it further.
+    pub fn max_players(&self) -> usize {
+        let blanks = self.blank_count();
+        let from_space = self
+            .free_space_per_snake
+            .map_or(blanks, |space| blanks / space.max(1))
+            .saturating_sub(self.bots);
+        self.player_limit
+            .map_or(from_space, |limit| limit.min(from_space))
+    }
 
-/// Set up the client for game execution.
-///
-/// Returns a sink/stream pair for communicating with the client.
-fn setup_client(
-    id: usize,
-    addr: SocketAddr,
-    reader: Reader,
-    writer: Writer,
-) -> (
-    impl Sink<SinkItem = String, SinkError = Error> + Send,
-    impl Stream<Item = Request, Error = Error> + Send,
-) {
-    let (tx_to_sock, rx_from_map) = mpsc::unbounded_channel::<String>();
-    let (tx_to_map, rx_from_sock) = mpsc::unbounded_channel::<Request>();
+    /// Tell the players waiting in the room about someone joining it, apart from the
+    /// new player themselves.
+    fn announce_joined(&mut self, addr: SocketAddr, name: &str) {
+        let msg = serde_json::json!({ "state": "joined", "addr": addr, "name": name });
+        self.announce(&msg, Some(addr));
+    }
 
-    let tx_to_sock = tx_to_sock.sink_map_err(to_broken_pipe);
-    let tx_to_map = tx_to_map.sink_map_err(to_broken_pipe);
-    let rx_from_sock = rx_from_sock.map_err(to_broken_pipe);
-    let rx_from_map = rx_from_map.map_err(to_broken_pipe);
-
-    let requests = io::lines(BufReader::new(reader))
-        .and_then(move |line: String| {
-            println!("{} ({}) received: {}", addr, id, line);
-            match line.as_str() {
-                "Forward" => Ok(Request::Forward),
-                "Left" => Ok(Request::Left),
-                "Right" => Ok(Request::Right),
-                _ => Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("couldn't parse line: {}", line),
-                )),
+    /// Send a message to every player waiting in the room, except `skip`.
+    ///
+    /// Like a ping, this doesn't wait on the connections: players that aren't keeping
+    /// up just miss it.
+    fn announce(&mut self, msg: &serde_json::Value, skip: Option<SocketAddr>) {
+        let line = format!("{}\n", msg);
+        for (addr, (_, _, writer)) in &mut self.players {
+            if Some(*addr) != skip {
+                let _ = try_write(writer, line.as_bytes());
             }
-        })
-        .forward(tx_to_map)
-        .map(|_| ());
-
-    let responses =
-        io::write_all(writer, format!("{{\"state\":\"start\",\"id\":{}}}\n", id))
-            .map(move |(writer, _)| writer)
-            .and_then(move |writer| {
-                rx_from_map.fold(writer, |writer, msg| {
-                    io::write_all(writer, format!("{}\n", msg)).map(|(writer, _)| writer)
-                })
-            });
+        }
+    }
 
-    let connection = requests.select2(responses).then(
-        move |result| -> Box<dyn Future<Item = _, Error = _> + Send> {
-            match result {
-                // things are OK; just send the other half
-                Ok(Either::A((_, responses))) => Box::new(responses.map(|_| ())),
-                // we've finished sending responses; don't wait for more requests!
-                Ok(Either::B(_)) => Box::new(future::ok(())),
-                // bad request; notify client and close connection
-                Err(Either::A((e, responses))) => {
-                    Box::new(responses.and_then(move |writer| {
-                        io::write_all(
-                            writer,
-                            format!("{{\"state\":\"error\",\"msg\":\"{}\"}}\n", e),
-                        )
-                        .and_then(|_| future::err(e))
-                    }))
-                }
-                // couldn't respond: just die, not much else to do
-                Err(Either::B((e, _))) => Box::new(future::err(e)),
-            }
-        },
-    );
+    /// How long clients have to answer each turn: the `move_timeout` if there is one,
+    /// otherwise the `timestep`.
+    pub fn move_deadline(&self) -> Option<Duration> {
+        self.move_timeout.or(self.timestep)
+    }
 
-    tokio::spawn(connection.then(move |result| {
-        if let Err(e) = result {
-            println!("Connection {} closed with error: {}", addr, e);
-        } else {
-            println!("Connection closed: {}", addr);
+    /// Test if the room can't accept any more players.
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.max_players()
+    }
+
+    /// Test if enough players have joined for the game to start by itself, either
+    /// because there are `auto_start` of them or because the room is full and
+    /// `start_when_full` is set.
+    ///
+    /// This is never true once the game has started.
+    pub fn should_auto_start(&self) -> bool {
+        match self.state {
+            RoomState::Waiting => {
+                let enough = self
+                    .auto_start
+                    .is_some_and(|min| self.players.len() >= min.max(1));
+                let full =
+                    self.start_when_full && !self.players.is_empty() && self.is_full();
+                enough || full
+            }
+            _ => false,
         }
-        Ok(())
-    }));
+    }
 
-    (tx_to_sock, rx_from_sock)
-}
+    /// Ping the players waiting for the game to start, dropping the connections that
+    /// turn out to be dead. Players already in a game are left alone.
+    ///
+    /// Returns how many connections were dropped.
+    pub fn ping(&mut self) -> usize {
+        let before = self.players.len();
+        self.players.retain(|addr, (name, _, writer)| {
+            let alive = ping(writer);
+            if !alive {
+                tracing::info!(%addr, "Dropping {} after a failed ping", name);
+            }
+            alive
+        });
+        before - self.players.len()
+    }
 
-/// Do one step of client interaction.
-fn do_client_step<'a, T, R>(
-    id: SnakeID,
-    tx: T,
-    rx: R,
-    map: Arc<Mutex<Map>>,
-    map_json: String,
-    timestep: Option<Duration>,
-) -> Box<dyn Future<Item = (SnakeID, T, R), Error = std::io::Error> + 'a + Send>
-where
-    T: Sink<SinkItem = String, SinkError = std::io::Error> + 'a + Send,
-    R: Stream<Item = Request, Error = std::io::Error> + 'a + Send,
-{
-    // don't bother receiving anything if they're dead
-    if !map.lock().unwrap().is_alive(id) {
-        let json = format!("{{\"state\":\"dead\",\"map\":{}}}", map_json);
-        return Box::new(tx.send(json).map(move |tx| (id, tx, rx)));
-    }
-
-    let rmap = map.clone();
-    let json = format!("{{\"state\":\"playing\",\"map\":{}}}", map_json);
-    let action = tx.send(json).and_then(move |tx| {
-        rx.into_future()
-            .map_err(|(e, _)| e)
-            .and_then(move |(req, rx)| {
-                match req {
-                    Some(Request::Forward) => {}
-                    Some(Request::Left) => rmap.lock().unwrap().turn_left(id),
-                    Some(Request::Right) => rmap.lock().unwrap().turn_right(id),
-                    None => return Err(to_broken_pipe("no request received")),
-                }
-                Ok((id, tx, rx))
-            })
-    });
+    /// The id the room was given when it was added to a [`RoomList`].
+    ///
+    /// [`RoomList`]: struct.RoomList.html
+    pub fn id(&self) -> usize {
+        self.id
+    }
 
-    let action = action.map_err(move |e| {
-        // on error, remove the associated snake from the map
-        map.lock().unwrap().delete_snake(id);
-        e
-    });
+    /// How many maps the current game has recorded, including any dropped from
+    /// `history`.
+    pub fn history_len(&self) -> usize {
+        self.history_trimmed + self.history.len()
+    }
 
-    if let Some(duration) = timestep {
-        let action = action
-            .timeout(duration)
-            .map_err(|e| Error::new(ErrorKind::TimedOut, e.to_string()));
-        Box::new(action)
-    } else {
-        Box::new(action)
+    /// The maps recorded from the given step onwards, as far as they're still kept.
+    ///
+    /// Steps count from the start of the game, so a client that has seen `n` maps can
+    /// ask for the ones after them with `history_since(n)`.
+    pub fn history_since(&self, step: usize) -> &[Map] {
+        let start = step.saturating_sub(self.history_trimmed);
+        &self.history[start.min(self.history.len())..]
     }
-}
 
-/// Execute the server work once we have all our client work done
-fn do_server_step<T>(
-    room: Arc<Mutex<Room>>,
-    map: Arc<Mutex<Map>>,
-    socket_txs: T,
-) -> Result<future::Loop<T, (Arc<Mutex<Room>>, T)>, ()> {
-    // always lock room before map
-    let mut room_inner = room.lock().unwrap();
-    let mut map_inner = map.lock().unwrap();
-    match map_inner.clone().step() {
-        Ok(map) => {
-            let map = std::mem::replace(&mut *map_inner, map);
-            room_inner.history.push(map);
-            if room_inner.history.len() > room_inner.max_turns {
-                println!("Exceeded maximum turn count! Aborting...");
-                Err(())
-            } else {
-                drop(room_inner);
-                Ok(future::Loop::Continue((room, socket_txs)))
+    /// The map recorded at the given step, counting from the start of the game.
+    ///
+    /// Returns `None` if the game hasn't got that far, or if the map has been dropped to
+    /// stay within `history_limit`.
+    pub fn history_at(&self, step: usize) -> Option<&Map> {
+        self.history.get(step.checked_sub(self.history_trimmed)?)
+    }
+
+    /// Add a map to the history, dropping the oldest ones beyond `history_limit`.
+    fn record(&mut self, map: Map) {
+        self.history.push(map);
+        if let Some(limit) = self.history_limit {
+            let excess = self.history.len().saturating_sub(limit);
+            self.history.drain(..excess);
+            self.history_trimmed += excess;
+        }
+    }
+
+    /// Watch the games played in this room.
+    pub fn events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.events.subscribe()
+    }
+
+    /// Reset the room to its initial state.
+    ///
+    /// This removes all players and subscribers, resets the map, and goes back to the
+    /// `Waiting` state. Players in a game in progress are sent `done` first, and are
+    /// disconnected even if the room has `keep_players` set.
+    pub fn reset(&mut self) -> Result<(), &'static str> {
+        if let RoomState::Playing { stays, .. } = &self.state {
+            for stay in stays {
+                stay.store(false, Ordering::Relaxed);
             }
         }
-        Err(scores) => {
-            room_inner.history.push(map_inner.clone());
-            if let RoomState::Playing { addrs, .. } = &room_inner.state {
-                let scores = scores
-                    .into_iter()
-                    .map(|(id, scr)| {
-                        (
-                            addrs
-                                .iter()
-                                .find(|&(_, &(_, old_id))| old_id == id)
-                                .map(|(&addr, (name, _))| (addr, name.clone()))
-                                .expect("addr -> snake table incomplete"),
-                            scr,
-                        )
-                    })
-                    .map(|((addr, name), scr)| (addr, (name, scr)))
-                    .collect();
-                room_inner.state = RoomState::Finished { scores };
-                Ok(future::Loop::Break(socket_txs))
-            } else {
-                println!("room in weird state?");
-                Err(())
+        self.clear()
+    }
+
+    /// Go back to the `Waiting` state, ending any game in progress; its players'
+    /// connections are left to come back to the room if they're set to.
+    fn clear(&mut self) -> Result<(), &'static str> {
+        self.players.clear();
+        self.history.clear();
+        self.history_trimmed = 0;
+        self.pending_reconnects.clear();
+        let old_state = std::mem::replace(&mut self.state, RoomState::Waiting);
+
+        match old_state {
+            RoomState::Playing { breaker, map, .. } => {
+                // it doesn't matter if nobody is watching
+                let scores = serde_json::to_string(&map.lock().unwrap().scores).unwrap();
+                let _ = self.events.send(RoomEvent::Done(Arc::new(scores)));
+                breaker.send(()).map_err(|_| "failed to send reset signal")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Go back to waiting for a new game, like [`reset`], but keep the players.
+    ///
+    /// Players waiting in the room stay put. If the room has `keep_players` set, the
+    /// players of the last game come back as well, so the same match can simply be
+    /// started again; for a game in progress, that's as soon as it's done with their
+    /// connections.
+    ///
+    /// [`reset`]: #method.reset
+    pub fn restart(&mut self) -> Result<(), &'static str> {
+        let players = std::mem::take(&mut self.players);
+        let result = self.clear();
+        self.players = players;
+        result
+    }
+
+    /// Take back a player whose game is over, ready for the next one.
+    ///
+    /// If another game has already started, their connection is closed instead.
+    fn rejoin(&mut self, addr: SocketAddr, socket: NamedSocket) {
+        if let RoomState::Playing { .. } = self.state {
+            tracing::info!("Connection closed, as the room is playing again");
+        } else {
+            tracing::info!("Connection kept for the next game");
+            self.players.insert(addr, socket);
+        }
+    }
+
+    /// Stop the room because the server is shutting down.
+    ///
+    /// Players waiting for the game are told why they're being disconnected; a game in
+    /// progress is ended as by [`reset`], so its players are sent `done`.
+    ///
+    /// [`reset`]: #method.reset
+    pub fn shut_down(&mut self) {
+        for (_, (_, _, mut writer)) in self.players.drain() {
+            // they're being disconnected anyway, so it doesn't matter if this fails
+            let _ = try_write(&mut writer, SHUTDOWN);
+        }
+        // the game may have just finished by itself, in which case there's nothing to stop
+        let _ = self.reset();
+    }
+
+    /// End a game in progress straight away, keeping the scores as they stand.
+    ///
+    /// Unlike [`reset`], the room moves on to `Finished` as if the game had ended by
+    /// itself, and its players are sent `done`.
+    ///
+    /// [`reset`]: #method.reset
+    pub fn force_end(&mut self) -> Result<(), &'static str> {
+        let map = match &self.state {
+            RoomState::Playing { map, .. } => map.clone(),
+            _ => return Err("room is not in progress"),
+        };
+        tracing::info!(room_id = self.id, "Ending game early.");
+        let breaker = self
+            .finish(&mut map.lock().unwrap())
+            .ok_or("room is not in progress")?;
+        breaker.send(()).map_err(|_| "failed to send end signal")
+    }
+
+    /// Hold a game in progress after the step it's on.
+    ///
+    /// Players aren't sent anything while the game is paused, so they aren't timed out
+    /// either; it carries on from where it stopped once [`resume`] is called.
+    ///
+    /// [`resume`]: #method.resume
+    pub fn pause(&mut self) -> Result<(), &'static str> {
+        match &self.state {
+            RoomState::Playing { paused, .. } if *paused.borrow() => {
+                Err("room is already paused")
+            }
+            RoomState::Playing { paused, .. } => {
+                tracing::info!(room_id = self.id, "Pausing game.");
+                paused.send(true).map_err(|_| "failed to send pause signal")
+            }
+            _ => Err("room is not in progress"),
+        }
+    }
+
+    /// Carry on with a game held by [`pause`].
+    ///
+    /// [`pause`]: #method.pause
+    pub fn resume(&mut self) -> Result<(), &'static str> {
+        match &self.state {
+            RoomState::Playing { paused, .. } if *paused.borrow() => {
+                tracing::info!(room_id = self.id, "Resuming game.");
+                paused
+                    .send(false)
+                    .map_err(|_| "failed to send resume signal")
+            }
+            RoomState::Playing { .. } => Err("room is not paused"),
+            _ => Err("room is not in progress"),
+        }
+    }
+
+    /// Test if the game in progress is paused.
+    pub fn is_paused(&self) -> bool {
+        match &self.state {
+            RoomState::Playing { paused, .. } => *paused.borrow(),
+            _ => false,
+        }
+    }
+
+    /// End the game in progress with the snakes' scores as they stand on `map`, the
+    /// room's current map.
+    ///
+    /// Returns the game's breaker, so whoever's ending the game early can stop it; if
+    /// the room isn't playing nothing happens and this returns `None`.
+    fn finish(&mut self, map_inner: &mut Map) -> Option<oneshot::Sender<()>> {
+        let (addrs, bots, breaker) =
+            match std::mem::replace(&mut self.state, RoomState::Waiting) {
+                RoomState::Playing {
+                    addrs,
+                    bots,
+                    breaker,
+                    ..
+                } => (addrs, bots, breaker),
+                state => {
+                    self.state = state;
+                    return None;
+                }
+            };
+
+        let scores = serde_json::to_string(&map_inner.scores).unwrap();
+        let _ = self.events.send(RoomEvent::Done(Arc::new(scores)));
+        metrics::counter!("snake_arena_games_finished_total").increment(1);
+        metrics::histogram!("snake_arena_game_turns").record(map_inner.turn() as f64);
+
+        // keep the final map too, so the last eliminations are recorded; any snakes
+        // still alive are scored as they stand
+        map_inner.mark_survivors();
+        self.record(map_inner.clone());
+
+        let addr_of = |id| {
+            addrs
+                .iter()
+                .find(|&(_, &(_, old_id))| old_id == id)
+                .map(|(&addr, _)| addr)
+        };
+        let causes = map_inner
+            .causes
+            .iter()
+            .filter_map(|(&id, &cause)| Some((addr_of(id)?, cause)))
+            .collect();
+        let scores = map_inner
+            .scores
+            .clone()
+            .into_iter()
+            .map(|(id, scr)| {
+                (
+                    addrs
+                        .iter()
+                        .find(|&(_, &(_, old_id))| old_id == id)
+                        .map(|(&addr, (name, _))| (addr, name.clone()))
+                        .expect("addr -> snake table incomplete"),
+                    scr,
+                )
+            })
+            .map(|((addr, name), scr)| (addr, (name, scr)))
+            .collect::<HashMap<_, _>>();
+        let player_scores = addrs
+            .values()
+            .filter(|(_, id)| !bots.contains(id))
+            .filter_map(|(name, id)| Some((name.as_str(), *map_inner.scores.get(id)?)));
+        self.leaderboard.lock().unwrap().record(player_scores);
+        self.state = RoomState::Finished { scores, causes };
+
+        // writing the file can take a while, so keep it out of the game's way
+        if let Some(dir) = self.history_dir.clone() {
+            let room_id = self.id;
+            let history = self.history.clone();
+            if self.history_trimmed > 0 {
+                tracing::warn!(
+                    room_id,
+                    "Saving only the last {} steps; the first {} were dropped to stay \
+                     within the history limit",
+                    history.len(),
+                    self.history_trimmed
+                );
             }
+            tokio::task::spawn_blocking(move || {
+                match replay::save_history(&dir, room_id, &history) {
+                    Ok(path) => {
+                        tracing::info!(room_id, "Saved history to {}", path.display())
+                    }
+                    Err(e) => tracing::error!(
+                        room_id,
+                        "Couldn't save history to {}: {}",
+                        dir.display(),
+                        e
+                    ),
+                }
+            });
+        }
+        Some(breaker)
+    }
+
+    /// Change the number of doodahs on the board of a game in progress.
+    pub fn set_doodah_count(&mut self, count: usize) -> Result<(), &'static str> {
+        match &self.state {
+            RoomState::Playing { map, .. } => map.lock().unwrap().set_doodah_count(count),
+            _ => Err("room is not in progress"),
+        }
+    }
+
+    /// Return the current room state.
+    pub fn get_state(&self) -> State {
+        match &self.state {
+            RoomState::Waiting => State::Waiting {
+                players: self
+                    .players
+                    .iter()
+                    .map(|(&addr, (name, _, _))| (addr, name.clone()))
+                    .collect(),
+                max_players: self.max_players(),
+            },
+            RoomState::Playing { map, addrs, .. } => State::Playing {
+                map: map.clone(),
+                players: addrs.clone(),
+            },
+            RoomState::Finished { scores, causes } => State::Finished {
+                scores: scores.clone(),
+                causes: causes.clone(),
+            },
         }
     }
 }
 
-/// Shut things off and start playing
+/// An error encountered while building a room, when its layout fails
+/// [`game::validate`].
 ///
-/// Returns `false` if the room failed to start.
-pub fn run(room: Arc<Mutex<Room>>) -> bool {
-    let mut room_inner = room.lock().unwrap();
+/// [`game::validate`]: ../game/fn.validate.html
+pub type BuildError = ValidationError;
 
-    // make sure the room is in a good state
-    let good = match &room_inner.state {
-        RoomState::Waiting => !room_inner.players.is_empty(),
-        _ => false,
+/// Build a room one setting at a time.
+///
+/// Settings that aren't given keep the same defaults as [`Room::new`]; the map has no
+/// size until `width`, `height` and `tiles` are set.
+///
+/// [`Room::new`]: struct.Room.html#method.new
+#[derive(Clone, Debug, Default)]
+pub struct RoomBuilder {
+    width: usize,
+    height: usize,
+    tiles: Vec<Tile>,
+    timestep: Option<Duration>,
+    move_timeout: Option<Duration>,
+    client_timeout: Option<Duration>,
+    reconnect_window: Duration,
+    countdown: Duration,
+    max_turns: Option<usize>,
+    auto_start: Option<usize>,
+    start_when_full: bool,
+    bots: usize,
+    keep_players: bool,
+    max_players: Option<usize>,
+    scoring: ScoringMode,
+    spawn: SpawnMode,
+    shrink: Option<(usize, usize)>,
+    history_limit: Option<usize>,
+    doodah_count: usize,
+    doodah_weights: Option<Vec<u32>>,
+    name: String,
+    description: String,
+}
+
+impl RoomBuilder {
+    /// Set the map width.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Set the map height.
+    pub fn height(mut self, height: usize) -> Self {
+        self.height = height;
+        self
+    }
+
+    /// Set the initial tiles, which should just be `Tile::Blank`, `Tile::Wall` and
+    /// `Tile::Hazard`.
+    pub fn tiles(mut self, tiles: Vec<Tile>) -> Self {
+        self.tiles = tiles;
+        self
+    }
+
+    /// Set how long between each snake movement.
+    pub fn timestep(mut self, timestep: Duration) -> Self {
+        self.timestep = Some(timestep);
+        self
+    }
+
+    /// Set how long the server waits for each client's move, separately from the
+    /// `timestep`.
+    pub fn move_timeout(mut self, timeout: Duration) -> Self {
+        self.move_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the most time a client may take over a turn before it's dropped.
+    pub fn client_timeout(mut self, timeout: Duration) -> Self {
+        self.client_timeout = Some(timeout);
+        self
+    }
+
+    /// Let players who lose their connection during a game take back their snake by
+    /// connecting again within `window`.
+    pub fn reconnect_window(mut self, window: Duration) -> Self {
+        self.reconnect_window = window;
+        self
+    }
+
+    /// Set how long players have to say they're ready before the game starts.
+    pub fn countdown(mut self, countdown: Duration) -> Self {
+        self.countdown = countdown;
+        self
+    }
+
+    /// Set the maximum number of turns in a game.
+    pub fn max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = Some(max_turns);
+        self
+    }
+
+    /// Set the number of players at which the game starts by itself.
+    pub fn auto_start(mut self, players: usize) -> Self {
+        self.auto_start = Some(players);
+        self
+    }
+
+    /// Start the game by itself once the room has as many players as it accepts.
+    pub fn start_when_full(mut self) -> Self {
+        self.start_when_full = true;
+        self
+    }
+
+    /// Add `bots` of the server's own snakes to every game.
+    pub fn bots(mut self, bots: usize) -> Self {
+        self.bots = bots;
+        self
+    }
+
+    /// Keep players in the room once their game is over, ready for the next one.
+    pub fn keep_players(mut self) -> Self {
+        self.keep_players = true;
+        self
+    }
+
+    /// Set the most players the room accepts.
+    pub fn max_players(mut self, players: usize) -> Self {
+        self.max_players = Some(players);
+        self
+    }
+
+    /// Set how snakes earn their points.
+    pub fn scoring(mut self, scoring: ScoringMode) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// Set how snakes are placed at the start of a game.
+    pub fn spawn(mut self, spawn: SpawnMode) -> Self {
+        self.spawn = spawn;
+        self
+    }
+
+    /// Close in the border after `after` turns, one ring every `interval` turns.
+    pub fn shrink(mut self, after: usize, interval: usize) -> Self {
+        self.shrink = Some((after, interval));
+        self
+    }
+
+    /// Keep at most `limit` maps of a game's history.
+    pub fn history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = Some(limit);
+        self
+    }
+
+    /// Set how many doodahs are kept on the board at once.
+    pub fn doodah_count(mut self, doodah_count: usize) -> Self {
+        self.doodah_count = doodah_count;
+        self
+    }
+
+    /// Set how likely doodahs are to be worth each value; see
+    /// [`Room::doodah_weights`].
+    ///
+    /// [`Room::doodah_weights`]: struct.Room.html#structfield.doodah_weights
+    pub fn doodah_weights(mut self, weights: Vec<u32>) -> Self {
+        self.doodah_weights = Some(weights);
+        self
+    }
+
+    /// Set the name of the room.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    /// Set the description for the room.
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+
+    /// Check the settings and create the room.
+    pub fn build(self) -> Result<Room, BuildError> {
+        game::validate(self.width, self.height, &self.tiles)?;
+
+        let mut room = Room::new(
+            self.width,
+            self.height,
+            self.tiles,
+            self.name,
+            self.description,
+        );
+        room.timestep = self.timestep;
+        room.max_turns = self.max_turns;
+        room.doodah_count = self.doodah_count;
+        room.auto_start = self.auto_start;
+        room.start_when_full = self.start_when_full;
+        room.bots = self.bots;
+        room.keep_players = self.keep_players;
+        room.player_limit = self.max_players;
+        room.countdown = self.countdown;
+        room.move_timeout = self.move_timeout;
+        room.reconnect_window = self.reconnect_window;
+        if let Some(timeout) = self.client_timeout {
+            room.client_timeout = timeout;
+        }
+        if let Some(weights) = self.doodah_weights {
+            room.doodah_weights = weights;
+        }
+        room.history_limit = self.history_limit;
+        room.scoring = self.scoring;
+        room.spawn = self.spawn;
+        if let Some((after, interval)) = self.shrink {
+            room.shrink_after = Some(after);
+            room.shrink_interval = interval;
+        }
+        Ok(room)
+    }
+}
+
+/// Helper to turn errors into `std::io::ErrorKind::BrokenPipe`
+fn to_broken_pipe<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::BrokenPipe, e.to_string())
+}
+
+/// The JSON form of a line sent by a client, like `{"action":"left"}`.
+#[derive(Deserialize)]
+struct JsonRequest {
+    action: String,
+}
+
+/// Parse a line sent by a client.
+///
+/// Lines are either plain commands like `Left`, or JSON objects like
+/// `{"action":"left"}`. Either way the command's case doesn't matter.
+fn parse_request(line: &str) -> Result<Request, Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+    let command = if line.trim_start().starts_with('{') {
+        serde_json::from_str::<JsonRequest>(line)
+            .map_err(|e| invalid(format!("couldn't parse line: {}: {}", line, e)))?
+            .action
+    } else {
+        line.to_owned()
     };
-    if !good {
-        return false;
+
+    match command.to_ascii_lowercase().as_str() {
+        "forward" => Ok(Request::Forward),
+        "left" => Ok(Request::Left),
+        "right" => Ok(Request::Right),
+        "status" => Ok(Request::Status),
+        "deltas" => Ok(Request::Deltas),
+        "ready" => Ok(Request::Ready),
+        "quit" => Ok(Request::Quit),
+        _ => Err(invalid(format!("couldn't parse line: {}", line))),
     }
+}
 
-    // let the players know we've started by providing them their ID
-    // this also clears the player list
-    let (addrs, sockets): (HashMap<_, _>, Vec<_>) = room_inner
-        .players
-        .drain()
-        .enumerate()
-        .map(|(id, (addr, (name, reader, writer)))| {
-            let (tx, rx) = setup_client(id, addr, reader, writer);
-            ((addr, (name, id)), (id, tx, rx))
-        })
-        .unzip();
+/// Set up the client for game execution.
+///
+/// The client is told the game's `rules`, which should be provided as JSON. It may send
+/// up to `max_errors` unparseable lines before being disconnected.
+///
+/// The connection is closed once the game is done with the client, unless there's a
+/// room to `rejoin` and the client's `stay` flag is still set by then: in that case the
+/// socket goes back to the room as a waiting player.
+fn setup_client(
+    id: usize,
+    addr: SocketAddr,
+    socket: NamedSocket,
+    rules: &str,
+    max_errors: usize,
+    rejoin: Option<Weak<Mutex<Room>>>,
+) -> Client {
+    let (name, reader, writer) = socket;
+    let (tx_to_sock, rx_from_map) = mpsc::unbounded_channel::<String>();
+    let (tx_to_map, rx_from_sock) = mpsc::unbounded_channel::<Request>();
+    let stay = Arc::new(AtomicBool::new(rejoin.is_some()));
 
-    // update the room state; we can drop the lock when we're done here
-    let map = Arc::new(Mutex::new(Map::new(
-        room_inner.width,
-        room_inner.height,
-        room_inner.tiles.clone(),
-        addrs.iter().map(|(_, &(_, id))| id).collect(),
-    )));
-    let (breaker_send, breaker_recv) = oneshot::channel();
-    room_inner.state = RoomState::Playing {
-        map,
-        addrs,
-        breaker: breaker_send,
+    let start = format!(
+        "{{\"state\":\"start\",\"id\":{},\"rules\":{}}}\n",
+        id, rules
+    );
+    let responses = async move {
+        let mut writer = writer;
+        let mut rx_from_map = rx_from_map;
+        writer.write_all(start.as_bytes()).await?;
+        writer.flush().await?;
+        while let Some(msg) = rx_from_map.recv().await {
+            writer.write_all(format!("{}\n", msg).as_bytes()).await?;
+            writer.flush().await?;
+        }
+        Ok::<_, Error>(writer)
     };
-    drop(room_inner);
 
-    let task = future::loop_fn((room, sockets), move |(room, sockets)| {
-        let room_inner = room.lock().unwrap();
-        if let RoomState::Playing { map, .. } = &room_inner.state {
-            let map = map.clone();
-            let timestep = room_inner.timestep;
-            drop(room_inner); // unlock the mutex now we have the map
-
-            // our serialize function will never fail
-            let map_inner = map.lock().unwrap();
-            let json = serde_json::to_string(&*map_inner).unwrap();
-            drop(map_inner); // unlock the mutex now we have the representation
-
-            let futs = sockets.into_iter().map(|(id, tx, rx)| {
-                do_client_step(id, tx, rx, map.clone(), json.clone(), timestep)
-            });
+    // the lines are kept outside the request loop, so the reader can be had back
+    // afterwards along with anything the client has already sent
+    let connection = async move {
+        let mut lines = reader.lines();
+        // the requests borrow the lines, so they have to be done with first
+        let writer = {
+            let requests = async {
+                // taken by the loop, so the game hears as soon as the client hangs up
+                let tx_to_map = tx_to_map;
+                let mut errors = 0;
+                while let Some(line) = lines.next_line().await? {
+                    tracing::debug!("Received: {}", line);
+                    let req = match parse_request(&line) {
+                        Ok(req) => req,
+                        Err(e) if errors < max_errors => {
+                            errors += 1;
+                            tracing::warn!(
+                                "Protocol error {}/{}: {}",
+                                errors,
+                                max_errors,
+                                e
+                            );
+                            Request::Forward
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    tx_to_map.send(req).map_err(to_broken_pipe)?;
+                }
+                Ok(())
+            };
 
-            stream::futures_unordered(futs)
-                .map(Some)
-                .or_else(|err| {
-                    // deal with errors by just ditching the socket
-                    println!("Error: {}", err);
-                    future::ok::<_, ()>(None)
-                })
-                .filter_map(|x| x)
-                .collect()
-                .and_then(move |sockets| do_server_step(room, map, sockets))
-        } else {
-            panic!("Error: room in weird state?");
+            tokio::pin!(requests, responses);
+            tokio::select! {
+                result = &mut requests => match result {
+                    // the client has hung up; just send the other half
+                    Ok(()) => responses.await.map(|_| None),
+                    // bad request; notify client and close connection
+                    Err(e) => {
+                        let mut writer = responses.await?;
+                        let msg = serde_json::json!({ "state": "error", "msg": e.to_string() });
+                        writer.write_all(format!("{}\n", msg).as_bytes()).await?;
+                        writer.flush().await?;
+                        Err(e)
+                    }
+                },
+                // we've finished sending responses; don't wait for more requests!
+                // (or we couldn't respond, in which case there's not much else to do)
+                result = &mut responses => result.map(Some),
+            }
+        }?;
+        Ok::<_, Error>(writer.map(|writer| (lines.into_inner(), writer)))
+    };
+
+    let span = tracing::info_span!("client", %addr, snake_id = id);
+    let keep = stay.clone();
+    tokio::spawn(
+        async move {
+            match connection.await {
+                Err(e) => {
+                    metrics::counter!("snake_arena_errors_total").increment(1);
+                    tracing::warn!("Connection closed with error: {}", e);
+                }
+                Ok(Some((reader, writer))) if keep.load(Ordering::Relaxed) => {
+                    match rejoin.and_then(|room| room.upgrade()) {
+                        Some(room) => {
+                            room.lock().unwrap().rejoin(addr, (name, reader, writer))
+                        }
+                        None => tracing::info!("Connection closed"),
+                    }
+                }
+                Ok(_) => tracing::info!("Connection closed"),
+            }
         }
-    });
+        .instrument(span),
+    );
 
-    // notify clients that the game is over
-    let task = task.and_then(|sockets| {
-        // send a "done" message to all sockets, ignoring errors
-        let futs = sockets.into_iter().map(|(_, tx, _)| {
-            tx.send("{\"state\":\"done\"}".into())
-                .and_then(|mut tx| tx.close())
-                .then(|_| Ok(()))
-        });
-        future::join_all(futs).map(|_| ())
-    });
+    Client {
+        id,
+        tx: tx_to_sock,
+        rx: rx_from_sock,
+        deltas: false,
+        quit: false,
+        stay,
+    }
+}
 
-    // cancel task if we get a message from the oneshot
-    let task = task.select(breaker_recv.map_err(|_| ()));
+/// Set up a snake to be played by the server.
+///
+/// The bot is driven like a client: it says it's ready straight away, and is asked
+/// for a move whenever it's sent a map to play on.
+fn setup_bot(id: SnakeID, mut bot: Box<dyn Bot>, map: Arc<Mutex<Map>>) -> Client {
+    let (tx_to_bot, mut rx_from_map) = mpsc::unbounded_channel::<String>();
+    let (tx_to_map, rx_from_bot) = mpsc::unbounded_channel::<Request>();
 
-    tokio::spawn(task.then(|_| {
-        println!("Room running task finished.");
-        Ok(())
-    }));
+    // it doesn't matter if the game's already over
+    let _ = tx_to_map.send(Request::Ready);
+    let play = async move {
+        while let Some(msg) = rx_from_map.recv().await {
+            if msg.starts_with("{\"state\":\"playing\"") {
+                let req = bot.decide(&map.lock().unwrap(), id);
+                if tx_to_map.send(req).is_err() {
+                    break;
+                }
+            }
+        }
+        tracing::info!("Bot finished");
+    };
+
+    let span = tracing::info_span!("bot", snake_id = id);
+    tokio::spawn(play.instrument(span));
+
+    Client {
+        id,
+        tx: tx_to_bot,
+        rx: rx_from_bot,
+        deltas: false,
+        quit: false,
+        stay: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+/// Take a client's snake out of the game because they asked to leave.
+///
+/// They're told it's done, and their connection closes once the client is dropped.
+fn quit(map: &Mutex<Map>, client: &mut Client) {
+    tracing::info!("Client quit the game");
+    map.lock()
+        .unwrap()
+        .delete_snake(client.id, DeathCause::Quit);
+    // they're leaving anyway, so it doesn't matter if they don't hear this
+    let _ = client.tx.send("{\"state\":\"quit\"}".into());
+    client.quit = true;
+    client.stay.store(false, Ordering::Relaxed);
+}
+
+/// Describe the standing of the given snake.
+fn status_json(map: &Map, id: SnakeID) -> String {
+    format!(
+        "{{\"state\":\"status\",\"score\":{},\"rank\":{},\"alive\":{}}}",
+        map.scores.get(&id).unwrap_or(&0),
+        map.rank(id).unwrap_or(0),
+        map.is_alive(id),
+    )
+}
 
-    true
+/// Answer a request that doesn't use up the client's turn.
+///
+/// Returns the reply, and whether the client now wants to be sent deltas.
+fn answer(map: &Mutex<Map>, id: SnakeID, req: Request, deltas: bool) -> (String, bool) {
+    match req {
+        Request::Deltas => ("{\"state\":\"deltas\"}".to_owned(), true),
+        Request::Ready => ("{\"state\":\"ready\"}".to_owned(), deltas),
+        _ => (status_json(&map.lock().unwrap(), id), deltas),
+    }
+}
+
+/// The countdown message, saying how long is left until `deadline`.
+fn countdown_message(deadline: time::Instant) -> String {
+    let remaining = deadline.saturating_duration_since(time::Instant::now());
+    format!(
+        "{{\"state\":\"countdown\",\"seconds\":{},\"remaining_ms\":{}}}",
+        remaining.as_secs_f64(),
+        remaining.as_millis()
+    )
+}
+
+/// Give the clients until `countdown` is up to say they're ready.
+///
+/// Until they do, they're sent how long is left every `COUNTDOWN_TICK`. Returns the
+/// clients that did; the rest are told they took too long, and their snakes are
+/// removed from the map. Clients that quit instead are dropped as well.
+async fn do_countdown(
+    clients: Vec<Client>,
+    map: &Arc<Mutex<Map>>,
+    countdown: Duration,
+) -> Vec<Client> {
+    let deadline = time::Instant::now() + countdown;
+
+    let waits = clients.into_iter().map(|mut client| async move {
+        let id = client.id;
+        let ready = async {
+            // the first tick is straight away
+            let mut ticks = time::interval(COUNTDOWN_TICK);
+
+            // answer any other requests until they're ready; moves are ignored
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {
+                        let msg = countdown_message(deadline);
+                        client.tx.send(msg).map_err(to_broken_pipe)?;
+                    }
+                    req = client.rx.recv() => match req {
+                        Some(Request::Ready) => break,
+                        Some(Request::Quit) => {
+                            quit(map, &mut client);
+                            return Ok(());
+                        }
+                        Some(req) if !req.is_move() => {
+                            let (json, deltas) = answer(map, id, req, client.deltas);
+                            client.deltas = deltas;
+                            client.tx.send(json).map_err(to_broken_pipe)?;
+                        }
+                        Some(_) => {}
+                        None => return Err(to_broken_pipe("no request received")),
+                    },
+                }
+            }
+            client
+                .tx
+                .send("{\"state\":\"ready\"}".into())
+                .map_err(to_broken_pipe)
+        };
+
+        match time::timeout_at(deadline, ready).await {
+            Ok(Ok(())) => Ok(client),
+            Ok(Err(e)) => {
+                map.lock().unwrap().delete_snake(id, DeathCause::Disconnect);
+                Err(e)
+            }
+            Err(_) => {
+                map.lock().unwrap().delete_snake(id, DeathCause::Timeout);
+                client.stay.store(false, Ordering::Relaxed);
+                let msg = "{\"state\":\"error\",\"msg\":\"not ready in time\"}";
+                let _ = client.tx.send(msg.into());
+                Err(Error::new(ErrorKind::TimedOut, "not ready in time"))
+            }
+        }
+    });
+
+    future::join_all(waits)
+        .await
+        .into_iter()
+        .filter_map(|result| {
+            result
+                .map_err(|err| {
+                    metrics::counter!("snake_arena_errors_total").increment(1);
+                    tracing::warn!("Error: {}", err)
+                })
+                .ok()
+        })
+        // those who quit have already been told, so they can go now
+        .filter(|client| !client.quit)
+        .collect()
+}
+
+/// What every client is told at the start of a turn.
+#[derive(Clone)]
+struct TurnInfo {
+    /// The current map.
+    map_json: Arc<String>,
+    /// How the map differs from the last one, if there was one.
+    delta_json: Option<Arc<String>>,
+    /// The number of the turn, counting from zero.
+    turn: usize,
+    /// How many snakes are still alive.
+    alive: usize,
+}
+
+/// Do one step of client interaction.
+///
+/// Clients that have asked for deltas are sent the `info`'s `delta_json` instead of
+/// the full `map_json`, if there is a previous map to compare against. Either way
+/// they're told which turn it is and how many snakes are alive. If the connection is
+/// lost and `hold` is set, the snake is left in the game for its player to reconnect
+/// to.
+async fn do_client_step(
+    mut client: Client,
+    map: Arc<Mutex<Map>>,
+    info: TurnInfo,
+    move_timeout: Option<Duration>,
+    hold: bool,
+) -> std::io::Result<Client> {
+    let id = client.id;
+    let frame = match info.delta_json {
+        Some(delta_json) if client.deltas => format!("\"delta\":{}", delta_json),
+        _ => format!("\"map\":{}", info.map_json),
+    };
+    let frame = format!("\"turn\":{},\"alive\":{},{}", info.turn, info.alive, frame);
+
+    // don't bother receiving anything if they're dead
+    let surroundings = map.lock().unwrap().surroundings(id);
+    let surroundings = match surroundings {
+        Some(surroundings) => serde_json::to_string(&surroundings).unwrap(),
+        None => {
+            let json = format!("{{\"state\":\"dead\",{}}}", frame);
+            client.tx.send(json).map_err(to_broken_pipe)?;
+            return Ok(client);
+        }
+    };
+
+    let json = format!(
+        "{{\"state\":\"playing\",{},\"surroundings\":{}}}",
+        frame, surroundings
+    );
+    let action = async {
+        let result = async {
+            client.tx.send(json).map_err(to_broken_pipe)?;
+
+            // answer any status requests until we get a real move
+            loop {
+                match client.rx.recv().await {
+                    Some(req) if !req.is_move() => {
+                        let (json, deltas) = answer(&map, id, req, client.deltas);
+                        client.deltas = deltas;
+                        client.tx.send(json).map_err(to_broken_pipe)?;
+                    }
+                    Some(req) => return Ok(req),
+                    None => return Err(to_broken_pipe("no request received")),
+                }
+            }
+        }
+        .await;
+
+        // on error, remove the associated snake from the map
+        if result.is_err() && !hold {
+            map.lock().unwrap().delete_snake(id, DeathCause::Disconnect);
+        }
+        result
+    };
+
+    // a move that's ready by the deadline still counts, even if it's only just in time
+    let req = if let Some(duration) = move_timeout {
+        match time::timeout(duration, action).await {
+            Ok(result) => result?,
+            Err(e) => {
+                map.lock().unwrap().delete_snake(id, DeathCause::Timeout);
+                client.stay.store(false, Ordering::Relaxed);
+                return Err(Error::new(ErrorKind::TimedOut, e.to_string()));
+            }
+        }
+    } else {
+        action.await?
+    };
+
+    match req {
+        Request::Forward | Request::Status | Request::Deltas | Request::Ready => {}
+        Request::Left => map.lock().unwrap().turn_left(id),
+        Request::Right => map.lock().unwrap().turn_right(id),
+        Request::Quit => quit(&map, &mut client),
+    }
+    Ok(client)
+}
+
+/// Keep the snake of a player who lost their connection in the game for the room's
+/// `reconnect_window`, removing it if they haven't come back by then.
+fn hold_for_reconnect(room: &Arc<Mutex<Room>>, map: &Arc<Mutex<Map>>, id: SnakeID) {
+    let mut room_inner = room.lock().unwrap();
+    let name = match &room_inner.state {
+        RoomState::Playing { addrs, .. } => addrs
+            .values()
+            .find(|&&(_, snake)| snake == id)
+            .map(|(name, _)| name.clone()),
+        _ => None,
+    };
+    // there's nothing to come back to for a snake that's already dead
+    let name = match name {
+        Some(name) if map.lock().unwrap().is_alive(id) => name,
+        _ => return,
+    };
+    tracing::info!("Holding snake {} for {} to reconnect", id, name);
+    let held = Instant::now();
+    room_inner
+        .pending_reconnects
+        .insert(id, (name.clone(), held));
+    let window = room_inner.reconnect_window;
+    drop(room_inner);
+
+    let room = room.clone();
+    let map = map.clone();
+    tokio::spawn(
+        async move {
+            time::sleep(window).await;
+            // always lock room before map
+            let mut room_inner = room.lock().unwrap();
+            let same_game = matches!(
+                &room_inner.state,
+                RoomState::Playing { map: current, .. } if Arc::ptr_eq(current, &map)
+            );
+            // the player may have come back and lost their connection again since, in
+            // which case the snake is being held by a later call
+            let still_held = matches!(
+                room_inner.pending_reconnects.get(&id),
+                Some(&(_, since)) if since == held
+            );
+            if same_game && still_held {
+                room_inner.pending_reconnects.remove(&id);
+                map.lock().unwrap().delete_snake(id, DeathCause::Disconnect);
+                tracing::info!("Gave up waiting for {} to reconnect", name);
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Put a player back in control of their snake, if they lost their connection to the
+/// game in progress and have come back under the same name within the room's
+/// `reconnect_window`.
+///
+/// They're sent `start` again, and play on from the next turn. If several snakes of
+/// players with that name are waiting, they get the one with the lowest ID. The socket
+/// is given back if there's no snake waiting for them.
+pub fn reconnect(
+    room: &Arc<Mutex<Room>>,
+    addr: SocketAddr,
+    socket: NamedSocket,
+) -> Result<(), NamedSocket> {
+    let mut room_inner = room.lock().unwrap();
+    if !matches!(room_inner.state, RoomState::Playing { .. }) {
+        return Err(socket);
+    }
+    let id = match room_inner
+        .pending_reconnects
+        .iter()
+        .find(|&(_, (name, _))| *name == socket.0)
+    {
+        Some((&id, _)) => id,
+        None => return Err(socket),
+    };
+    room_inner.pending_reconnects.remove(&id);
+
+    let rules = serde_json::to_string(&room_inner.rules()).unwrap();
+    let max_errors = room_inner.max_protocol_errors;
+    let rejoin = if room_inner.keep_players {
+        Some(Arc::downgrade(room))
+    } else {
+        None
+    };
+    if let RoomState::Playing {
+        addrs,
+        reconnects,
+        stays,
+        ..
+    } = &mut room_inner.state
+    {
+        tracing::info!(%addr, "{} reconnected to snake {}", socket.0, id);
+        addrs.retain(|_, &mut (_, snake)| snake != id);
+        addrs.insert(addr, (socket.0.clone(), id));
+        let client = setup_client(id, addr, socket, &rules, max_errors, rejoin);
+        stays.push(client.stay.clone());
+        // if the game has just finished, dropping the client closes the connection
+        let _ = reconnects.send(client);
+    }
+    Ok(())
+}
+
+/// Execute the server work once we have all our client work done
+///
+/// Returns whether the game has finished.
+fn do_server_step(room: &Mutex<Room>, map: &Arc<Mutex<Map>>) -> Result<bool, ()> {
+    // always lock room before map
+    let mut room_inner = room.lock().unwrap();
+    // the game may have been ended early while the clients were having their turn, in
+    // which case its last map has already been recorded
+    match &room_inner.state {
+        RoomState::Playing { map: current, .. } if Arc::ptr_eq(current, map) => {}
+        _ => return Ok(true),
+    }
+    let mut map_inner = map.lock().unwrap();
+    let (map, finished) = match map_inner.clone().step() {
+        Ok(map) => {
+            let turn = map.turn();
+            let finished = room_inner.max_turns.is_some_and(|max| turn >= max);
+            if finished {
+                tracing::info!("Reached maximum turn count; ending game.");
+            }
+            (map, finished)
+        }
+        Err(map) => (*map, true),
+    };
+    let map = std::mem::replace(&mut *map_inner, map);
+    room_inner.record(map);
+    metrics::counter!("snake_arena_steps_total", "room_id" => room_inner.id.to_string())
+        .increment(1);
+
+    // only bother serializing the map if someone is watching
+    if room_inner.events.receiver_count() > 0 {
+        let json = serde_json::to_string(&*map_inner).unwrap();
+        let _ = room_inner.events.send(RoomEvent::Step(Arc::new(json)));
+    }
+
+    if !finished {
+        return Ok(false);
+    }
+    if room_inner.finish(&mut map_inner).is_some() {
+        Ok(true)
+    } else {
+        metrics::counter!("snake_arena_errors_total").increment(1);
+        tracing::error!("room in weird state?");
+        Err(())
+    }
+}
+
+/// Let a connection watch the games played in a room without playing in them.
+///
+/// The spectator is sent a `{"state":"spectating","map":...}` message for every step,
+/// starting with the current map if a game is in progress, and a
+/// `{"state":"done","scores":{...}}` message whenever a game ends. It keeps watching
+/// until it disconnects or the room is deleted. A spectator that falls behind skips
+/// straight to the latest maps, so it never holds up the game.
+pub fn spectate(room: &Mutex<Room>, addr: SocketAddr, reader: Reader, writer: Writer) {
+    // take the current map and subscribe together, so no step is missed in between
+    let (current, mut rx) = {
+        let room_inner = room.lock().unwrap();
+        let current = match &room_inner.state {
+            RoomState::Playing { map, .. } => {
+                Some(serde_json::to_string(&*map.lock().unwrap()).unwrap())
+            }
+            _ => None,
+        };
+        (current, room_inner.events())
+    };
+
+    let send = async move {
+        let mut writer = writer;
+        if let Some(json) = current {
+            let msg = format!("{{\"state\":\"spectating\",\"map\":{}}}\n", json);
+            writer.write_all(msg.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        loop {
+            let msg = match rx.recv().await {
+                Ok(RoomEvent::Step(json)) => {
+                    format!("{{\"state\":\"spectating\",\"map\":{}}}\n", json)
+                }
+                Ok(RoomEvent::Done(scores)) => {
+                    format!("{{\"state\":\"done\",\"scores\":{}}}\n", scores)
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+            writer.write_all(msg.as_bytes()).await?;
+            writer.flush().await?;
+        }
+    };
+
+    // spectators have nothing to say, but we need to notice when they hang up
+    let receive = async move {
+        let mut lines = reader.lines();
+        while lines.next_line().await?.is_some() {}
+        Ok::<_, Error>(())
+    };
+
+    let span = tracing::info_span!("spectator", %addr);
+    tokio::spawn(
+        async move {
+            let result = tokio::select! {
+                result = send => result,
+                result = receive => result,
+            };
+            if let Err(e) = result {
+                tracing::info!("Spectator left with error: {}", e);
+            } else {
+                tracing::info!("Spectator left");
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Shut things off and start playing
+///
+/// Fails if the room isn't ready to start, or if its map can't fit every player.
+pub fn run(room: Arc<Mutex<Room>>) -> std::io::Result<()> {
+    let mut room_inner = room.lock().unwrap();
+
+    // make sure the room is in a good state
+    let good = match &room_inner.state {
+        RoomState::Waiting => !room_inner.players.is_empty() || room_inner.bots > 0,
+        _ => false,
+    };
+    if !good {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "room is already in progress or has no players",
+        ));
+    }
+
+    // the layout may have been changed since the room was made
+    game::validate(room_inner.width, room_inner.height, &room_inner.tiles)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    // build the map before touching the players, so they stay put if it fails;
+    // snake IDs are handed out in order below
+    let options = MapOptions {
+        starts: None,
+        spawn: room_inner.spawn,
+        wrap: room_inner.wrap,
+        doodah_count: room_inner.doodah_count,
+        initial_length: room_inner.initial_length,
+        seed: room_inner.seed,
+    };
+    let mut map = Map::new(
+        room_inner.width,
+        room_inner.height,
+        room_inner.tiles.clone(),
+        (0..room_inner.players.len() + room_inner.bots).collect(),
+        options,
+    )
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    map.turn_penalty = room_inner.turn_penalty;
+    map.scoring = room_inner.scoring;
+    map.set_doodah_weights(room_inner.doodah_weights.clone());
+    map.shrink_after = room_inner.shrink_after;
+    map.shrink_interval = room_inner.shrink_interval;
+    metrics::counter!("snake_arena_games_started_total").increment(1);
+
+    // clients' connections are logged as part of the room
+    let span = tracing::info_span!("room", room_id = room_inner.id);
+    let _entered = span.enter();
+
+    // let the players know we've started by providing them their ID
+    // this also clears the player list
+    let max_errors = room_inner.max_protocol_errors;
+    let rules = serde_json::to_string(&room_inner.rules()).unwrap();
+    let rejoin = if room_inner.keep_players {
+        Some(Arc::downgrade(&room))
+    } else {
+        None
+    };
+    let (mut addrs, mut clients): (HashMap<_, _>, Vec<_>) = room_inner
+        .players
+        .drain()
+        .enumerate()
+        .map(|(id, (addr, socket))| {
+            let name = socket.0.clone();
+            let client =
+                setup_client(id, addr, socket, &rules, max_errors, rejoin.clone());
+            ((addr, (name, id)), client)
+        })
+        .unzip();
+
+    // the bots' snakes come after the players'
+    let map = Arc::new(Mutex::new(map));
+    let bots: HashSet<_> = (clients.len()..clients.len() + room_inner.bots).collect();
+    for &id in &bots {
+        let addr = SocketAddr::from(([0, 0, 0, 0], id as u16));
+        addrs.insert(addr, (format!("bot {}", id), id));
+        clients.push(setup_bot(id, Box::new(Greedy), map.clone()));
+    }
+
+    // kept for telling the players the game is over if it gets cancelled
+    let senders: Vec<_> = clients.iter().map(|client| client.tx.clone()).collect();
+    let stays = clients.iter().map(|client| client.stay.clone()).collect();
+
+    // update the room state; we can drop the lock when we're done here
+    let countdown = room_inner.countdown;
+    let client_timeout = room_inner.client_timeout;
+    let hold = room_inner.reconnect_window > Duration::from_secs(0);
+    let (breaker_send, breaker_recv) = oneshot::channel();
+    let (pause_send, mut pause_recv) = watch::channel(false);
+    let (reconnect_send, mut reconnect_recv) = mpsc::unbounded_channel();
+    room_inner.pending_reconnects.clear();
+    room_inner.state = RoomState::Playing {
+        map,
+        addrs,
+        bots,
+        breaker: breaker_send,
+        paused: pause_send,
+        reconnects: reconnect_send,
+        stays,
+    };
+    drop(room_inner);
+
+    let task = async move {
+        let mut clients = clients;
+        if countdown > Duration::from_secs(0) {
+            let map = match &room.lock().unwrap().state {
+                RoomState::Playing { map, .. } => map.clone(),
+                _ => panic!("Error: room in weird state?"),
+            };
+            clients = do_countdown(clients, &map, countdown).await;
+        }
+
+        let mut dead = Vec::new();
+        loop {
+            // hold off on the next step while the game is paused; the sender only goes
+            // away once the room has moved on, in which case the breaker stops us anyway
+            while *pause_recv.borrow() {
+                if pause_recv.changed().await.is_err() {
+                    return;
+                }
+            }
+
+            // players who've come back take their snakes again from this turn
+            while let Ok(client) = reconnect_recv.try_recv() {
+                clients.push(client);
+            }
+
+            let (map, timestep, move_timeout, info) = {
+                let room_inner = room.lock().unwrap();
+                let map = match &room_inner.state {
+                    RoomState::Playing { map, .. } => map.clone(),
+                    _ => panic!("Error: room in weird state?"),
+                };
+
+                // our serialize function will never fail
+                let map_inner = map.lock().unwrap();
+                let json = serde_json::to_string(&*map_inner).unwrap();
+                // the last map in the history is the one clients were sent last step
+                let delta_json = room_inner
+                    .history
+                    .last()
+                    .map(|old| serde_json::to_string(&map_inner.diff(old)).unwrap());
+                let alive = map_inner.alive_count();
+                // the number of the turn clients are about to play, counting from zero
+                let turn = map_inner.turn();
+                drop(map_inner);
+
+                let info = TurnInfo {
+                    map_json: Arc::new(json),
+                    delta_json: delta_json.map(Arc::new),
+                    turn,
+                    alive,
+                };
+                (
+                    map.clone(),
+                    room_inner.timestep,
+                    room_inner.move_deadline(),
+                    info,
+                )
+            };
+
+            let steps = clients.into_iter().map(|client| {
+                let id = client.id;
+                let stay = client.stay.clone();
+                let step =
+                    do_client_step(client, map.clone(), info.clone(), move_timeout, hold);
+                // giving up on the step drops the client, which closes its connection
+                let map = map.clone();
+                let room = room.clone();
+                async move {
+                    let result = time::timeout(client_timeout, step)
+                        .await
+                        .unwrap_or_else(|_| {
+                            map.lock().unwrap().delete_snake(id, DeathCause::Timeout);
+                            stay.store(false, Ordering::Relaxed);
+                            Err(Error::new(
+                                ErrorKind::TimedOut,
+                                "client stopped responding",
+                            ))
+                        });
+                    match &result {
+                        Err(e) if hold && e.kind() == ErrorKind::BrokenPipe => {
+                            hold_for_reconnect(&room, &map, id)
+                        }
+                        _ => {}
+                    }
+                    result
+                }
+            });
+
+            // deal with errors by just ditching the socket
+            let started = time::Instant::now();
+            clients = future::join_all(steps)
+                .await
+                .into_iter()
+                .filter_map(|result| {
+                    result
+                        .map_err(|err| {
+                            metrics::counter!("snake_arena_errors_total").increment(1);
+                            tracing::warn!("Error: {}", err)
+                        })
+                        .ok()
+                })
+                .collect::<Vec<_>>();
+
+            // clients whose snakes have died have just been told so; they only need to
+            // hear about the end of the game
+            let (alive, died) = {
+                let map = map.lock().unwrap();
+                clients
+                    .into_iter()
+                    .partition(|client| map.is_alive(client.id))
+            };
+            clients = alive;
+            // those who quit have already been told, so they can go now
+            dead.extend(died.into_iter().filter(|client: &Client| !client.quit));
+
+            // keep to the timestep even when everyone has answered early
+            if let Some(timestep) = timestep {
+                time::sleep_until(started + timestep).await;
+            }
+
+            match do_server_step(&room, &map) {
+                Ok(false) => {}
+                Ok(true) => break,
+                Err(()) => return,
+            }
+        }
+
+        // notify clients that the game is over; dropping them closes the connection
+        for client in clients.into_iter().chain(dead) {
+            let _ = client.tx.send("{\"state\":\"done\"}".into());
+        }
+    };
+
+    tokio::spawn(
+        async move {
+            // cancel task if we get a message from the oneshot, letting the players know
+            // the game is over before their connections are closed; that's checked
+            // first, so the task never runs on after the room has moved on
+            tokio::select! {
+                biased;
+                _ = breaker_recv => {
+                    for tx in senders {
+                        let _ = tx.send("{\"state\":\"done\"}".into());
+                    }
+                }
+                _ = task => {}
+            }
+            tracing::info!("Room running task finished.");
+        }
+        .instrument(span.clone()),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
+
+    /// A room with the given map, and otherwise the default settings.
+    fn test_room(width: usize, height: usize, tiles: Vec<Tile>) -> Room {
+        Room::builder()
+            .width(width)
+            .height(height)
+            .tiles(tiles)
+            .name("test")
+            .build()
+            .unwrap()
+    }
+
+    /// A room with an open `width` by `height` map and no doodahs.
+    fn open_room(width: usize, height: usize) -> Arc<Mutex<Room>> {
+        let tiles = vec![Tile::Blank; width * height];
+        Arc::new(Mutex::new(test_room(width, height, tiles)))
+    }
+
+    /// A made-up address for the player with the given number.
+    fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], n))
+    }
+
+    /// Connect a player called `name`, giving the room's end of the connection and the
+    /// player's.
+    fn connect(name: &str) -> (NamedSocket, DuplexStream) {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let (reader, writer) = tokio::io::split(Box::new(ours) as Box<dyn Stream>);
+        ((name.to_owned(), BufReader::new(reader), writer), theirs)
+    }
+
+    /// Put players with the given names in a waiting room, returning their ends of the
+    /// connections.
+    fn join(room: &Mutex<Room>, names: &[&str]) -> Vec<DuplexStream> {
+        let mut room = room.lock().unwrap();
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let (socket, client) = connect(name);
+                room.players.insert(addr(i as u16 + 1), socket);
+                client
+            })
+            .collect()
+    }
+
+    /// The names of the players waiting in a room, in order.
+    fn waiting(room: &Mutex<Room>) -> Vec<String> {
+        let mut names: Vec<_> = room
+            .lock()
+            .unwrap()
+            .players
+            .values()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Wait until `done` is true, failing the test if it takes too long.
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("took too long");
+    }
+
+    #[tokio::test]
+    async fn restarted_room_keeps_waiting_players() {
+        let room = open_room(5, 5);
+        let _clients = join(&room, &["a", "b"]);
+
+        room.lock().unwrap().restart().unwrap();
+
+        assert!(matches!(room.lock().unwrap().state, RoomState::Waiting));
+        assert_eq!(waiting(&room), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn restarted_game_keeps_its_players() {
+        let room = open_room(5, 5);
+        room.lock().unwrap().keep_players = true;
+        let _clients = join(&room, &["a", "b"]);
+        run(room.clone()).unwrap();
+        assert!(waiting(&room).is_empty());
+
+        room.lock().unwrap().restart().unwrap();
+
+        eventually(|| room.lock().unwrap().players.len() == 2).await;
+        assert_eq!(waiting(&room), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn reset_game_drops_its_players() {
+        let room = open_room(5, 5);
+        room.lock().unwrap().keep_players = true;
+        let clients = join(&room, &["a", "b"]);
+        run(room.clone()).unwrap();
+
+        room.lock().unwrap().reset().unwrap();
+
+        // the connections are closed rather than going back to the room
+        for mut client in clients {
+            let mut received = String::new();
+            time::timeout(Duration::from_secs(5), client.read_to_string(&mut received))
+                .await
+                .expect("connection wasn't closed")
+                .unwrap();
+            assert!(received.ends_with("{\"state\":\"done\"}\n"));
+        }
+        assert!(waiting(&room).is_empty());
+    }
+
+    /// Read the next line a player is sent.
+    async fn next_line(client: &mut DuplexStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while client.read_exact(&mut byte).await.is_ok() && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    /// A room where disconnected players have `window` to come back, with a game that
+    /// goes on regardless.
+    fn reconnect_room(window: Duration) -> Arc<Mutex<Room>> {
+        let room = open_room(7, 7);
+        {
+            let mut room = room.lock().unwrap();
+            room.reconnect_window = window;
+            room.timestep = Some(Duration::from_millis(5));
+            room.seed = Some(1);
+        }
+        room
+    }
+
+    #[tokio::test]
+    async fn player_reconnects_within_window() {
+        let room = reconnect_room(Duration::from_secs(60));
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        assert!(next_line(&mut clients[0])
+            .await
+            .starts_with("{\"state\":\"start\""));
+
+        drop(clients);
+        eventually(|| room.lock().unwrap().pending_reconnects.contains_key(&0)).await;
+
+        let (socket, _) = connect("b");
+        assert!(reconnect(&room, addr(8), socket).is_err());
+        let (socket, mut client) = connect("a");
+        assert!(reconnect(&room, addr(9), socket).is_ok());
+        assert!(next_line(&mut client)
+            .await
+            .starts_with("{\"state\":\"start\",\"id\":0,"));
+        assert!(next_line(&mut client)
+            .await
+            .starts_with("{\"state\":\"playing\""));
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Playing { players, .. } => {
+                assert_eq!(players.len(), 1);
+                assert_eq!(players[&addr(9)], ("a".to_owned(), 0));
+            }
+            state => panic!("game isn't playing: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnecting_twice_gets_the_full_window_again() {
+        let window = Duration::from_millis(400);
+        let room = reconnect_room(window);
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        assert!(next_line(&mut clients[0])
+            .await
+            .starts_with("{\"state\":\"start\""));
+
+        drop(clients);
+        eventually(|| room.lock().unwrap().pending_reconnects.contains_key(&0)).await;
+        let first_held = time::Instant::now();
+
+        let (socket, mut client) = connect("a");
+        assert!(reconnect(&room, addr(9), socket).is_ok());
+        assert!(next_line(&mut client)
+            .await
+            .starts_with("{\"state\":\"start\""));
+        // play on for a while before losing the connection again
+        let back = time::Instant::now();
+        while back.elapsed() < window / 2 {
+            assert!(next_line(&mut client)
+                .await
+                .starts_with("{\"state\":\"playing\""));
+            client.write_all(b"forward\n").await.unwrap();
+        }
+        drop(client);
+        eventually(|| room.lock().unwrap().pending_reconnects.contains_key(&0)).await;
+
+        // the first hold has run out, but not the second
+        time::sleep_until(first_held + window * 5 / 4).await;
+        let room = room.lock().unwrap();
+        assert!(room.pending_reconnects.contains_key(&0));
+        match &room.state {
+            RoomState::Playing { map, .. } => assert!(map.lock().unwrap().is_alive(0)),
+            state => panic!("game isn't playing: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn players_with_same_name_time_out_separately() {
+        let room = reconnect_room(Duration::from_millis(50));
+        let clients = join(&room, &["a", "a"]);
+        run(room.clone()).unwrap();
+
+        drop(clients);
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Finished { causes, .. } => {
+                assert_eq!(causes.len(), 2);
+                assert!(causes.values().all(|&c| c == DeathCause::Disconnect));
+            }
+            state => panic!("game isn't finished: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn bots_take_player_spaces() {
+        let room = open_room(2, 2);
+        let mut room = room.lock().unwrap();
+        assert_eq!(room.max_players(), 4);
+
+        room.bots = 1;
+        assert_eq!(room.max_players(), 3);
+        room.free_space_per_snake = Some(2);
+        assert_eq!(room.max_players(), 1);
+        room.bots = 3;
+        assert_eq!(room.max_players(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_room_with_bots_starts_and_finishes() {
+        let room = open_room(3, 1);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 2;
+            room.start_when_full = true;
+            room.max_turns = Some(3);
+        }
+        let clients = join(&room, &["a"]);
+        assert!(room.lock().unwrap().should_auto_start());
+        run(room.clone()).unwrap();
+        // the bots carry on without the player
+        drop(clients);
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn bots_only_room_finishes() {
+        let room = open_room(5, 5);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 2;
+            room.max_turns = Some(5);
+        }
+        run(room.clone()).unwrap();
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Finished { scores, .. } => assert_eq!(scores.len(), 2),
+            state => panic!("game isn't finished: {:?}", state),
+        }
+        // bots don't get a place on the leaderboard
+        assert!(room
+            .lock()
+            .unwrap()
+            .leaderboard
+            .lock()
+            .unwrap()
+            .standings()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn game_ends_after_max_turns_with_survivor() {
+        // a lone snake with nothing to eat can go round an open map forever
+        let room = open_room(5, 5);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 1;
+            room.max_turns = Some(4);
+        }
+        run(room.clone()).unwrap();
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+
+        let room = room.lock().unwrap();
+        assert_eq!(room.history_len(), 5);
+        let last = room.history.last().unwrap();
+        assert_eq!(last.turn(), 4);
+        assert!(last.is_alive(0));
+        assert_eq!(last.causes[&0], DeathCause::Survived);
+        match &room.state {
+            RoomState::Finished { scores, .. } => assert_eq!(scores.len(), 1),
+            state => panic!("game isn't finished: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn turn_counts_up_each_step() {
+        let room = open_room(5, 5);
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+        assert!(next_line(client).await.starts_with("{\"state\":\"start\""));
+
+        for turn in 0..4 {
+            let playing = format!("{{\"state\":\"playing\",\"turn\":{},", turn);
+            assert!(next_line(client).await.starts_with(&playing));
+            client.write_all(b"forward\n").await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_start_waits_for_enough_players() {
+        let room = open_room(5, 5);
+        room.lock().unwrap().auto_start = Some(2);
+        let _first = join(&room, &["a"]);
+        assert!(!room.lock().unwrap().should_auto_start());
+
+        let (socket, _second) = connect("b");
+        room.lock().unwrap().players.insert(addr(9), socket);
+        assert!(room.lock().unwrap().should_auto_start());
+
+        room.lock().unwrap().auto_start = None;
+        assert!(!room.lock().unwrap().should_auto_start());
+    }
+
+    #[tokio::test]
+    async fn five_by_five_room_caps_players_by_space() {
+        let (width, height, tiles) =
+            Map::from_ascii("#####\n#...#\n#...#\n#...#\n#####").unwrap();
+        let mut room = test_room(width, height, tiles);
+        assert_eq!(room.max_players(), 9);
+        room.free_space_per_snake = Some(4);
+        assert_eq!(room.max_players(), 2);
+        let room = Mutex::new(room);
+
+        let waiting = WaitingList::new();
+        let mut clients = Vec::new();
+        for n in 1..=3 {
+            let ((name, reader, writer), client) = connect("a");
+            waiting.insert(addr(n), name, reader, writer).await;
+            clients.push(client);
+        }
+        waiting.subscribe(&addr(1), &room).await.unwrap();
+        waiting.subscribe(&addr(2), &room).await.unwrap();
+        let err = waiting.subscribe(&addr(3), &room).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(waiting.contains(&addr(3)).await);
+        assert!(room.lock().unwrap().is_full());
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Waiting { max_players, .. } => assert_eq!(max_players, 2),
+            state => panic!("room isn't waiting: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn deltas_rebuild_the_maps_sent() {
+        let room = open_room(6, 6);
+        {
+            let mut room = room.lock().unwrap();
+            room.doodah_count = 3;
+            room.seed = Some(2);
+        }
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+        next_line(client).await;
+
+        let parse =
+            |line: String| serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        let first = parse(next_line(client).await);
+        let mut map: Map = serde_json::from_value(first["map"].clone()).unwrap();
+        client.write_all(b"deltas\nforward\n").await.unwrap();
+        assert_eq!(parse(next_line(client).await)["state"], "deltas");
+
+        let mut maps = vec![map.clone()];
+        for _ in 0..5 {
+            let frame = parse(next_line(client).await);
+            assert!(frame.get("map").is_none());
+            let changes: Vec<game::TileChange> =
+                serde_json::from_value(frame["delta"]["changes"].clone()).unwrap();
+            map.apply_changes(&changes);
+            maps.push(map.clone());
+            client.write_all(b"forward\n").await.unwrap();
+        }
+
+        // the last map sent goes in the history with the next step
+        eventually(|| room.lock().unwrap().history_len() == maps.len()).await;
+        let room = room.lock().unwrap();
+        for (step, map) in maps.iter().enumerate() {
+            assert_eq!(
+                map.tiles,
+                room.history_at(step).unwrap().tiles,
+                "step {}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn plain_commands_parse_in_any_case() {
+        assert_eq!(parse_request("Forward").unwrap(), Request::Forward);
+        assert_eq!(parse_request("Left").unwrap(), Request::Left);
+        assert_eq!(parse_request("right").unwrap(), Request::Right);
+        assert_eq!(parse_request("STATUS").unwrap(), Request::Status);
+        assert_eq!(parse_request("deltas").unwrap(), Request::Deltas);
+        assert_eq!(parse_request("Ready").unwrap(), Request::Ready);
+        assert_eq!(parse_request("quit").unwrap(), Request::Quit);
+    }
+
+    #[test]
+    fn json_commands_parse() {
+        assert_eq!(
+            parse_request(r#"{"action":"left"}"#).unwrap(),
+            Request::Left
+        );
+        assert_eq!(
+            parse_request(r#"  { "action": "Forward" }"#).unwrap(),
+            Request::Forward
+        );
+        assert_eq!(
+            parse_request(r#"{"action":"right","extra":1}"#).unwrap(),
+            Request::Right
+        );
+    }
+
+    #[test]
+    fn bad_lines_are_invalid_input() {
+        for line in &[
+            "",
+            "backwards",
+            "left please",
+            r#"{"action":"jump"}"#,
+            r#"{"action":3}"#,
+            r#"{"move":"left"}"#,
+            "{not json",
+        ] {
+            let err = parse_request(line).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", line);
+        }
+    }
+
+    /// Play a game with one player called `a`, who does nothing but what `client` is
+    /// given to do, and give how their snake ended up.
+    async fn lone_player_fate(
+        move_timeout: Duration,
+        client: impl FnOnce(DuplexStream) -> Option<DuplexStream>,
+    ) -> DeathCause {
+        let room = open_room(7, 7);
+        room.lock().unwrap().move_timeout = Some(move_timeout);
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let _client = client(clients.remove(0));
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Finished { causes, .. } => causes[&addr(1)],
+            state => panic!("game isn't finished: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_player_times_out() {
+        let fate = lone_player_fate(Duration::from_millis(50), Some).await;
+        assert_eq!(fate, DeathCause::Timeout);
+    }
+
+    #[tokio::test]
+    async fn dropped_player_disconnects() {
+        let fate = lone_player_fate(Duration::from_secs(60), |_| None).await;
+        assert_eq!(fate, DeathCause::Disconnect);
+    }
+
+    /// A connection that takes writes as `write` says, and never has anything to read.
+    #[derive(Debug)]
+    struct MockStream {
+        write: fn() -> Poll<io::Result<usize>>,
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            _: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            (self.write)()
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A connection whose writes fail, as if the client had gone away.
+    fn broken(name: &str) -> NamedSocket {
+        let stream = MockStream {
+            write: || Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+        };
+        let (reader, writer) = tokio::io::split(Box::new(stream) as Box<dyn Stream>);
+        (name.to_owned(), BufReader::new(reader), writer)
+    }
+
+    /// A connection that can't take anything right now, as if its buffer were full.
+    fn blocked(name: &str) -> NamedSocket {
+        let stream = MockStream {
+            write: || Poll::Pending,
+        };
+        let (reader, writer) = tokio::io::split(Box::new(stream) as Box<dyn Stream>);
+        (name.to_owned(), BufReader::new(reader), writer)
+    }
+
+    #[tokio::test]
+    async fn ping_tells_dead_connections_apart() {
+        let ((_, _, mut writer), mut client) = connect("a");
+        assert!(ping(&mut writer));
+        assert_eq!(next_line(&mut client).await, "{\"state\":\"ping\"}");
+
+        let (_, _, mut writer) = broken("b");
+        assert!(!ping(&mut writer));
+
+        // a client that's only slow gets another chance
+        let (_, _, mut writer) = blocked("c");
+        assert!(ping(&mut writer));
+    }
+
+    #[tokio::test]
+    async fn failed_pings_drop_waiting_connections() {
+        let waiting = WaitingList::new();
+        let (a, mut client) = connect("a");
+        for (n, (name, reader, writer)) in
+            vec![a, broken("b"), blocked("c")].into_iter().enumerate()
+        {
+            waiting
+                .insert(addr(n as u16 + 1), name, reader, writer)
+                .await;
+        }
+
+        assert_eq!(waiting.ping().await, 1);
+        assert!(waiting.contains(&addr(1)).await);
+        assert!(!waiting.contains(&addr(2)).await);
+        assert!(waiting.contains(&addr(3)).await);
+        assert_eq!(next_line(&mut client).await, "{\"state\":\"ping\"}");
+    }
+
+    #[tokio::test]
+    async fn failed_pings_drop_players_waiting_in_a_room() {
+        let room = open_room(5, 5);
+        let _clients = join(&room, &["a"]);
+        room.lock().unwrap().players.insert(addr(2), broken("b"));
+
+        assert_eq!(room.lock().unwrap().ping(), 1);
+        assert_eq!(waiting(&room), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn dead_players_are_told_once() {
+        // a player who only goes forward soon hits the wall, but the bot plays on
+        let (width, height, tiles) = Map::from_ascii(
+            "#######\n#.....#\n#.....#\n#.....#\n#.....#\n#.....#\n#######",
+        )
+        .unwrap();
+        let room = Arc::new(Mutex::new(test_room(width, height, tiles)));
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 1;
+            room.max_turns = Some(20);
+            room.seed = Some(3);
+        }
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+
+        let mut lines = Vec::new();
+        loop {
+            let line = next_line(client).await;
+            if line.starts_with("{\"state\":\"playing\"") {
+                client.write_all(b"forward\n").await.unwrap();
+            }
+            if line.is_empty() || line.starts_with("{\"state\":\"done\"") {
+                break;
+            }
+            lines.push(line);
+        }
+        let dead = lines
+            .iter()
+            .filter(|line| line.starts_with("{\"state\":\"dead\""))
+            .count();
+        assert_eq!(dead, 1);
+        assert!(lines.last().unwrap().starts_with("{\"state\":\"dead\""));
+
+        // the game went on for a while after
+        let playing = lines.len() - 2;
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+        assert!(room.lock().unwrap().history_len() > playing + 5);
+    }
+
+    /// A waiting list with `count` players in it, along with their ends of the
+    /// connections.
+    async fn waiting_list(count: u16) -> (WaitingList, Vec<DuplexStream>) {
+        let waiting = WaitingList::new();
+        let mut clients = Vec::new();
+        for n in 1..=count {
+            let ((name, reader, writer), client) = connect(&n.to_string());
+            waiting.insert(addr(n), name, reader, writer).await;
+            clients.push(client);
+        }
+        (waiting, clients)
+    }
+
+    #[tokio::test]
+    async fn full_room_refuses_subscribers() {
+        // a limit above the room's space is no use, as every snake needs a blank tile
+        let room = open_room(2, 1);
+        room.lock().unwrap().player_limit = Some(10);
+        assert_eq!(room.lock().unwrap().max_players(), 2);
+
+        let (list, _clients) = waiting_list(3).await;
+        list.subscribe(&addr(1), &room).await.unwrap();
+        list.subscribe(&addr(2), &room).await.unwrap();
+        assert!(room.lock().unwrap().is_full());
+
+        let err = list.subscribe(&addr(3), &room).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(list.contains(&addr(3)).await);
+        assert_eq!(waiting(&room), ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn full_room_refuses_everyone_at_once() {
+        let room = open_room(2, 1);
+        let (list, _clients) = waiting_list(3).await;
+        let err = list.subscribe_all(&room).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(list.len().await, 3);
+        assert!(waiting(&room).is_empty());
+
+        let (list, _clients) = waiting_list(2).await;
+        list.subscribe_all(&room).await.unwrap();
+        assert!(list.is_empty().await);
+        assert_eq!(waiting(&room), ["1", "2"]);
+    }
+
+    /// Play a few turns in a room with a fast timestep but a slower `move_timeout`, with
+    /// a player who takes `delay` over every move. Gives how many turns they were asked
+    /// for a move, along with the room.
+    async fn play_slowly(
+        move_timeout: Duration,
+        delay: Duration,
+    ) -> (usize, Arc<Mutex<Room>>) {
+        let room = open_room(7, 7);
+        {
+            let mut room = room.lock().unwrap();
+            room.timestep = Some(Duration::from_millis(10));
+            room.move_timeout = Some(move_timeout);
+        }
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+
+        let mut turns = 0;
+        while turns < 3 {
+            let line = next_line(client).await;
+            if line.starts_with("{\"state\":\"playing\"") {
+                turns += 1;
+                time::sleep(delay).await;
+                // a player who's too late has already been dropped
+                if client.write_all(b"forward\n").await.is_err() {
+                    break;
+                }
+            } else if !line.starts_with("{\"state\":\"start\"") {
+                break;
+            }
+        }
+        (turns, room)
+    }
+
+    #[tokio::test]
+    async fn moves_just_inside_the_timeout_count() {
+        let timeout = Duration::from_millis(400);
+        let (turns, room) = play_slowly(timeout, Duration::from_millis(250)).await;
+        assert_eq!(turns, 3);
+        assert!(matches!(
+            room.lock().unwrap().state,
+            RoomState::Playing { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn moves_past_the_timeout_are_too_late() {
+        let timeout = Duration::from_millis(400);
+        let (turns, room) = play_slowly(timeout, Duration::from_millis(600)).await;
+        assert_eq!(turns, 1);
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+        let state = room.lock().unwrap().get_state();
+        match state {
+            State::Finished { causes, .. } => {
+                assert_eq!(causes[&addr(1)], DeathCause::Timeout)
+            }
+            state => panic!("game isn't finished: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn json_actions_parse_in_any_case() {
+        assert_eq!(
+            parse_request(r#"{"action":"Left"}"#).unwrap(),
+            Request::Left
+        );
+        assert_eq!(
+            parse_request(r#"{"action":"RIGHT"}"#).unwrap(),
+            Request::Right
+        );
+        assert!(parse_request("lfet").is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_in_any_case_are_not_protocol_errors() {
+        let room = open_room(7, 7);
+        room.lock().unwrap().max_protocol_errors = 0;
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+        assert!(next_line(client).await.starts_with("{\"state\":\"start\""));
+
+        for command in &[
+            "LEFT",
+            "{\"action\":\"Right\"}",
+            "  {\"action\":\"FORWARD\"}",
+        ] {
+            assert!(next_line(client)
+                .await
+                .starts_with("{\"state\":\"playing\""));
+            client
+                .write_all(format!("{}\n", command).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // anything else still is
+        assert!(next_line(client)
+            .await
+            .starts_with("{\"state\":\"playing\""));
+        client.write_all(b"lfet\n").await.unwrap();
+        let reply: serde_json::Value =
+            serde_json::from_str(&next_line(client).await).unwrap();
+        assert_eq!(reply["state"], "error");
+    }
+
+    #[tokio::test]
+    async fn bad_json_requests_get_a_json_error() {
+        let room = open_room(7, 7);
+        room.lock().unwrap().max_protocol_errors = 0;
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+        assert!(next_line(client).await.starts_with("{\"state\":\"start\""));
+        assert!(next_line(client)
+            .await
+            .starts_with("{\"state\":\"playing\""));
+
+        client.write_all(b"{\"action\":\"jump\"}\n").await.unwrap();
+        let reply: serde_json::Value =
+            serde_json::from_str(&next_line(client).await).unwrap();
+        assert_eq!(reply["state"], "error");
+        assert!(reply["msg"]
+            .as_str()
+            .unwrap()
+            .contains("{\"action\":\"jump\"}"));
+    }
+
+    #[tokio::test]
+    async fn hung_client_is_dropped_and_the_game_goes_on() {
+        // without a timestep or move timeout, only the client timeout can end a turn
+        let room = open_room(7, 7);
+        {
+            let mut room = room.lock().unwrap();
+            room.client_timeout = Duration::from_millis(100);
+            room.bots = 1;
+            room.max_turns = Some(5);
+        }
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+
+        let mut received = String::new();
+        time::timeout(
+            Duration::from_secs(5),
+            clients[0].read_to_string(&mut received),
+        )
+        .await
+        .expect("connection wasn't closed")
+        .unwrap();
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+
+        let room = room.lock().unwrap();
+        let last = room.history.last().unwrap();
+        assert_eq!(last.turn(), 5);
+        let state = room.get_state();
+        match state {
+            State::Finished { causes, .. } => {
+                assert_eq!(causes[&addr(1)], DeathCause::Timeout);
+            }
+            state => panic!("game isn't finished: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn players_hear_how_many_snakes_are_alive() {
+        let room = open_room(7, 7);
+        let mut clients = join(&room, &["a", "b"]);
+        run(room.clone()).unwrap();
+        for client in clients.iter_mut() {
+            assert!(next_line(client).await.starts_with("{\"state\":\"start\""));
+        }
+
+        let parse =
+            |line: String| serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        for client in clients.iter_mut() {
+            let playing = parse(next_line(client).await);
+            assert_eq!(
+                (playing["turn"].as_u64(), playing["alive"].as_u64()),
+                (Some(0), Some(2))
+            );
+        }
+        clients[0].write_all(b"forward\n").await.unwrap();
+        clients[1].write_all(b"quit\n").await.unwrap();
+
+        let playing = parse(next_line(&mut clients[0]).await);
+        assert_eq!(playing["state"], "playing");
+        assert_eq!(
+            (playing["turn"].as_u64(), playing["alive"].as_u64()),
+            (Some(1), Some(1))
+        );
+    }
+
+    /// Read every line a player has been sent so far, without waiting for more.
+    async fn lines_so_far(client: &mut DuplexStream) -> Vec<serde_json::Value> {
+        let mut lines = Vec::new();
+        while let Ok(line) =
+            time::timeout(Duration::from_millis(50), next_line(client)).await
+        {
+            lines.push(serde_json::from_str(&line).unwrap());
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn waiting_players_hear_who_joins_and_leaves() {
+        let room = open_room(5, 5);
+        let (list, mut clients) = waiting_list(3).await;
+        list.subscribe(&addr(1), &room).await.unwrap();
+        list.subscribe(&addr(2), &room).await.unwrap();
+        list.unsubscribe(&addr(2), &room).await.unwrap();
+        list.subscribe_all(&room).await.unwrap();
+
+        let joined = |n: u16| serde_json::json!({ "state": "joined", "addr": addr(n), "name": n.to_string() });
+        let left = |n: u16| serde_json::json!({ "state": "left", "addr": addr(n) });
+        let mut first = lines_so_far(&mut clients[0]).await;
+        assert_eq!(first[..2], [joined(2), left(2)]);
+        first[2..].sort_by_key(|line| line["name"].to_string());
+        assert_eq!(first[2..], [joined(2), joined(3)]);
+
+        // nobody hears about themselves
+        for (n, client) in clients.iter_mut().enumerate().skip(1) {
+            let own = serde_json::json!(addr(n as u16 + 1));
+            assert!(lines_so_far(client)
+                .await
+                .iter()
+                .all(|line| line["addr"] != own));
+        }
+    }
+
+    #[tokio::test]
+    async fn player_quits_and_the_game_goes_on() {
+        let room = open_room(7, 7);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 1;
+            room.max_turns = Some(5);
+            room.seed = Some(1);
+        }
+        let mut clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let client = &mut clients[0];
+        assert!(next_line(client).await.starts_with("{\"state\":\"start\""));
+        assert!(next_line(client)
+            .await
+            .starts_with("{\"state\":\"playing\""));
+        client.write_all(b"forward\n").await.unwrap();
+        assert!(next_line(client)
+            .await
+            .starts_with("{\"state\":\"playing\""));
+        client.write_all(b"{\"action\":\"quit\"}\n").await.unwrap();
+
+        // they're told, and then the connection is closed
+        let mut received = String::new();
+        time::timeout(Duration::from_secs(5), client.read_to_string(&mut received))
+            .await
+            .expect("connection wasn't closed")
+            .unwrap();
+        assert_eq!(received, "{\"state\":\"quit\"}\n");
+
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+        let room = room.lock().unwrap();
+        let last = room.history.last().unwrap();
+        assert_eq!(last.turn(), 5);
+        let mut causes: Vec<_> = last.causes.values().copied().collect();
+        causes.sort_by_key(|cause| cause.to_string());
+        assert_eq!(causes, [DeathCause::Quit, DeathCause::Survived]);
+    }
+
+    #[tokio::test]
+    async fn paused_game_holds_until_resumed() {
+        let room = open_room(7, 7);
+        {
+            let mut room = room.lock().unwrap();
+            room.bots = 1;
+            room.max_turns = Some(30);
+            room.timestep = Some(Duration::from_millis(5));
+            room.seed = Some(1);
+        }
+        assert!(room.lock().unwrap().pause().is_err());
+        run(room.clone()).unwrap();
+        eventually(|| room.lock().unwrap().history_len() >= 3).await;
+
+        room.lock().unwrap().pause().unwrap();
+        assert!(room.lock().unwrap().is_paused());
+        assert!(room.lock().unwrap().pause().is_err());
+        // a step already under way may still finish
+        time::sleep(Duration::from_millis(50)).await;
+        let steps = room.lock().unwrap().history_len();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(room.lock().unwrap().history_len(), steps);
+
+        room.lock().unwrap().resume().unwrap();
+        assert!(!room.lock().unwrap().is_paused());
+        assert!(room.lock().unwrap().resume().is_err());
+        eventually(|| matches!(room.lock().unwrap().state, RoomState::Finished { .. }))
+            .await;
+        assert_eq!(room.lock().unwrap().history_len(), 31);
+    }
+
+    #[tokio::test]
+    async fn step_after_forced_end_changes_nothing() {
+        let room = open_room(5, 5);
+        let _clients = join(&room, &["a"]);
+        run(room.clone()).unwrap();
+        let map = match &room.lock().unwrap().state {
+            RoomState::Playing { map, .. } => map.clone(),
+            state => panic!("game isn't playing: {:?}", state),
+        };
+
+        room.lock().unwrap().force_end().unwrap();
+        let recorded = room.lock().unwrap().history.len();
+        let mut events = room.lock().unwrap().events();
+
+        // as if the step loop had got to the end of the turn just as the game ended
+        assert_eq!(do_server_step(&room, &map), Ok(true));
+        assert_eq!(room.lock().unwrap().history.len(), recorded);
+        assert!(events.try_recv().is_err());
+    }
 }